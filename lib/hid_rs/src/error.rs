@@ -0,0 +1,179 @@
+use std::fmt::{
+    self, Display, Formatter,
+};
+use std::io;
+
+#[cfg(windows)]
+use winapi::shared::winerror::{
+    ERROR_ACCESS_DENIED,
+    ERROR_DEV_NOT_EXIST,
+    ERROR_DEVICE_NOT_CONNECTED,
+    ERROR_FILE_NOT_FOUND,
+    ERROR_INSUFFICIENT_BUFFER,
+    ERROR_MORE_DATA,
+    ERROR_PATH_NOT_FOUND,
+    ERROR_SEM_TIMEOUT,
+    ERROR_SHARING_VIOLATION,
+    WAIT_TIMEOUT,
+};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // code is the OS error these were mapped from, None when they weren't
+    NotFound { code: Option<i32> },
+    AccessDenied { code: Option<i32> },
+    Disconnected { code: Option<i32> },
+    Timeout { code: Option<i32> },
+    BufferTooSmall,
+    // a descriptor, uevent or string the device gave us that we can't make sense of
+    InvalidDescriptor(String),
+    // a report or field values that don't fit the report descriptor
+    InvalidReport(String),
+    // any other OS error, code is errno on Linux and GetLastError() on Windows
+    Os { code: i32 },
+    // std errors that don't carry an OS code
+    Io(io::Error),
+}
+
+impl Error {
+    pub fn last_os_error() -> Error {
+        Error::from(io::Error::last_os_error())
+    }
+
+    pub fn from_os_code(code: i32) -> Error {
+        let os_code = Some(code);
+        #[cfg(windows)]
+        let error = match code as u32 {
+            ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => Error::NotFound { code: os_code },
+            ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION => Error::AccessDenied { code: os_code },
+            ERROR_DEVICE_NOT_CONNECTED | ERROR_DEV_NOT_EXIST => Error::Disconnected { code: os_code },
+            ERROR_SEM_TIMEOUT | WAIT_TIMEOUT => Error::Timeout { code: os_code },
+            ERROR_INSUFFICIENT_BUFFER | ERROR_MORE_DATA => Error::BufferTooSmall,
+            _ => Error::Os { code },
+        };
+        #[cfg(target_os = "linux")]
+        let error = match code {
+            libc::ENOENT => Error::NotFound { code: os_code },
+            libc::EACCES | libc::EPERM => Error::AccessDenied { code: os_code },
+            libc::ENODEV | libc::ENXIO => Error::Disconnected { code: os_code },
+            libc::ETIMEDOUT => Error::Timeout { code: os_code },
+            libc::EMSGSIZE | libc::EOVERFLOW => Error::BufferTooSmall,
+            _ => Error::Os { code },
+        };
+        error
+    }
+
+    // errno on Linux and GetLastError() on Windows, if this came from the OS
+    pub fn os_code(&self) -> Option<i32> {
+        match *self {
+            Error::NotFound { code } |
+            Error::AccessDenied { code } |
+            Error::Disconnected { code } |
+            Error::Timeout { code } => code,
+            Error::Os { code } => Some(code),
+            Error::Io(ref error) => error.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn invalid_descriptor<S: Into<String>>(message: S) -> Error {
+        Error::InvalidDescriptor(message.into())
+    }
+
+    pub(crate) fn invalid_report<S: Into<String>>(message: S) -> Error {
+        Error::InvalidReport(message.into())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let message = match self {
+            Error::NotFound { .. } => "device not found",
+            Error::AccessDenied { .. } => "access to the device was denied",
+            Error::Disconnected { .. } => "device disconnected",
+            Error::Timeout { .. } => "timed out waiting for the device",
+            Error::BufferTooSmall => return write!(f, "buffer is too small for the report"),
+            Error::InvalidDescriptor(message) => return write!(f, "invalid descriptor: {}", message),
+            Error::InvalidReport(message) => return write!(f, "invalid report: {}", message),
+            Error::Os { code } => return write!(f, "{}", io::Error::from_raw_os_error(*code)),
+            Error::Io(error) => return write!(f, "{}", error),
+        };
+        match self.os_code() {
+            Some(code) => write!(f, "{} ({})", message, io::Error::from_raw_os_error(code)),
+            None => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        if let Some(code) = error.raw_os_error() {
+            return Error::from_os_code(code);
+        }
+        match error.kind() {
+            io::ErrorKind::NotFound => Error::NotFound { code: None },
+            io::ErrorKind::PermissionDenied => Error::AccessDenied { code: None },
+            io::ErrorKind::NotConnected => Error::Disconnected { code: None },
+            io::ErrorKind::TimedOut => Error::Timeout { code: None },
+            _ => Error::Io(error),
+        }
+    }
+}
+
+// lets code built on io::Result keep using ?, errors that came from the OS
+// go back to being that OS error
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        if let Some(code) = error.os_code() {
+            return io::Error::from_raw_os_error(code);
+        }
+        let kind = match &error {
+            Error::NotFound { .. } => io::ErrorKind::NotFound,
+            Error::AccessDenied { .. } => io::ErrorKind::PermissionDenied,
+            Error::Disconnected { .. } => io::ErrorKind::NotConnected,
+            Error::Timeout { .. } => io::ErrorKind::TimedOut,
+            Error::BufferTooSmall | Error::InvalidDescriptor(_) | Error::InvalidReport(_) => io::ErrorKind::InvalidData,
+            Error::Os { .. } | Error::Io(_) => io::ErrorKind::Other,
+        };
+        match error {
+            Error::Io(error) => error,
+            error => io::Error::new(kind, error),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_errors_keep_the_os_code() {
+        let error = Error::from(io::Error::from_raw_os_error(libc::ENODEV));
+        assert!(matches!(error, Error::Disconnected { code: Some(libc::ENODEV) }));
+        assert_eq!(error.os_code(), Some(libc::ENODEV));
+        assert_eq!(io::Error::from(error).raw_os_error(), Some(libc::ENODEV));
+
+        assert!(matches!(Error::from_os_code(libc::EACCES), Error::AccessDenied { code: Some(libc::EACCES) }));
+        assert!(matches!(Error::from_os_code(libc::EIO), Error::Os { code: libc::EIO }));
+    }
+
+    #[test]
+    fn errors_without_an_os_code() {
+        let error = Error::from(io::Error::new(io::ErrorKind::TimedOut, "slow"));
+        assert!(matches!(error, Error::Timeout { code: None }));
+        assert_eq!(error.os_code(), None);
+        assert_eq!(io::Error::from(error).kind(), io::ErrorKind::TimedOut);
+        assert_eq!(Error::NotFound { code: None }.to_string(), "device not found");
+    }
+}
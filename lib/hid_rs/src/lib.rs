@@ -1,2 +1,7 @@
+pub mod error;
 pub mod usb;
-pub(crate) mod utils;
\ No newline at end of file
+pub(crate) mod utils;
+
+pub use error::{
+    Error, Result,
+};
\ No newline at end of file
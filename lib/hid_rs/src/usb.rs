@@ -1,30 +1,67 @@
+#[cfg(feature = "async")]
+pub mod async_device;
+pub mod backend;
+pub mod codec;
+pub mod descriptor;
 pub mod device;
 pub mod hid;
+pub mod monitor;
+pub mod query;
+pub mod raw;
 
+#[cfg(feature = "async")]
+pub use async_device::AsyncHidDevice;
+pub use codec::HidReportCodec;
+pub use query::HidQuery;
+
+#[cfg(windows)]
 use device::info::iter::HIDDeviceInfoIter;
+#[cfg(target_os = "linux")]
+use device::info::hidraw::{
+    HIDDeviceInfoIter,
+    SYSFS_ROOT,
+};
 use device::{
     HIDDevice,
     open_device,
 };
+#[cfg(windows)]
 use crate::utils::{
     str_to_os_str,
 };
 
-use std::io;
+use crate::error::{
+    Result,
+};
 
+#[cfg(target_os = "linux")]
+use std::path::Path;
+
+#[cfg(windows)]
 pub fn hid_enumerate_all() -> HIDDeviceInfoIter {
-    HIDDeviceInfoIter {
-        index: 0,
-        device_info_set: None,
-    }
+    HIDDeviceInfoIter::new()
+}
+
+#[cfg(target_os = "linux")]
+pub fn hid_enumerate_all() -> HIDDeviceInfoIter {
+    hid_enumerate_sysfs(SYSFS_ROOT)
+}
+
+// walk <sysfs_root>/class/hidraw instead of /sys, e.g. a fake tree in tests
+#[cfg(target_os = "linux")]
+pub fn hid_enumerate_sysfs<P: AsRef<Path>>(sysfs_root: P) -> HIDDeviceInfoIter {
+    HIDDeviceInfoIter::new(sysfs_root)
 }
 
-pub fn hid_open_path(device_path: &String) -> io::Result<HIDDevice> {
-    match open_device(str_to_os_str(device_path).as_ptr(), true) {
+pub fn hid_open_path(device_path: &str) -> Result<HIDDevice> {
+    // enumeration opens without access, reports need read and write
+    #[cfg(windows)]
+    let result = open_device(str_to_os_str(device_path).as_ptr(), false);
+    #[cfg(target_os = "linux")]
+    let result = open_device(device_path);
+    match result {
         Ok(handle) => {
-            let device = HIDDevice {
-                handle: handle,
-            };
+            let device = unsafe { HIDDevice::from_raw(handle) };
             Ok(device)
         }
         Err(error) => Err(error),
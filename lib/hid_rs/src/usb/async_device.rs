@@ -0,0 +1,230 @@
+#[cfg(target_os = "linux")]
+use tokio::io::unix::AsyncFd;
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+use tokio::task;
+
+use futures_core::Stream;
+
+use std::future::Future;
+#[cfg(target_os = "linux")]
+use std::io;
+use std::pin::Pin;
+#[cfg(windows)]
+use std::sync::Arc;
+use std::task::{
+    Context, Poll,
+};
+
+use super::device::HIDDevice;
+use super::hid::{
+    hid_get_feature_report,
+    hid_set_feature_report,
+};
+#[cfg(windows)]
+use super::hid::{
+    hid_read_timeout,
+    hid_write,
+};
+use crate::error::{
+    Error, Result,
+};
+
+// A HIDDevice for async code, has to be created and used inside a tokio
+// runtime. Same report buffer conventions as HIDDevice.
+//
+// hidraw nodes are made non-blocking and polled by tokio's reactor. Tokio has
+// no reactor for overlapped handles on Windows, there the overlapped calls
+// wait on the blocking pool instead. A read that is given up on keeps its
+// pool thread until the next report comes in. Feature reports go through the
+// blocking pool on both, they wait for the device to answer.
+pub struct AsyncHidDevice {
+    #[cfg(target_os = "linux")]
+    device: AsyncFd<HIDDevice>,
+    #[cfg(windows)]
+    device: Arc<HIDDevice>,
+}
+
+#[cfg(target_os = "linux")]
+impl AsyncHidDevice {
+    pub fn new(device: HIDDevice) -> Result<AsyncHidDevice> {
+        let handle = device.as_raw();
+        let flags = unsafe { libc::fcntl(handle, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(handle, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        let device = AsyncFd::with_interest(device, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(AsyncHidDevice {
+            device,
+        })
+    }
+
+    // Wait for the next input report, Error::Disconnected once unplugged
+    pub async fn read_report(&self, data: &mut [u8]) -> Result<usize> {
+        loop {
+            let mut guard = self.device.readable().await?;
+            let result = guard.try_io(|device| {
+                let len = unsafe {
+                    libc::read(device.get_ref().as_raw(), data.as_mut_ptr() as *mut libc::c_void, data.len())
+                };
+                if len < 0 { Err(io::Error::last_os_error()) } else { Ok(len as usize) }
+            });
+            match result {
+                Ok(Ok(len)) => return Ok(len),
+                Ok(Err(error)) => return Err(device_error(error)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub async fn write_report(&self, data: &[u8]) -> Result<usize> {
+        loop {
+            let mut guard = self.device.writable().await?;
+            let result = guard.try_io(|device| {
+                let len = unsafe {
+                    libc::write(device.get_ref().as_raw(), data.as_ptr() as *const libc::c_void, data.len())
+                };
+                if len < 0 { Err(io::Error::last_os_error()) } else { Ok(len as usize) }
+            });
+            match result {
+                Ok(Ok(len)) => return Ok(len),
+                Ok(Err(error)) => return Err(device_error(error)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    // hidraw feature reports are ioctls that block until the device answers,
+    // which over Bluetooth can take seconds. The blocking pool gets its own
+    // duplicate of the fd so it stays open if this future is dropped.
+    async fn blocking<T, F>(&self, call: F) -> Result<T>
+        where T: Send + 'static, F: FnOnce(&HIDDevice) -> Result<T> + Send + 'static
+    {
+        let fd = unsafe { libc::fcntl(self.device.get_ref().as_raw(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let device = unsafe { HIDDevice::from_raw(fd) };
+        match task::spawn_blocking(move || call(&device)).await {
+            Ok(result) => result,
+            Err(error) => Err(Error::Io(error.into())),
+        }
+    }
+
+    pub fn into_inner(self) -> HIDDevice {
+        self.device.into_inner()
+    }
+}
+
+#[cfg(windows)]
+impl AsyncHidDevice {
+    pub fn new(device: HIDDevice) -> Result<AsyncHidDevice> {
+        Ok(AsyncHidDevice {
+            device: Arc::new(device),
+        })
+    }
+
+    // Wait for the next input report, Error::Disconnected once unplugged
+    pub async fn read_report(&self, data: &mut [u8]) -> Result<usize> {
+        let mut buffer = vec![0u8; data.len()];
+        let (buffer, len) = self.blocking(move |device| {
+            let len = hid_read_timeout(device.as_raw(), &mut buffer, None)?;
+            Ok((buffer, len as usize))
+        }).await?;
+        data[..len].copy_from_slice(&buffer[..len]);
+        Ok(len)
+    }
+
+    pub async fn write_report(&self, data: &[u8]) -> Result<usize> {
+        let data = data.to_vec();
+        self.blocking(move |device| hid_write(device.as_raw(), &data).map(|len| len as usize)).await
+    }
+
+    // Give the device back, None while a read given up on still holds it
+    pub fn into_inner(self) -> Option<HIDDevice> {
+        Arc::try_unwrap(self.device).ok()
+    }
+
+    async fn blocking<T, F>(&self, call: F) -> Result<T>
+        where T: Send + 'static, F: FnOnce(&HIDDevice) -> Result<T> + Send + 'static
+    {
+        let device = Arc::clone(&self.device);
+        match task::spawn_blocking(move || call(&device)).await {
+            Ok(result) => result,
+            Err(error) => Err(Error::Io(error.into())),
+        }
+    }
+}
+
+impl AsyncHidDevice {
+    pub async fn get_feature_report(&self, data: &mut [u8]) -> Result<usize> {
+        let mut buffer = data.to_vec();
+        let (buffer, len) = self.blocking(move |device| {
+            let len = hid_get_feature_report(device.as_raw(), &mut buffer)?;
+            Ok((buffer, len as usize))
+        }).await?;
+        data.copy_from_slice(&buffer);
+        Ok(len)
+    }
+
+    pub async fn set_feature_report(&self, data: &[u8]) -> Result<usize> {
+        let data = data.to_vec();
+        self.blocking(move |device| hid_set_feature_report(device.as_raw(), &data).map(|len| len as usize)).await
+    }
+
+    // Input reports of up to report_len bytes as they come in. Ends after
+    // the first error.
+    pub fn input_reports(&self, report_len: usize) -> InputReports<'_> {
+        InputReports {
+            device: self,
+            report_len,
+            read: None,
+            done: false,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn device_error(error: io::Error) -> Error {
+    match error.raw_os_error() {
+        // hidraw answers reads and writes on a removed device with EIO
+        Some(libc::EIO) | Some(libc::ENODEV) => Error::Disconnected { code: error.raw_os_error() },
+        _ => Error::from(error),
+    }
+}
+
+type ReadFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>>;
+
+pub struct InputReports<'a> {
+    device: &'a AsyncHidDevice,
+    report_len: usize,
+    read: Option<ReadFuture<'a>>,
+    done: bool,
+}
+
+impl<'a> Stream for InputReports<'a> {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Vec<u8>>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let device = self.device;
+        let report_len = self.report_len;
+        let read = self.read.get_or_insert_with(|| Box::pin(async move {
+            let mut report = vec![0u8; report_len];
+            let len = device.read_report(&mut report).await?;
+            report.truncate(len);
+            Ok(report)
+        }));
+
+        match read.as_mut().poll(cx) {
+            Poll::Ready(report) => {
+                self.read = None;
+                self.done = report.is_err();
+                Poll::Ready(Some(report))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+pub mod mock;
+
+use crate::error::{
+    Result,
+};
+use super::device::HIDDevice;
+use super::device::info::HIDDeviceInfo;
+use super::hid::{
+    hid_get_feature_report,
+    hid_set_feature_report,
+    hid_write,
+};
+use super::{
+    hid_enumerate_all,
+    hid_open_path,
+};
+
+// The operations a protocol module needs from an open HID device. Report
+// buffers follow hidapi's layout, data[0] is the report id.
+pub trait HidDeviceIo {
+    fn get_feature_report(&self, data: &mut [u8]) -> Result<usize>;
+    fn set_feature_report(&self, data: &[u8]) -> Result<usize>;
+    // Ok(0) means no input report was available
+    fn read_input_report(&self, data: &mut [u8]) -> Result<usize>;
+    fn write_output_report(&self, data: &[u8]) -> Result<usize>;
+}
+
+pub trait HidBackend {
+    type Device: HidDeviceIo;
+
+    fn enumerate(&self) -> Box<dyn Iterator<Item = Result<HIDDeviceInfo>>>;
+    fn open(&self, device_path: &str) -> Result<Self::Device>;
+}
+
+// The platform's own HID stack (SetupDi on Windows, hidraw on Linux)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NativeBackend;
+
+impl HidBackend for NativeBackend {
+    type Device = HIDDevice;
+
+    fn enumerate(&self) -> Box<dyn Iterator<Item = Result<HIDDeviceInfo>>> {
+        Box::new(hid_enumerate_all())
+    }
+
+    fn open(&self, device_path: &str) -> Result<HIDDevice> {
+        hid_open_path(device_path)
+    }
+}
+
+impl HidDeviceIo for HIDDevice {
+    fn get_feature_report(&self, data: &mut [u8]) -> Result<usize> {
+        hid_get_feature_report(self.as_raw(), data).map(|len| len as usize)
+    }
+
+    fn set_feature_report(&self, data: &[u8]) -> Result<usize> {
+        hid_set_feature_report(self.as_raw(), data).map(|len| len as usize)
+    }
+
+    fn read_input_report(&self, data: &mut [u8]) -> Result<usize> {
+        self.read(data)
+    }
+
+    fn write_output_report(&self, data: &[u8]) -> Result<usize> {
+        hid_write(self.as_raw(), data).map(|len| len as usize)
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{
+    HashMap, VecDeque,
+};
+use std::io;
+use std::sync::{
+    Arc, Mutex, MutexGuard,
+};
+
+use super::{
+    HidBackend,
+    HidDeviceIo,
+};
+use crate::error::{
+    Error, Result,
+};
+use crate::usb::device::info::HIDDeviceInfo;
+
+// In-memory backend for testing protocol code without hardware. Devices are
+// scripted with canned reports up front and record everything written to them.
+#[derive(Debug, Default, Clone)]
+pub struct MockBackend {
+    devices: Vec<(HIDDeviceInfo, MockDevice)>,
+}
+
+impl MockBackend {
+    pub fn new() -> MockBackend {
+        MockBackend::default()
+    }
+
+    // the returned device shares its state with the ones handed out by open()
+    pub fn add_device(&mut self, device_info: HIDDeviceInfo) -> MockDevice {
+        let device = MockDevice::default();
+        self.devices.push((device_info, device.clone()));
+        device
+    }
+}
+
+impl HidBackend for MockBackend {
+    type Device = MockDevice;
+
+    fn enumerate(&self) -> Box<dyn Iterator<Item = Result<HIDDeviceInfo>>> {
+        let device_infos: Vec<Result<HIDDeviceInfo>> = self.devices.iter()
+            .map(|(device_info, _)| Ok(device_info.clone()))
+            .collect();
+        Box::new(device_infos.into_iter())
+    }
+
+    fn open(&self, device_path: &str) -> Result<MockDevice> {
+        self.devices.iter()
+            .find(|(device_info, _)| device_info.path == device_path)
+            .map(|(_, device)| device.clone())
+            .ok_or(Error::NotFound { code: None })
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    feature_reports: HashMap<u8, Vec<u8>>,
+    queued_feature_reports: HashMap<u8, VecDeque<Vec<u8>>>,
+    input_reports: VecDeque<Vec<u8>>,
+    feature_writes: Vec<Vec<u8>>,
+    output_writes: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MockDevice {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockDevice {
+    // answer every get_feature_report for report[0] with this report
+    pub fn set_feature_report_reply(&self, report: &[u8]) {
+        self.state().feature_reports.insert(report[0], report.to_vec());
+    }
+
+    // answer the next get_feature_report for report[0] with this report, once;
+    // queued replies go out in order before the one set above
+    pub fn push_feature_report_reply(&self, report: &[u8]) {
+        self.state().queued_feature_reports.entry(report[0]).or_default().push_back(report.to_vec());
+    }
+
+    // read_input_report replays these in order
+    pub fn push_input_report(&self, report: &[u8]) {
+        self.state().input_reports.push_back(report.to_vec());
+    }
+
+    pub fn feature_writes(&self) -> Vec<Vec<u8>> {
+        self.state().feature_writes.clone()
+    }
+
+    pub fn output_writes(&self) -> Vec<Vec<u8>> {
+        self.state().output_writes.clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl HidDeviceIo for MockDevice {
+    fn get_feature_report(&self, data: &mut [u8]) -> Result<usize> {
+        let report_id = *data.first().ok_or(Error::BufferTooSmall)?;
+        let mut state = self.state();
+        if let Some(report) = state.queued_feature_reports.get_mut(&report_id).and_then(VecDeque::pop_front) {
+            return Ok(copy_report(&report, data));
+        }
+        match state.feature_reports.get(&report_id) {
+            Some(report) => Ok(copy_report(report, data)),
+            None => Err(Error::Io(io::Error::other(
+                format!("no canned feature report {:#04x}", report_id),
+            ))),
+        }
+    }
+
+    fn set_feature_report(&self, data: &[u8]) -> Result<usize> {
+        self.state().feature_writes.push(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn read_input_report(&self, data: &mut [u8]) -> Result<usize> {
+        match self.state().input_reports.pop_front() {
+            Some(report) => Ok(copy_report(&report, data)),
+            None => Ok(0), // like a non-blocking read with nothing pending
+        }
+    }
+
+    fn write_output_report(&self, data: &[u8]) -> Result<usize> {
+        self.state().output_writes.push(data.to_vec());
+        Ok(data.len())
+    }
+}
+
+fn copy_report(report: &[u8], data: &mut [u8]) -> usize {
+    let len = report.len().min(data.len());
+    data[..len].copy_from_slice(&report[..len]);
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_report_replies() {
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&[0x04, 0xaa]);
+        device.push_feature_report_reply(&[0x04, 0x01]);
+        device.push_feature_report_reply(&[0x04, 0x02]);
+
+        let mut data = [0x04, 0x00];
+        for expected in [0x01, 0x02, 0xaa, 0xaa].iter() {
+            assert_eq!(device.get_feature_report(&mut data).unwrap(), 2);
+            assert_eq!(data[1], *expected);
+        }
+
+        let mut unknown = [0x05, 0x00];
+        assert!(matches!(device.get_feature_report(&mut unknown), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn feature_report_needs_a_report_id() {
+        let device = MockDevice::default();
+        assert!(matches!(device.get_feature_report(&mut []), Err(Error::BufferTooSmall)));
+    }
+
+    #[test]
+    fn short_buffer_truncates_report() {
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&[0x10, 1, 2, 3, 4]);
+        let mut data = [0x10, 0, 0];
+        assert_eq!(device.get_feature_report(&mut data).unwrap(), 3);
+        assert_eq!(data, [0x10, 1, 2]);
+    }
+
+    #[test]
+    fn writes_are_recorded_and_input_replayed() {
+        let mut backend = MockBackend::new();
+        let device_info = HIDDeviceInfo {
+            path: "/dev/hidraw0".to_string(),
+            ..HIDDeviceInfo::default()
+        };
+        let scripted = backend.add_device(device_info);
+        scripted.push_input_report(&[0x01, 0x42]);
+
+        let device = backend.open("/dev/hidraw0").unwrap();
+        assert!(matches!(backend.open("/dev/hidraw1"), Err(Error::NotFound { .. })));
+        device.set_feature_report(&[0x05, 1, 2]).unwrap();
+        device.write_output_report(&[0x06, 0xff]).unwrap();
+        assert_eq!(scripted.feature_writes(), vec![vec![0x05, 1, 2]]);
+        assert_eq!(scripted.output_writes(), vec![vec![0x06, 0xff]]);
+
+        let mut data = [0u8; 4];
+        assert_eq!(device.read_input_report(&mut data).unwrap(), 2);
+        assert_eq!(&data[..2], &[0x01, 0x42]);
+        assert_eq!(device.read_input_report(&mut data).unwrap(), 0);
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+
+use super::descriptor::{
+    ReportDescriptor,
+    ReportField,
+    ReportType,
+    Usage,
+};
+use super::device::HIDDevice;
+use crate::error::{
+    Error, Result,
+};
+
+// Turns reports into named values and back using a device's report
+// descriptor, so a device doesn't need its own module to be read.
+//
+//     let codec = HidReportCodec::from_device(&device)?;
+//     let len = device.read(&mut data)?;
+//     let report = codec.decode_input(&data[..len])?;
+//     let x = report.get("X").map(|x| x.physical);
+//
+// Names are what Usage displays as. Items of a field that share the field's
+// last usage are told apart with an index, "ff00:0001[3]".
+#[derive(Debug, Clone)]
+pub struct HidReportCodec {
+    descriptor: ReportDescriptor,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldValue {
+    pub name: String,
+    pub usage: Usage,
+    pub logical: i32,
+    // logical mapped onto the physical range and scaled by the unit exponent
+    pub physical: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedReport {
+    pub report_type: ReportType,
+    pub report_id: u8,
+    // pressed buttons of an array field show up as a value of 1, the rest are
+    // left out
+    pub values: Vec<FieldValue>,
+}
+
+impl DecodedReport {
+    pub fn get(&self, name: &str) -> Option<&FieldValue> {
+        self.values.iter().find(|value| value.name == name)
+    }
+}
+
+impl HidReportCodec {
+    pub fn new(descriptor: ReportDescriptor) -> HidReportCodec {
+        HidReportCodec {
+            descriptor,
+        }
+    }
+
+    pub fn from_device(device: &HIDDevice) -> Result<HidReportCodec> {
+        Ok(HidReportCodec::new(device.report_descriptor()?))
+    }
+
+    pub fn descriptor(&self) -> &ReportDescriptor {
+        &self.descriptor
+    }
+
+    // An input report the way HIDDevice::read hands it out, which leaves off
+    // the report id for devices that don't number their reports
+    pub fn decode_input(&self, data: &[u8]) -> Result<DecodedReport> {
+        if self.descriptor.uses_report_ids() {
+            self.decode(ReportType::Input, data)
+        } else {
+            let mut report = Vec::with_capacity(data.len() + 1);
+            report.push(0);
+            report.extend_from_slice(data);
+            self.decode(ReportType::Input, &report)
+        }
+    }
+
+    // report[0] is the report id, 0 for devices without numbered reports
+    pub fn decode(&self, report_type: ReportType, report: &[u8]) -> Result<DecodedReport> {
+        let report_id = *report.first().ok_or_else(|| Error::invalid_report("report is empty"))?;
+        let report_len = self.report_len(report_type, report_id)?;
+        if report.len() < report_len {
+            return Err(Error::invalid_report(format!(
+                "report {} is {} bytes, expected {}", report_id, report.len(), report_len
+            )));
+        }
+        let body = &report[1..];
+
+        let mut values = Vec::new();
+        for field in self.data_fields(report_type, report_id) {
+            for index in 0..field.report_count {
+                let raw = read_bits(body, field.bit_offset + index * field.report_size, field.report_size);
+                let logical = to_logical(field, raw);
+                if field.is_variable() {
+                    let usage = field.usage(index as usize).unwrap_or(Usage { page: 0, id: 0 });
+                    values.push(FieldValue {
+                        name: item_name(field, index as usize),
+                        usage,
+                        logical,
+                        physical: to_physical(field, logical),
+                    });
+                } else if let Some(usage) = field.array_usage(logical) {
+                    values.push(FieldValue {
+                        name: usage.to_string(),
+                        usage,
+                        logical: 1,
+                        physical: 1.0,
+                    });
+                }
+            }
+        }
+
+        Ok(DecodedReport {
+            report_type,
+            report_id,
+            values,
+        })
+    }
+
+    // Build a report, report[0] being the report id, from logical values by
+    // name. Fields that aren't given are 0, array fields take the names of the
+    // usages that are set.
+    pub fn encode(&self, report_type: ReportType, report_id: u8, values: &[(&str, i32)]) -> Result<Vec<u8>> {
+        let mut report = vec![0u8; self.report_len(report_type, report_id)?];
+        report[0] = report_id;
+
+        let mut unused: HashMap<&str, i32> = values.iter().cloned().collect();
+        for field in self.data_fields(report_type, report_id) {
+            if field.is_variable() {
+                for index in 0..field.report_count {
+                    let name = item_name(field, index as usize);
+                    if let Some(logical) = unused.remove(name.as_str()) {
+                        if logical < field.logical_minimum || logical > field.logical_maximum {
+                            return Err(Error::invalid_report(format!(
+                                "{} is out of range, {} not in {}..={}",
+                                name, logical, field.logical_minimum, field.logical_maximum
+                            )));
+                        }
+                        write_bits(&mut report[1..], field.bit_offset + index * field.report_size, field.report_size, logical as u32);
+                    }
+                }
+            } else {
+                let mut slot = 0;
+                for (index, usage) in field.usages.iter().enumerate() {
+                    let name = usage.to_string();
+                    match unused.remove(name.as_str()) {
+                        Some(logical) if logical != 0 => {}
+                        _ => continue,
+                    }
+                    if slot == field.report_count {
+                        return Err(Error::invalid_report(format!("too many usages set at once with {}", name)));
+                    }
+                    let value = field.logical_minimum + index as i32;
+                    write_bits(&mut report[1..], field.bit_offset + slot * field.report_size, field.report_size, value as u32);
+                    slot += 1;
+                }
+            }
+        }
+
+        if let Some(name) = unused.keys().next() {
+            return Err(Error::invalid_report(format!("report {} has no field {}", report_id, name)));
+        }
+        Ok(report)
+    }
+
+    // An output report ready for HIDDevice::write
+    pub fn encode_output(&self, report_id: u8, values: &[(&str, i32)]) -> Result<Vec<u8>> {
+        self.encode(ReportType::Output, report_id, values)
+    }
+
+    fn report_len(&self, report_type: ReportType, report_id: u8) -> Result<usize> {
+        self.descriptor.report_len(report_type, report_id)
+            .ok_or_else(|| Error::invalid_report(format!("no {:?} report {}", report_type, report_id)))
+    }
+
+    // padding is constant and wider than 32 bits isn't a value we can hold
+    fn data_fields(&self, report_type: ReportType, report_id: u8) -> impl Iterator<Item = &ReportField> {
+        self.descriptor.report_fields(report_type, report_id)
+            .filter(|field| !field.is_constant() && field.report_size > 0 && field.report_size <= 32)
+    }
+}
+
+fn item_name(field: &ReportField, index: usize) -> String {
+    let shared_from = field.usages.len().saturating_sub(1);
+    match field.usage(index) {
+        Some(usage) if field.report_count as usize > field.usages.len() && index >= shared_from => {
+            format!("{}[{}]", usage, index - shared_from)
+        }
+        Some(usage) => usage.to_string(),
+        None => format!("{:?}[{}]", field.report_type, index),
+    }
+}
+
+// Values are signed only when the logical range goes below 0
+fn to_logical(field: &ReportField, raw: u32) -> i32 {
+    if field.logical_minimum < 0 && field.report_size < 32 && raw & (1 << (field.report_size - 1)) != 0 {
+        (raw | !0u32 << field.report_size) as i32
+    } else {
+        raw as i32
+    }
+}
+
+// HID spec section 6.2.2.7
+fn to_physical(field: &ReportField, logical: i32) -> f64 {
+    let (physical_minimum, physical_maximum) = field.physical_range();
+    let logical_span = field.logical_maximum as f64 - field.logical_minimum as f64;
+    let physical = if logical_span == 0.0 {
+        logical as f64
+    } else {
+        let resolution = (physical_maximum as f64 - physical_minimum as f64) / logical_span;
+        physical_minimum as f64 + (logical as f64 - field.logical_minimum as f64) * resolution
+    };
+    physical * 10f64.powi(field.unit_exponent)
+}
+
+// Reports are little endian down to the bit, bit 0 is the low bit of byte 0
+fn read_bits(data: &[u8], bit_offset: u32, bit_len: u32) -> u32 {
+    let mut value = 0u32;
+    for bit in 0..bit_len {
+        let position = (bit_offset + bit) as usize;
+        let set = data.get(position / 8).is_some_and(|&b| b & (1 << (position % 8)) != 0);
+        if set {
+            value |= 1 << bit;
+        }
+    }
+    value
+}
+
+fn write_bits(data: &mut [u8], bit_offset: u32, bit_len: u32, value: u32) {
+    for bit in 0..bit_len {
+        let position = (bit_offset + bit) as usize;
+        if let Some(b) = data.get_mut(position / 8) {
+            if value & (1 << bit) != 0 {
+                *b |= 1 << (position % 8);
+            } else {
+                *b &= !(1 << (position % 8));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Input report 1: up to two of eight buttons as an array, a signed X with
+    // a physical range in tenths, three vendor values sharing one usage.
+    // Output report 2: three LEDs.
+    const DESCRIPTOR: &[u8] = &[
+        0x05, 0x01,             // Usage Page (Generic Desktop)
+        0x09, 0x04,             // Usage (Joystick)
+        0xa1, 0x01,             // Collection (Application)
+        0x85, 0x01,             //   Report ID (1)
+        0x05, 0x09,             //   Usage Page (Button)
+        0x19, 0x01,             //   Usage Minimum (1)
+        0x29, 0x08,             //   Usage Maximum (8)
+        0x15, 0x01,             //   Logical Minimum (1)
+        0x25, 0x08,             //   Logical Maximum (8)
+        0x75, 0x04,             //   Report Size (4)
+        0x95, 0x02,             //   Report Count (2)
+        0x81, 0x00,             //   Input (Data, Array)
+        0xa4,                   //   Push
+        0x05, 0x01,             //   Usage Page (Generic Desktop)
+        0x09, 0x30,             //   Usage (X)
+        0x15, 0x9c,             //   Logical Minimum (-100)
+        0x25, 0x64,             //   Logical Maximum (100)
+        0x36, 0x18, 0xfc,       //   Physical Minimum (-1000)
+        0x46, 0xe8, 0x03,       //   Physical Maximum (1000)
+        0x55, 0x0f,             //   Unit Exponent (-1)
+        0x75, 0x08,             //   Report Size (8)
+        0x95, 0x01,             //   Report Count (1)
+        0x81, 0x02,             //   Input (Data, Variable)
+        0xb4,                   //   Pop
+        0x06, 0x00, 0xff,       //   Usage Page (Vendor)
+        0x09, 0x01,             //   Usage (1)
+        0x15, 0x00,             //   Logical Minimum (0)
+        0x25, 0x0f,             //   Logical Maximum (15)
+        0x95, 0x03,             //   Report Count (3)
+        0x81, 0x02,             //   Input (Data, Variable)
+        0x95, 0x01,             //   Report Count (1)
+        0x81, 0x03,             //   Input (Constant)
+        0x85, 0x02,             //   Report ID (2)
+        0x05, 0x08,             //   Usage Page (LED)
+        0x19, 0x01,             //   Usage Minimum (1)
+        0x29, 0x03,             //   Usage Maximum (3)
+        0x25, 0x01,             //   Logical Maximum (1)
+        0x75, 0x01,             //   Report Size (1)
+        0x95, 0x03,             //   Report Count (3)
+        0x91, 0x02,             //   Output (Data, Variable)
+        0x95, 0x05,             //   Report Count (5)
+        0x91, 0x03,             //   Output (Constant)
+        0xc0,                   // End Collection
+    ];
+
+    // Buttons 1 and 3, X at -5, vendor values 1, 2, 3
+    const INPUT: [u8; 5] = [0x01, 0x31, 0xfb, 0x21, 0x03];
+
+    fn codec() -> HidReportCodec {
+        HidReportCodec::new(ReportDescriptor::parse(DESCRIPTOR).unwrap())
+    }
+
+    fn logical(report: &DecodedReport, name: &str) -> Option<i32> {
+        report.get(name).map(|value| value.logical)
+    }
+
+    fn is_invalid_report<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::InvalidReport(_)))
+    }
+
+    #[test]
+    fn decode_input_report() {
+        let report = codec().decode_input(&INPUT).unwrap();
+        assert_eq!((report.report_type, report.report_id), (ReportType::Input, 1));
+        let names: Vec<&str> = report.values.iter().map(|value| value.name.as_str()).collect();
+        assert_eq!(names, vec!["Button 1", "Button 3", "X", "ff00:0001[0]", "ff00:0001[1]", "ff00:0001[2]"]);
+
+        // array fields only list what is pressed
+        assert_eq!(logical(&report, "Button 3"), Some(1));
+        assert_eq!(logical(&report, "Button 2"), None);
+        assert_eq!(logical(&report, "ff00:0001[2]"), Some(3));
+
+        // signed, then -50 on the physical range in tenths
+        let x = report.get("X").unwrap();
+        assert_eq!(x.usage, Usage { page: 0x01, id: 0x30 });
+        assert_eq!(x.logical, -5);
+        assert!((x.physical - -5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_array_slots() {
+        let report = codec().decode(ReportType::Input, &[0x01, 0x00, 0x64, 0x00, 0x00]).unwrap();
+        assert_eq!(logical(&report, "Button 1"), None);
+        assert!((report.get("X").unwrap().physical - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn input_round_trip() {
+        let report = codec().encode(ReportType::Input, 1, &[
+            ("Button 1", 1),
+            ("Button 3", 1),
+            ("X", -5),
+            ("ff00:0001[0]", 1),
+            ("ff00:0001[1]", 2),
+            ("ff00:0001[2]", 3),
+        ]).unwrap();
+        assert_eq!(report, INPUT.to_vec());
+
+        let decoded = codec().decode(ReportType::Input, &report).unwrap();
+        let values: Vec<(&str, i32)> = decoded.values.iter()
+            .map(|value| (value.name.as_str(), value.logical))
+            .collect();
+        assert_eq!(codec().encode(ReportType::Input, 1, &values).unwrap(), report);
+    }
+
+    #[test]
+    fn output_round_trip() {
+        let codec = codec();
+        let report = codec.encode_output(2, &[("0008:0001", 1), ("0008:0003", 1)]).unwrap();
+        assert_eq!(report, vec![0x02, 0x05]);
+        let decoded = codec.decode(ReportType::Output, &report).unwrap();
+        assert_eq!(logical(&decoded, "0008:0001"), Some(1));
+        assert_eq!(logical(&decoded, "0008:0002"), Some(0));
+        assert_eq!(logical(&decoded, "0008:0003"), Some(1));
+    }
+
+    #[test]
+    fn unnumbered_reports() {
+        // Usage (X), 0..255 in one byte, no report id
+        let codec = HidReportCodec::new(ReportDescriptor::parse(&[
+            0x05, 0x01, 0x09, 0x30, 0x15, 0x00, 0x26, 0xff, 0x00, 0x75, 0x08, 0x95, 0x01, 0x81, 0x02,
+        ]).unwrap());
+        // read leaves off the report id, decode wants it as 0
+        let report = codec.decode_input(&[0xc8]).unwrap();
+        assert_eq!(report.report_id, 0);
+        assert_eq!(logical(&report, "X"), Some(200));
+        assert_eq!(codec.decode(ReportType::Input, &[0x00, 0xc8]).unwrap(), report);
+        assert_eq!(codec.encode(ReportType::Input, 0, &[("X", 200)]).unwrap(), vec![0x00, 0xc8]);
+    }
+
+    #[test]
+    fn bad_reports() {
+        let codec = codec();
+        assert!(is_invalid_report(codec.decode_input(&[])));
+        assert!(is_invalid_report(codec.decode_input(&INPUT[..4])));
+        assert!(is_invalid_report(codec.decode_input(&[0x03, 0x00])));
+        assert!(is_invalid_report(codec.encode(ReportType::Input, 1, &[("X", 101)])));
+        assert!(is_invalid_report(codec.encode(ReportType::Input, 1, &[("Y", 0)])));
+        assert!(is_invalid_report(codec.encode(ReportType::Input, 1, &[("Button 1", 1), ("Button 2", 1), ("Button 3", 1)])));
+        assert!(is_invalid_report(codec.encode_output(1, &[])));
+    }
+}
@@ -0,0 +1,598 @@
+#[cfg(windows)]
+mod preparsed;
+
+#[cfg(windows)]
+pub(crate) use preparsed::from_preparsed_data;
+
+use std::collections::HashMap;
+use std::fmt::{
+    self, Display, Formatter,
+};
+
+use crate::error::{
+    Error, Result,
+};
+
+// Report descriptors are laid out in the HID spec, section 6.2.2
+// https://www.usb.org/sites/default/files/documents/hid1_11.pdf
+
+// collection types, section 6.2.2.6
+pub const COLLECTION_PHYSICAL: u8 = 0x00;
+pub const COLLECTION_APPLICATION: u8 = 0x01;
+pub const COLLECTION_LOGICAL: u8 = 0x02;
+
+// bits of an Input, Output or Feature item's data, section 6.2.2.5
+pub const FIELD_CONSTANT: u32 = 0x01;
+pub const FIELD_VARIABLE: u32 = 0x02;
+pub const FIELD_RELATIVE: u32 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReportType {
+    Input,
+    Output,
+    Feature,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Usage {
+    pub page: u16,
+    pub id: u16,
+}
+
+// Names for the usages gamepads and trackers tend to have, anything else is
+// page:id in hex
+// https://usb.org/sites/default/files/hut1_21.pdf
+impl Display for Usage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match (self.page, self.id) {
+            (0x01, 0x01) => "Pointer",
+            (0x01, 0x02) => "Mouse",
+            (0x01, 0x04) => "Joystick",
+            (0x01, 0x05) => "Gamepad",
+            (0x01, 0x30) => "X",
+            (0x01, 0x31) => "Y",
+            (0x01, 0x32) => "Z",
+            (0x01, 0x33) => "Rx",
+            (0x01, 0x34) => "Ry",
+            (0x01, 0x35) => "Rz",
+            (0x01, 0x36) => "Slider",
+            (0x01, 0x37) => "Dial",
+            (0x01, 0x38) => "Wheel",
+            (0x01, 0x39) => "Hat switch",
+            (0x01, 0x90) => "D-pad Up",
+            (0x01, 0x91) => "D-pad Down",
+            (0x01, 0x92) => "D-pad Right",
+            (0x01, 0x93) => "D-pad Left",
+            (0x09, id) => return write!(f, "Button {}", id),
+            (0x0a, id) => return write!(f, "Ordinal {}", id),
+            _ => return write!(f, "{:04x}:{:04x}", self.page, self.id),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collection {
+    pub collection_type: u8,
+    pub usage: Usage,
+    // index into ReportDescriptor::collections, None for top-level collections
+    pub parent: Option<usize>,
+}
+
+// One Input, Output or Feature item: report_count items of report_size bits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportField {
+    pub report_type: ReportType,
+    // 0 when the device doesn't number its reports
+    pub report_id: u8,
+    // the innermost collection the field is in
+    pub collection: Option<usize>,
+    pub usages: Vec<Usage>,
+    // from the start of the report, not counting the report id byte
+    pub bit_offset: u32,
+    pub report_size: u32,
+    pub report_count: u32,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+    pub physical_minimum: i32,
+    pub physical_maximum: i32,
+    pub unit_exponent: i32,
+    pub unit: u32,
+    // FIELD_CONSTANT, FIELD_VARIABLE, ...
+    pub flags: u32,
+}
+
+impl ReportField {
+    pub fn is_constant(&self) -> bool {
+        self.flags & FIELD_CONSTANT != 0
+    }
+
+    // a variable field has one value per usage, an array field holds the
+    // indexes of the usages that are active
+    pub fn is_variable(&self) -> bool {
+        self.flags & FIELD_VARIABLE != 0
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.flags & FIELD_RELATIVE != 0
+    }
+
+    // parsed fields always fit, see Parser::add_field
+    pub fn bit_len(&self) -> u32 {
+        self.report_size.saturating_mul(self.report_count)
+    }
+
+    // The usage of item index in a variable field. Devices may list fewer
+    // usages than items, the last one then applies to the rest.
+    pub fn usage(&self, index: usize) -> Option<Usage> {
+        self.usages.get(index).or_else(|| self.usages.last()).copied()
+    }
+
+    // The usage an array field's value stands for
+    pub fn array_usage(&self, value: i32) -> Option<Usage> {
+        if value < self.logical_minimum || value > self.logical_maximum {
+            return None; // nothing pressed
+        }
+        self.usages.get((value - self.logical_minimum) as usize).copied()
+    }
+
+    // Without a physical range the logical one is used, section 6.2.2.7
+    pub fn physical_range(&self) -> (i32, i32) {
+        if self.physical_minimum == 0 && self.physical_maximum == 0 {
+            (self.logical_minimum, self.logical_maximum)
+        } else {
+            (self.physical_minimum, self.physical_maximum)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportDescriptor {
+    pub collections: Vec<Collection>,
+    pub fields: Vec<ReportField>,
+}
+
+impl ReportDescriptor {
+    pub fn parse(bytes: &[u8]) -> Result<ReportDescriptor> {
+        Parser::default().parse(bytes)
+    }
+
+    pub fn uses_report_ids(&self) -> bool {
+        self.fields.iter().any(|field| field.report_id != 0)
+    }
+
+    pub fn report_ids(&self, report_type: ReportType) -> Vec<u8> {
+        let mut report_ids: Vec<u8> = self.fields.iter()
+            .filter(|field| field.report_type == report_type)
+            .map(|field| field.report_id)
+            .collect();
+        report_ids.sort();
+        report_ids.dedup();
+        report_ids
+    }
+
+    pub fn report_fields(&self, report_type: ReportType, report_id: u8) -> impl Iterator<Item = &ReportField> {
+        self.fields.iter()
+            .filter(move |field| field.report_type == report_type && field.report_id == report_id)
+    }
+
+    // The buffer length for a report in hidapi's convention, so counting the
+    // report id byte even for devices without numbered reports. None if the
+    // device has no such report.
+    pub fn report_len(&self, report_type: ReportType, report_id: u8) -> Option<usize> {
+        self.report_fields(report_type, report_id)
+            .map(|field| field.bit_offset + field.bit_len())
+            .max()
+            .map(|bits| 1 + (bits as usize).div_ceil(8))
+    }
+}
+
+// Global items, section 6.2.2.7. Push and Pop save and restore these.
+#[derive(Debug, Clone, Default)]
+struct Globals {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: Item,
+    physical_minimum: i32,
+    physical_maximum: i32,
+    unit_exponent: i32,
+    unit: u32,
+    report_size: u32,
+    report_id: u8,
+    report_count: u32,
+}
+
+// Local items, section 6.2.2.8. They only last until the next main item.
+#[derive(Debug, Default)]
+struct Locals {
+    usages: Vec<Usage>,
+    usage_minimum: Option<Usage>,
+    usage_maximum: Option<Usage>,
+}
+
+#[derive(Debug, Default)]
+struct Parser {
+    globals: Globals,
+    global_stack: Vec<Globals>,
+    locals: Locals,
+    // the collections that are open, innermost last
+    open_collections: Vec<usize>,
+    bit_offsets: HashMap<(ReportType, u8), u32>,
+    descriptor: ReportDescriptor,
+}
+
+impl Parser {
+    fn parse(mut self, bytes: &[u8]) -> Result<ReportDescriptor> {
+        for item in items(bytes) {
+            let item = item?;
+            match item.tag {
+                // main items
+                0x80 => self.add_field(ReportType::Input, &item)?,
+                0x90 => self.add_field(ReportType::Output, &item)?,
+                0xb0 => self.add_field(ReportType::Feature, &item)?,
+                0xa0 => self.open_collection(&item),
+                0xc0 => {
+                    if self.open_collections.pop().is_none() {
+                        return Err(Error::invalid_descriptor("End Collection without a Collection"));
+                    }
+                    self.locals = Locals::default();
+                }
+                // global items
+                0x04 => self.globals.usage_page = item.data as u16,
+                0x14 => self.globals.logical_minimum = item.signed(),
+                0x24 => self.globals.logical_maximum = item,
+                0x34 => self.globals.physical_minimum = item.signed(),
+                0x44 => self.globals.physical_maximum = item.signed(),
+                0x54 => self.globals.unit_exponent = unit_exponent(item.data),
+                0x64 => self.globals.unit = item.data,
+                0x74 => self.globals.report_size = item.data,
+                0x84 => {
+                    if item.data == 0 || item.data > 0xff {
+                        return Err(Error::invalid_descriptor(format!("bad report id {}", item.data)));
+                    }
+                    self.globals.report_id = item.data as u8;
+                }
+                0x94 => self.globals.report_count = item.data,
+                0xa4 => self.global_stack.push(self.globals.clone()),
+                0xb4 => {
+                    self.globals = self.global_stack.pop()
+                        .ok_or_else(|| Error::invalid_descriptor("Pop without a Push"))?;
+                }
+                // local items
+                0x08 => {
+                    let usage = self.usage(&item);
+                    self.locals.usages.push(usage);
+                }
+                0x18 => self.locals.usage_minimum = Some(self.usage(&item)),
+                0x28 => self.locals.usage_maximum = Some(self.usage(&item)),
+                // designators, strings and delimiters aren't needed
+                _ => {}
+            }
+        }
+
+        if !self.open_collections.is_empty() {
+            return Err(Error::invalid_descriptor("Collection without an End Collection"));
+        }
+        Ok(self.descriptor)
+    }
+
+    // a 4 byte usage carries its own page
+    fn usage(&self, item: &Item) -> Usage {
+        let page = if item.size == 4 { (item.data >> 16) as u16 } else { self.globals.usage_page };
+        Usage {
+            page,
+            id: item.data as u16,
+        }
+    }
+
+    // Usages in the order they were given, ranges expanded in place
+    fn take_usages(&mut self) -> Vec<Usage> {
+        let mut locals = std::mem::take(&mut self.locals);
+        if let (Some(minimum), Some(maximum)) = (locals.usage_minimum, locals.usage_maximum) {
+            locals.usages.extend((minimum.id..=maximum.id).map(|id| Usage {
+                page: minimum.page,
+                id,
+            }));
+        }
+        locals.usages
+    }
+
+    fn open_collection(&mut self, item: &Item) {
+        let usage_page = self.globals.usage_page;
+        let usage = self.take_usages().first().copied().unwrap_or(Usage {
+            page: usage_page,
+            id: 0,
+        });
+        self.descriptor.collections.push(Collection {
+            collection_type: item.data as u8,
+            usage,
+            parent: self.open_collections.last().copied(),
+        });
+        self.open_collections.push(self.descriptor.collections.len() - 1);
+    }
+
+    fn add_field(&mut self, report_type: ReportType, item: &Item) -> Result<()> {
+        let usages = self.take_usages();
+        let globals = &self.globals;
+        let bit_len = globals.report_size.checked_mul(globals.report_count)
+            .ok_or_else(|| Error::invalid_descriptor("report is too long"))?;
+
+        let bit_offset = self.bit_offsets.entry((report_type, globals.report_id)).or_insert(0);
+        let field = ReportField {
+            report_type,
+            report_id: globals.report_id,
+            collection: self.open_collections.last().copied(),
+            usages,
+            bit_offset: *bit_offset,
+            report_size: globals.report_size,
+            report_count: globals.report_count,
+            logical_minimum: globals.logical_minimum,
+            logical_maximum: logical_maximum(globals.logical_minimum, &globals.logical_maximum),
+            physical_minimum: globals.physical_minimum,
+            physical_maximum: globals.physical_maximum,
+            unit_exponent: globals.unit_exponent,
+            unit: globals.unit,
+            flags: item.data,
+        };
+        *bit_offset = bit_offset.checked_add(bit_len)
+            .ok_or_else(|| Error::invalid_descriptor("report is too long"))?;
+
+        if bit_len > 0 {
+            self.descriptor.fields.push(field);
+        }
+        Ok(())
+    }
+}
+
+// Lots of devices give e.g. 0xff in one byte for 255, which read as signed is
+// -1. Read it unsigned when that is the only way it makes sense.
+fn logical_maximum(logical_minimum: i32, logical_maximum: &Item) -> i32 {
+    let signed = logical_maximum.signed();
+    if signed < logical_minimum && logical_minimum >= 0 {
+        logical_maximum.data as i32
+    } else {
+        signed
+    }
+}
+
+// Unit Exponent is a 4 bit two's complement number, 0x0e is -2
+pub(crate) fn unit_exponent(data: u32) -> i32 {
+    match data {
+        0..=7 => data as i32,
+        8..=15 => data as i32 - 16,
+        _ => data as i32,
+    }
+}
+
+// A short item, section 6.2.2.2. tag has the type bits but not the size ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Item {
+    pub(crate) tag: u8,
+    pub(crate) size: usize,
+    pub(crate) data: u32,
+}
+
+impl Item {
+    fn signed(&self) -> i32 {
+        match self.size {
+            1 => self.data as u8 as i8 as i32,
+            2 => self.data as u16 as i16 as i32,
+            _ => self.data as i32,
+        }
+    }
+}
+
+// Walks the short items of a descriptor, long items are skipped since nothing
+// defines any. A truncated item is an error and ends the walk.
+pub(crate) fn items(bytes: &[u8]) -> Items<'_> {
+    Items {
+        bytes,
+        position: 0,
+    }
+}
+
+pub(crate) struct Items<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = Result<Item>;
+
+    fn next(&mut self) -> Option<Result<Item>> {
+        loop {
+            let prefix = *self.bytes.get(self.position)?;
+            if prefix == 0xfe {
+                // long item, size is in the next byte
+                match self.bytes.get(self.position + 1) {
+                    Some(&size) => {
+                        self.position += 3 + size as usize;
+                        continue;
+                    }
+                    None => return Some(self.truncated()),
+                }
+            }
+
+            let size = match prefix & 0x03 {
+                3 => 4,
+                size => size as usize,
+            };
+            let data = match self.bytes.get(self.position + 1..self.position + 1 + size) {
+                Some(data) => data,
+                None => return Some(self.truncated()),
+            };
+            self.position += 1 + size;
+            return Some(Ok(Item {
+                tag: prefix & 0xfc,
+                size,
+                data: data.iter().rev().fold(0u32, |value, &b| (value << 8) | b as u32),
+            }));
+        }
+    }
+}
+
+impl<'a> Items<'a> {
+    fn truncated(&mut self) -> Result<Item> {
+        let position = self.position;
+        self.position = self.bytes.len();
+        Err(Error::invalid_descriptor(format!("item at byte {} is truncated", position)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A gamepad with four buttons and two signed axes in input report 1 and
+    // one value in output report 2. Push and Pop keep the axes' logical range
+    // away from the output report.
+    const GAMEPAD: &[u8] = &[
+        0x05, 0x01,       // Usage Page (Generic Desktop)
+        0x09, 0x05,       // Usage (Gamepad)
+        0xa1, 0x01,       // Collection (Application)
+        0x85, 0x01,       //   Report ID (1)
+        0x05, 0x09,       //   Usage Page (Button)
+        0x19, 0x01,       //   Usage Minimum (1)
+        0x29, 0x04,       //   Usage Maximum (4)
+        0x15, 0x00,       //   Logical Minimum (0)
+        0x25, 0x01,       //   Logical Maximum (1)
+        0x75, 0x01,       //   Report Size (1)
+        0x95, 0x04,       //   Report Count (4)
+        0x81, 0x02,       //   Input (Data, Variable)
+        0x75, 0x04,       //   Report Size (4)
+        0x95, 0x01,       //   Report Count (1)
+        0x81, 0x03,       //   Input (Constant)
+        0x05, 0x01,       //   Usage Page (Generic Desktop)
+        0xa4,             //   Push
+        0xa1, 0x00,       //   Collection (Physical)
+        0x09, 0x30,       //     Usage (X)
+        0x09, 0x31,       //     Usage (Y)
+        0x15, 0x81,       //     Logical Minimum (-127)
+        0x25, 0x7f,       //     Logical Maximum (127)
+        0x75, 0x08,       //     Report Size (8)
+        0x95, 0x02,       //     Report Count (2)
+        0x81, 0x02,       //     Input (Data, Variable)
+        0xc0,             //   End Collection
+        0xb4,             //   Pop
+        0x85, 0x02,       //   Report ID (2)
+        0x09, 0x32,       //   Usage (Z)
+        0x26, 0xff, 0x00, //   Logical Maximum (255)
+        0x75, 0x08,       //   Report Size (8)
+        0x91, 0x02,       //   Output (Data, Variable)
+        0xc0,             // End Collection
+    ];
+
+    fn usage(page: u16, id: u16) -> Usage {
+        Usage {
+            page,
+            id,
+        }
+    }
+
+    fn invalid(bytes: &[u8]) -> bool {
+        matches!(ReportDescriptor::parse(bytes), Err(Error::InvalidDescriptor(_)))
+    }
+
+    #[test]
+    fn collections() {
+        let descriptor = ReportDescriptor::parse(GAMEPAD).unwrap();
+        assert_eq!(descriptor.collections, vec![
+            Collection {
+                collection_type: COLLECTION_APPLICATION,
+                usage: usage(0x01, 0x05),
+                parent: None,
+            },
+            Collection {
+                collection_type: COLLECTION_PHYSICAL,
+                usage: usage(0x01, 0x00),
+                parent: Some(0),
+            },
+        ]);
+    }
+
+    #[test]
+    fn usage_range_and_padding() {
+        let descriptor = ReportDescriptor::parse(GAMEPAD).unwrap();
+        let fields: Vec<&ReportField> = descriptor.report_fields(ReportType::Input, 1).collect();
+        assert_eq!(fields.len(), 3);
+
+        let buttons = fields[0];
+        assert_eq!(buttons.usages, (1..=4).map(|id| usage(0x09, id)).collect::<Vec<_>>());
+        assert_eq!((buttons.bit_offset, buttons.report_size, buttons.report_count), (0, 1, 4));
+        assert!(buttons.is_variable() && !buttons.is_constant());
+        assert_eq!(buttons.collection, Some(0));
+
+        let padding = fields[1];
+        assert!(padding.is_constant());
+        assert_eq!((padding.bit_offset, padding.bit_len()), (4, 4));
+
+        let axes = fields[2];
+        assert_eq!(axes.usages, vec![usage(0x01, 0x30), usage(0x01, 0x31)]);
+        assert_eq!((axes.bit_offset, axes.report_size, axes.report_count), (8, 8, 2));
+        assert_eq!((axes.logical_minimum, axes.logical_maximum), (-127, 127));
+        assert_eq!(axes.collection, Some(1));
+    }
+
+    #[test]
+    fn report_ids_and_lengths() {
+        let descriptor = ReportDescriptor::parse(GAMEPAD).unwrap();
+        assert!(descriptor.uses_report_ids());
+        assert_eq!(descriptor.report_ids(ReportType::Input), vec![1]);
+        assert_eq!(descriptor.report_ids(ReportType::Output), vec![2]);
+        assert_eq!(descriptor.report_ids(ReportType::Feature), Vec::<u8>::new());
+        // report id byte included
+        assert_eq!(descriptor.report_len(ReportType::Input, 1), Some(4));
+        assert_eq!(descriptor.report_len(ReportType::Output, 2), Some(2));
+        assert_eq!(descriptor.report_len(ReportType::Input, 2), None);
+    }
+
+    #[test]
+    fn pop_restores_globals() {
+        let descriptor = ReportDescriptor::parse(GAMEPAD).unwrap();
+        let z = descriptor.report_fields(ReportType::Output, 2).next().unwrap();
+        assert_eq!(z.usages, vec![usage(0x01, 0x32)]);
+        // the Report Count and Logical Minimum from before the Push
+        assert_eq!((z.bit_offset, z.report_size, z.report_count), (0, 8, 1));
+        assert_eq!((z.logical_minimum, z.logical_maximum), (0, 255));
+    }
+
+    #[test]
+    fn item_data() {
+        let descriptor = ReportDescriptor::parse(&[
+            0x0b, 0x01, 0x00, 0x00, 0xff, // Usage (ff00:0001)
+            0x15, 0x00,                   // Logical Minimum (0)
+            0x25, 0xff,                   // Logical Maximum (255, not -1)
+            0x55, 0x0e,                   // Unit Exponent (-2)
+            0x75, 0x08,                   // Report Size (8)
+            0x95, 0x01,                   // Report Count (1)
+            0xfe, 0x02, 0x00, 0xaa, 0xbb, // long item, skipped
+            0xb1, 0x02,                   // Feature (Data, Variable)
+        ]).unwrap();
+        let field = &descriptor.fields[0];
+        assert_eq!(field.report_type, ReportType::Feature);
+        assert_eq!(field.report_id, 0);
+        assert_eq!(field.usages, vec![usage(0xff00, 0x0001)]);
+        assert_eq!(field.logical_maximum, 255);
+        assert_eq!(field.unit_exponent, -2);
+        assert!(!descriptor.uses_report_ids());
+    }
+
+    #[test]
+    fn field_size_overflow() {
+        // Report Size (0xffffffff), Report Count (2)
+        assert!(invalid(&[0x77, 0xff, 0xff, 0xff, 0xff, 0x95, 0x02, 0x81, 0x02]));
+        // two fields that each fit but not one after the other
+        assert!(invalid(&[0x77, 0x00, 0x00, 0x00, 0x80, 0x95, 0x01, 0x81, 0x02, 0x81, 0x02]));
+    }
+
+    #[test]
+    fn bad_structure() {
+        assert!(invalid(&[0xb4]));                   // Pop without a Push
+        assert!(invalid(&[0xc0]));                   // End Collection first
+        assert!(invalid(&[0xa1, 0x01]));             // Collection left open
+        assert!(invalid(&[0x85, 0x00]));             // Report ID (0)
+        assert!(invalid(&[0x86, 0x00, 0x01]));       // Report ID (256)
+        assert!(invalid(&[0x05, 0x01, 0x26, 0xff])); // truncated item
+        assert!(invalid(&[0xfe]));                   // truncated long item
+    }
+}
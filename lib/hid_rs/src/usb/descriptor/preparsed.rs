@@ -0,0 +1,301 @@
+use winapi::shared::hidpi::{
+    HidP_Feature,
+    HidP_GetButtonCaps,
+    HidP_GetCaps,
+    HidP_GetLinkCollectionNodes,
+    HidP_GetValueCaps,
+    HidP_InitializeReportForID,
+    HidP_Input,
+    HidP_Output,
+    HidP_SetUsageValue,
+    HidP_SetUsageValueArray,
+    HidP_SetUsages,
+    HIDP_BUTTON_CAPS,
+    HIDP_CAPS,
+    HIDP_LINK_COLLECTION_NODE,
+    HIDP_REPORT_TYPE,
+    HIDP_STATUS_SUCCESS,
+    HIDP_VALUE_CAPS,
+    PHIDP_PREPARSED_DATA,
+};
+use winapi::shared::hidsdi::{
+    HidD_FreePreparsedData,
+    HidD_GetPreparsedData,
+};
+use winapi::shared::minwindef::{
+    ULONG,
+};
+use winapi::shared::ntdef::{
+    NTSTATUS,
+};
+use winapi::um::winnt::{
+    HANDLE,
+    PCHAR,
+};
+
+use std::ptr;
+
+use super::{
+    unit_exponent,
+    Collection,
+    ReportDescriptor,
+    ReportField,
+    ReportType,
+    Usage,
+    FIELD_VARIABLE,
+};
+use crate::error::{
+    Error, Result,
+};
+
+// Windows parses the descriptor itself and only hands out what it made of
+// it, the capabilities of one top-level collection. Those don't say where a
+// field sits in its report, so every field is set in an empty report and the
+// bits that changed give it away.
+// https://docs.microsoft.com/en-us/windows-hardware/drivers/hid/preparsed-data
+pub(crate) fn from_preparsed_data(handle: HANDLE) -> Result<ReportDescriptor> {
+    let mut preparsed_data: PHIDP_PREPARSED_DATA = ptr::null_mut();
+    // returns a BOOLEAN rather than a BOOL
+    if 0 == unsafe { HidD_GetPreparsedData(handle, &mut preparsed_data) } {
+        return Err(Error::last_os_error());
+    }
+    let descriptor = unsafe { rebuild_descriptor(preparsed_data) };
+    unsafe { HidD_FreePreparsedData(preparsed_data) };
+    descriptor
+}
+
+unsafe fn rebuild_descriptor(preparsed_data: PHIDP_PREPARSED_DATA) -> Result<ReportDescriptor> {
+    let mut caps: HIDP_CAPS = std::mem::zeroed();
+    check(HidP_GetCaps(preparsed_data, &mut caps), "could not read HID capabilities")?;
+
+    let mut descriptor = ReportDescriptor::default();
+
+    // node 0 is the top-level collection, parents are node indexes too
+    let mut node_count = caps.NumberLinkCollectionNodes as ULONG;
+    let mut nodes = vec![std::mem::zeroed::<HIDP_LINK_COLLECTION_NODE>(); node_count as usize];
+    check(
+        HidP_GetLinkCollectionNodes(nodes.as_mut_ptr(), &mut node_count, preparsed_data),
+        "could not read HID collections"
+    )?;
+    for (index, node) in nodes.iter().take(node_count as usize).enumerate() {
+        descriptor.collections.push(Collection {
+            collection_type: node.CollectionType() as u8,
+            usage: Usage {
+                page: node.LinkUsagePage,
+                id: node.LinkUsage,
+            },
+            parent: if index == 0 { None } else { Some(node.Parent as usize) },
+        });
+    }
+
+    let report_types = [
+        (ReportType::Input, HidP_Input, caps.InputReportByteLength, caps.NumberInputButtonCaps, caps.NumberInputValueCaps),
+        (ReportType::Output, HidP_Output, caps.OutputReportByteLength, caps.NumberOutputButtonCaps, caps.NumberOutputValueCaps),
+        (ReportType::Feature, HidP_Feature, caps.FeatureReportByteLength, caps.NumberFeatureButtonCaps, caps.NumberFeatureValueCaps),
+    ];
+    for &(report_type, hidp_report_type, report_len, button_count, value_count) in report_types.iter() {
+        let prober = Prober {
+            report_type: hidp_report_type,
+            report_len: report_len as usize,
+            preparsed_data,
+        };
+
+        let mut value_count = value_count;
+        let mut value_caps = vec![std::mem::zeroed::<HIDP_VALUE_CAPS>(); value_count as usize];
+        if value_count > 0 {
+            check(
+                HidP_GetValueCaps(hidp_report_type, value_caps.as_mut_ptr(), &mut value_count, preparsed_data),
+                "could not read HID value capabilities"
+            )?;
+        }
+        for cap in value_caps.iter().take(value_count as usize) {
+            for field in value_fields(&prober, report_type, cap)? {
+                descriptor.fields.push(field);
+            }
+        }
+
+        let mut button_count = button_count;
+        let mut button_caps = vec![std::mem::zeroed::<HIDP_BUTTON_CAPS>(); button_count as usize];
+        if button_count > 0 {
+            check(
+                HidP_GetButtonCaps(hidp_report_type, button_caps.as_mut_ptr(), &mut button_count, preparsed_data),
+                "could not read HID button capabilities"
+            )?;
+        }
+        for cap in button_caps.iter().take(button_count as usize) {
+            descriptor.fields.push(button_field(&prober, report_type, cap)?);
+        }
+    }
+
+    // back in the order the descriptor had them
+    descriptor.fields.sort_by_key(|field| (field.report_type as u8, field.report_id, field.bit_offset));
+    Ok(descriptor)
+}
+
+// A ranged value cap is one field per usage, a value array is one field with
+// report_count values
+unsafe fn value_fields(prober: &Prober, report_type: ReportType, cap: &HIDP_VALUE_CAPS) -> Result<Vec<ReportField>> {
+    let usages: Vec<u16> = if cap.IsRange != 0 {
+        let range = cap.u.Range();
+        (range.UsageMin..=range.UsageMax).collect()
+    } else {
+        vec![cap.u.NotRange().Usage]
+    };
+    let report_count = if cap.IsRange != 0 { 1 } else { cap.ReportCount as u32 };
+    let bit_len = cap.BitSize as u32 * report_count;
+
+    let mut fields = Vec::new();
+    for usage in usages {
+        let set_to = |fill: u8| {
+            move |report: PCHAR, report_len: ULONG| {
+                if report_count > 1 {
+                    let mut value = vec![fill; (bit_len as usize).div_ceil(8)];
+                    HidP_SetUsageValueArray(
+                        prober.report_type, cap.UsagePage, cap.LinkCollection, usage,
+                        value.as_mut_ptr() as PCHAR, value.len() as u16,
+                        prober.preparsed_data, report, report_len
+                    )
+                } else {
+                    let all_set = if cap.BitSize >= 32 { !0 } else { (1u32 << cap.BitSize) - 1 };
+                    HidP_SetUsageValue(
+                        prober.report_type, cap.UsagePage, cap.LinkCollection, usage,
+                        if fill == 0 { 0 } else { all_set },
+                        prober.preparsed_data, report, report_len
+                    )
+                }
+            }
+        };
+        // fields with a null value start out all ones, those show up when cleared
+        let bit_offset = match prober.probe(cap.ReportID, set_to(0xff))? {
+            Some(found) => found,
+            None => prober.probe(cap.ReportID, set_to(0))?
+                .ok_or_else(|| Error::invalid_descriptor("could not locate a HID value"))?,
+        };
+
+        fields.push(ReportField {
+            report_type,
+            report_id: cap.ReportID,
+            collection: Some(cap.LinkCollection as usize),
+            usages: vec![Usage {
+                page: cap.UsagePage,
+                id: usage,
+            }],
+            bit_offset: bit_offset.0,
+            report_size: cap.BitSize as u32,
+            report_count,
+            logical_minimum: cap.LogicalMin,
+            logical_maximum: cap.LogicalMax,
+            physical_minimum: cap.PhysicalMin,
+            physical_maximum: cap.PhysicalMax,
+            unit_exponent: unit_exponent(cap.UnitsExp),
+            unit: cap.Units,
+            flags: cap.BitField as u32,
+        });
+    }
+    Ok(fields)
+}
+
+// Variable buttons are a bit each. Button arrays don't say how wide their
+// slots are, they are taken to be a byte, which is what keyboards and
+// consumer controls use.
+unsafe fn button_field(prober: &Prober, report_type: ReportType, cap: &HIDP_BUTTON_CAPS) -> Result<ReportField> {
+    let usages: Vec<u16> = if cap.IsRange != 0 {
+        let range = cap.u.Range();
+        (range.UsageMin..=range.UsageMax).collect()
+    } else {
+        vec![cap.u.NotRange().Usage]
+    };
+    let (bit_offset, first_value) = prober.probe(cap.ReportID, |report, report_len| {
+        let mut usage = usages[0];
+        let mut usage_count: ULONG = 1;
+        HidP_SetUsages(
+            prober.report_type, cap.UsagePage, cap.LinkCollection,
+            &mut usage, &mut usage_count,
+            prober.preparsed_data, report, report_len
+        )
+    })?.ok_or_else(|| Error::invalid_descriptor("could not locate a HID button"))?;
+
+    let variable = cap.BitField as u32 & FIELD_VARIABLE != 0;
+    let (report_size, report_count, logical_minimum, logical_maximum) = if variable {
+        (1, usages.len() as u32, 0, 1)
+    } else {
+        // an array slot holds the index of the usage, the first one's index
+        // is what the probe wrote
+        (8, 1, first_value as i32, first_value as i32 + usages.len() as i32 - 1)
+    };
+
+    Ok(ReportField {
+        report_type,
+        report_id: cap.ReportID,
+        collection: Some(cap.LinkCollection as usize),
+        usages: usages.iter().map(|&id| Usage {
+            page: cap.UsagePage,
+            id,
+        }).collect(),
+        bit_offset,
+        report_size,
+        report_count,
+        logical_minimum,
+        logical_maximum,
+        physical_minimum: 0,
+        physical_maximum: 0,
+        unit_exponent: 0,
+        unit: 0,
+        flags: cap.BitField as u32,
+    })
+}
+
+struct Prober {
+    report_type: HIDP_REPORT_TYPE,
+    report_len: usize,
+    preparsed_data: PHIDP_PREPARSED_DATA,
+}
+
+impl Prober {
+    // Run set on an initialized report and compare it to an untouched one.
+    // Returns the first bit that changed, not counting the report id byte, and
+    // the byte found there. None if nothing changed.
+    unsafe fn probe<F>(&self, report_id: u8, set: F) -> Result<Option<(u32, u8)>>
+        where F: FnOnce(PCHAR, ULONG) -> NTSTATUS
+    {
+        let empty = self.initialized_report(report_id)?;
+        let mut report = self.initialized_report(report_id)?;
+        check(
+            set(report.as_mut_ptr() as PCHAR, report.len() as ULONG),
+            "could not place a HID field"
+        )?;
+
+        for (index, (&before, &after)) in empty.iter().zip(report.iter()).enumerate().skip(1) {
+            let changed = before ^ after;
+            if changed != 0 {
+                let bit = changed.trailing_zeros();
+                let bit_offset = (index as u32 - 1) * 8 + bit;
+                // the value may straddle a byte boundary
+                let next = *report.get(index + 1).unwrap_or(&0) as u16;
+                let value = (((next << 8) | after as u16) >> bit) as u8;
+                return Ok(Some((bit_offset, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    unsafe fn initialized_report(&self, report_id: u8) -> Result<Vec<u8>> {
+        let mut report = vec![0u8; self.report_len];
+        check(
+            HidP_InitializeReportForID(
+                self.report_type, report_id, self.preparsed_data,
+                report.as_mut_ptr() as PCHAR, report.len() as ULONG
+            ),
+            "could not initialize a HID report"
+        )?;
+        Ok(report)
+    }
+}
+
+fn check(status: NTSTATUS, message: &str) -> Result<()> {
+    if status == HIDP_STATUS_SUCCESS {
+        Ok(())
+    } else {
+        Err(Error::invalid_descriptor(message))
+    }
+}
@@ -1,33 +1,139 @@
 pub(crate) mod info;
 
-use std::io;
+pub use info::HIDDeviceInfo;
 
+use std::time::Duration;
+
+use super::descriptor::ReportDescriptor;
+use super::hid::hid_read_timeout;
+#[cfg(target_os = "linux")]
+use super::hid::hid_get_report_descriptor;
+use crate::error::{
+    Error, Result,
+};
+
+#[cfg(windows)]
 use winapi::shared::minwindef::{TRUE};
+#[cfg(windows)]
 use winapi::um::handleapi::{
     INVALID_HANDLE_VALUE,
     CloseHandle
 };
+#[cfg(windows)]
 use winapi::um::winnt::{
     HANDLE, LPCSTR,
     GENERIC_READ, GENERIC_WRITE,
     FILE_SHARE_READ, FILE_SHARE_WRITE,
 };
+#[cfg(windows)]
 use winapi::um::fileapi::{
     CreateFileA,
     OPEN_EXISTING,
 };
+#[cfg(windows)]
 use winapi::um::winbase::{
     FILE_FLAG_OVERLAPPED,
 };
 
+#[cfg(windows)]
 use std::ptr;
 
+#[cfg(target_os = "linux")]
+use std::fs::OpenOptions;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{
+    AsRawFd, IntoRawFd, RawFd,
+};
+
+#[cfg(windows)]
+pub type RawHandle = HANDLE;
+#[cfg(target_os = "linux")]
+pub type RawHandle = RawFd;
+
+// An open HID device, the handle is closed when this is dropped
 #[derive(Debug)]
 pub struct HIDDevice {
-    pub handle: HANDLE,
+    handle: RawHandle,
+    blocking: bool,
+}
+
+// A device handle can be used from any thread, every I/O call brings its own
+// OVERLAPPED and event on Windows so nothing is shared between them
+#[cfg(windows)]
+unsafe impl Send for HIDDevice {}
+#[cfg(windows)]
+unsafe impl Sync for HIDDevice {}
+
+impl Drop for HIDDevice {
+    fn drop(&mut self) {
+        // nothing useful to do if this fails
+        let _ = close_device(self.handle);
+    }
 }
 
-pub fn open_device(device_path: LPCSTR, enumerate: bool) -> io::Result<HANDLE> {
+// so the fd can be handed to poll/epoll based event loops
+#[cfg(target_os = "linux")]
+impl AsRawFd for HIDDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.handle
+    }
+}
+
+impl HIDDevice {
+    /// Take ownership of an open handle, it will be closed on drop.
+    ///
+    /// # Safety
+    /// `handle` must be open and nothing else may close it or wrap it again.
+    pub unsafe fn from_raw(handle: RawHandle) -> HIDDevice {
+        HIDDevice {
+            handle,
+            blocking: true,
+        }
+    }
+
+    pub fn as_raw(&self) -> RawHandle {
+        self.handle
+    }
+
+    // Give up ownership, the caller is now responsible for closing the handle
+    pub fn into_raw(self) -> RawHandle {
+        let handle = self.handle;
+        std::mem::forget(self);
+        handle
+    }
+
+    // Read one input report into data, blocking until it arrives unless the
+    // device is in non-blocking mode. Ok(0) means there was no report to read.
+    pub fn read(&self, data: &mut [u8]) -> Result<usize> {
+        let timeout = if self.blocking { None } else { Some(Duration::from_millis(0)) };
+        hid_read_timeout(self.handle, data, timeout).map(|len| len as usize)
+    }
+
+    // Like read but gives up with Ok(0) after timeout
+    pub fn read_timeout(&self, data: &mut [u8], timeout: Duration) -> Result<usize> {
+        hid_read_timeout(self.handle, data, Some(timeout)).map(|len| len as usize)
+    }
+
+    pub fn set_blocking(&mut self, blocking: bool) {
+        self.blocking = blocking;
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        self.blocking
+    }
+
+    // Windows never hands out the descriptor itself, there it is rebuilt from
+    // the preparsed data and only covers this device's top-level collection
+    pub fn report_descriptor(&self) -> Result<ReportDescriptor> {
+        #[cfg(windows)]
+        return super::descriptor::from_preparsed_data(self.handle);
+        #[cfg(target_os = "linux")]
+        return ReportDescriptor::parse(&hid_get_report_descriptor(self.handle)?);
+    }
+}
+
+#[cfg(windows)]
+pub fn open_device(device_path: LPCSTR, enumerate: bool) -> Result<HANDLE> {
     let desired_access = if enumerate { 0 } else { GENERIC_WRITE | GENERIC_READ };
     // https://github.com/signal11/hidapi/commit/b5b2e1779b6cd2edda3066bbbf0921a2d6b1c3c0
     let share_mode = FILE_SHARE_READ | FILE_SHARE_WRITE;
@@ -44,17 +150,37 @@ pub fn open_device(device_path: LPCSTR, enumerate: bool) -> io::Result<HANDLE> {
         )
     };
     if handle == INVALID_HANDLE_VALUE {
-        Err(io::Error::last_os_error())
+        Err(Error::last_os_error())
     } else {
         Ok(handle)
     }
 }
 
-fn close_device(handle: HANDLE) -> io::Result<()> {
+// /dev/hidrawN
+#[cfg(target_os = "linux")]
+pub fn open_device(device_path: &str) -> Result<RawFd> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    Ok(file.into_raw_fd())
+}
+
+#[cfg(windows)]
+pub(crate) fn close_device(handle: HANDLE) -> Result<()> {
     let result = TRUE == unsafe { CloseHandle(handle) };
     if result {
         Ok(())
     } else {
-        Err(io::Error::last_os_error())
+        Err(Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn close_device(handle: RawFd) -> Result<()> {
+    if unsafe { libc::close(handle) } == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
     }
 }
@@ -1,11 +1,15 @@
+#[cfg(windows)]
 pub(crate) mod iter;
+#[cfg(windows)]
 pub(crate) mod sys;
+#[cfg(target_os = "linux")]
+pub(crate) mod hidraw;
 
 use std::fmt::{
     Display, Formatter, Result,
 };
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct HIDDeviceInfo {
     pub vendor_id: u16,
     pub product_id: u16,
@@ -17,6 +21,9 @@ pub struct HIDDeviceInfo {
     pub serial_number: String,
     pub manufacturer_string: String,
     pub product_string: String,
+    // of the top-level collection
+    pub usage_page: u16,
+    pub usage: u16,
 }
 
 impl Display for HIDDeviceInfo {
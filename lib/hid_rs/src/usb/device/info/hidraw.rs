@@ -0,0 +1,303 @@
+use std::fs;
+use std::path::{
+    Path, PathBuf,
+};
+
+use super::{HIDDeviceInfo};
+use crate::usb::descriptor::items;
+use crate::error::{
+    Error, Result,
+};
+
+pub const SYSFS_ROOT: &str = "/sys";
+
+// bus types from linux/input.h
+const BUS_USB: u16 = 0x03;
+
+pub struct HIDDeviceInfoIter {
+    sysfs_root: PathBuf,
+    hidraw_nodes: Option<std::vec::IntoIter<PathBuf>>,
+}
+
+impl Iterator for HIDDeviceInfoIter {
+    type Item = Result<HIDDeviceInfo>;
+
+    fn next(&mut self) -> Option<Result<HIDDeviceInfo>> {
+        match self.hidraw_nodes() {
+            Ok(hidraw_nodes) => {
+                // an error is returned but we keep going with the next node
+                hidraw_nodes.next().map(|node| get_device_info(&node))
+            }
+            Err(_) => None, // can't iterate if we can't list the class directory
+        }
+    }
+}
+
+impl HIDDeviceInfoIter {
+    pub fn new<P: AsRef<Path>>(sysfs_root: P) -> HIDDeviceInfoIter {
+        HIDDeviceInfoIter {
+            sysfs_root: sysfs_root.as_ref().to_path_buf(),
+            hidraw_nodes: None,
+        }
+    }
+
+    fn hidraw_nodes(&mut self) -> Result<&mut std::vec::IntoIter<PathBuf>> {
+        if self.hidraw_nodes.is_none() {
+            let nodes = list_hidraw_nodes(&self.sysfs_root)?;
+            self.hidraw_nodes = Some(nodes.into_iter());
+        }
+        Ok(self.hidraw_nodes.as_mut().unwrap()) // cache success
+    }
+}
+
+// /sys/class/hidraw/hidrawN -> ../../devices/.../0003:054C:03D5.0001/hidraw/hidrawN
+fn list_hidraw_nodes(sysfs_root: &Path) -> Result<Vec<PathBuf>> {
+    let mut nodes = vec![];
+    for entry in fs::read_dir(sysfs_root.join("class").join("hidraw"))? {
+        nodes.push(entry?.path());
+    }
+    // read_dir order is unspecified, keep hidraw0 before hidraw1
+    nodes.sort_by_key(|node| hidraw_index(node));
+    Ok(nodes)
+}
+
+fn hidraw_index(node: &Path) -> (usize, String) {
+    let name = node.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let index = name.trim_start_matches("hidraw").parse().unwrap_or(usize::MAX);
+    (index, name.to_string())
+}
+
+// https://github.com/signal11/hidapi/blob/a6a622ffb680c55da0de787ff93b80280498330f/linux/hid.c#L430
+pub fn get_device_info(hidraw_node: &Path) -> Result<HIDDeviceInfo> {
+    let node_name = hidraw_node.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::invalid_descriptor("bad hidraw node name"))?;
+
+    // the HID device this hidraw node belongs to
+    let hid_device = hidraw_node.join("device");
+    let uevent = fs::read_to_string(hid_device.join("uevent"))?;
+
+    let (bus_type, vendor_id, product_id) = match uevent_value(&uevent, "HID_ID").and_then(parse_hid_id) {
+        Some(hid_id) => hid_id,
+        None => return Err(Error::invalid_descriptor("uevent is missing HID_ID")),
+    };
+
+    // hidraw has one node for all top-level collections, like hidapi report the first
+    let report_descriptor = fs::read(hid_device.join("report_descriptor")).unwrap_or_default();
+    let (usage_page, usage) = first_top_level_usage(&report_descriptor).unwrap_or((0, 0));
+
+    let mut device_info = HIDDeviceInfo {
+        vendor_id,
+        product_id,
+        path: format!("/dev/{}", node_name),
+        driver_name: uevent_value(&uevent, "DRIVER").unwrap_or("").to_string(),
+        // the subsystem the HID device sits on, "hid" for anything with a hidraw node
+        class: fs::read_link(hid_device.join("subsystem"))
+            .ok()
+            .and_then(|subsystem| subsystem.file_name().and_then(|n| n.to_str()).map(String::from))
+            .unwrap_or_default(),
+        // bluetooth devices only have these
+        product_string: uevent_value(&uevent, "HID_NAME").unwrap_or("").to_string(),
+        serial_number: uevent_value(&uevent, "HID_UNIQ").unwrap_or("").to_string(),
+        usage_page,
+        usage,
+        ..HIDDeviceInfo::default()
+    };
+
+    if bus_type == BUS_USB {
+        // HID device -> USB interface -> USB device
+        let usb_interface = hid_device.join("..");
+        let usb_device = usb_interface.join("..");
+
+        if let Some(interface_number) = read_attribute(&usb_interface, "bInterfaceNumber") {
+            device_info.interface_number = u16::from_str_radix(&interface_number, 16).unwrap_or(0);
+        }
+        if let Some(release_number) = read_attribute(&usb_device, "bcdDevice") {
+            device_info.release_number = u16::from_str_radix(&release_number, 16).unwrap_or(0);
+        }
+        if let Some(manufacturer) = read_attribute(&usb_device, "manufacturer") {
+            device_info.manufacturer_string = manufacturer;
+        }
+        if let Some(product) = read_attribute(&usb_device, "product") {
+            device_info.product_string = product;
+        }
+        if let Some(serial) = read_attribute(&usb_device, "serial") {
+            device_info.serial_number = serial;
+        }
+    }
+
+    Ok(device_info)
+}
+
+fn read_attribute(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|value| value.trim_end().to_string())
+}
+
+fn uevent_value<'a>(uevent: &'a str, key: &str) -> Option<&'a str> {
+    uevent.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if k == key => Some(v),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+// HID_ID=0003:0000054C:000003D5
+fn parse_hid_id(hid_id: &str) -> Option<(u16, u16, u16)> {
+    let mut parts = hid_id.split(':');
+    let bus_type = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let vendor_id = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let product_id = u32::from_str_radix(parts.next()?, 16).ok()?;
+    Some((bus_type as u16, vendor_id as u16, product_id as u16))
+}
+
+// Walk the short items up to the first top-level Collection and return the
+// Usage Page/Usage in effect for it. Unlike ReportDescriptor::parse this
+// doesn't care what comes after.
+fn first_top_level_usage(report_descriptor: &[u8]) -> Option<(u16, u16)> {
+    let mut usage_page = None;
+    let mut usage = None;
+    for item in items(report_descriptor) {
+        let item = item.ok()?;
+        match item.tag {
+            0x04 => usage_page = Some(item.data as u16), // Usage Page (global)
+            0x08 if usage.is_none() => {
+                // Usage (local), a 4 byte usage carries its own page
+                if item.size == 4 {
+                    usage_page = Some((item.data >> 16) as u16);
+                }
+                usage = Some(item.data as u16);
+            }
+            0xa0 => return Some((usage_page?, usage?)), // Collection
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::usb::hid_enumerate_sysfs;
+
+    // Joystick application collection, the first of the PS Move's two
+    const REPORT_DESCRIPTOR: &[u8] = &[0x05, 0x01, 0x09, 0x04, 0xa1, 0x01, 0xc0];
+
+    // A sysfs tree laid out like the kernel's, removed on drop
+    struct FakeSysfs {
+        root: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new(name: &str) -> FakeSysfs {
+            let root = std::env::temp_dir().join(format!("hid_rs_{}_{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("class").join("hidraw")).unwrap();
+            fs::create_dir_all(root.join("bus").join("hid")).unwrap();
+            FakeSysfs {
+                root,
+            }
+        }
+
+        // devices/<parent>/<hid_device>/hidraw/<node> with class/hidraw/<node>
+        // and <node>/device linking to it like the real thing
+        fn add_hidraw(&self, parent: &str, hid_device: &str, node: &str, uevent: &str) -> PathBuf {
+            let hid_device = self.root.join("devices").join(parent).join(hid_device);
+            let hidraw_node = hid_device.join("hidraw").join(node);
+            fs::create_dir_all(&hidraw_node).unwrap();
+            fs::write(hid_device.join("uevent"), uevent).unwrap();
+            fs::write(hid_device.join("report_descriptor"), REPORT_DESCRIPTOR).unwrap();
+            symlink(self.root.join("bus").join("hid"), hid_device.join("subsystem")).unwrap();
+            symlink(&hid_device, hidraw_node.join("device")).unwrap();
+            symlink(&hidraw_node, self.root.join("class").join("hidraw").join(node)).unwrap();
+            hid_device
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn enumerate_usb_and_bluetooth_nodes() {
+        let sysfs = FakeSysfs::new("enumerate");
+
+        // PS Move on USB, the HID device sits on interface 1-1:1.0 of device 1-1
+        let usb_device = sysfs.root.join("devices").join("usb1").join("1-1");
+        sysfs.add_hidraw(
+            "usb1/1-1/1-1:1.0",
+            "0003:054C:03D5.0001",
+            "hidraw1",
+            "DRIVER=sony\nHID_ID=0003:0000054C:000003D5\nHID_NAME=Sony Motion Controller\nHID_UNIQ=\n",
+        );
+        fs::write(usb_device.join("1-1:1.0").join("bInterfaceNumber"), "00\n").unwrap();
+        fs::write(usb_device.join("bcdDevice"), "0100\n").unwrap();
+        fs::write(usb_device.join("manufacturer"), "Sony Computer Entertainment\n").unwrap();
+        fs::write(usb_device.join("product"), "Motion Controller\n").unwrap();
+
+        // the same controller over Bluetooth, which only has the uevent
+        sysfs.add_hidraw(
+            "virtual/bluetooth/hci0/hci0:11",
+            "0005:054C:03D5.0002",
+            "hidraw0",
+            "DRIVER=sony\nHID_ID=0005:0000054C:000003D5\nHID_NAME=Motion Controller\nHID_UNIQ=00:06:f7:a2:7e:01\n",
+        );
+
+        let devices: Vec<HIDDeviceInfo> = hid_enumerate_sysfs(&sysfs.root)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let bluetooth = &devices[0];
+        assert_eq!(bluetooth.path, "/dev/hidraw0");
+        assert_eq!((bluetooth.vendor_id, bluetooth.product_id), (0x054c, 0x03d5));
+        assert_eq!(bluetooth.product_string, "Motion Controller");
+        assert_eq!(bluetooth.serial_number, "00:06:f7:a2:7e:01");
+        assert_eq!(bluetooth.manufacturer_string, "");
+        assert_eq!(bluetooth.release_number, 0);
+
+        let usb = &devices[1];
+        assert_eq!(usb.path, "/dev/hidraw1");
+        assert_eq!((usb.vendor_id, usb.product_id), (0x054c, 0x03d5));
+        assert_eq!(usb.driver_name, "sony");
+        assert_eq!(usb.class, "hid");
+        assert_eq!(usb.interface_number, 0);
+        assert_eq!(usb.release_number, 0x0100);
+        assert_eq!(usb.manufacturer_string, "Sony Computer Entertainment");
+        assert_eq!(usb.product_string, "Motion Controller");
+        assert_eq!(usb.serial_number, "");
+        assert_eq!((usb.usage_page, usb.usage), (0x01, 0x04));
+    }
+
+    #[test]
+    fn bad_uevent_is_an_error_for_that_node_only() {
+        let sysfs = FakeSysfs::new("bad_uevent");
+        sysfs.add_hidraw("virtual/a", "0005:054C:03D5.0001", "hidraw0", "DRIVER=sony\n");
+        sysfs.add_hidraw(
+            "virtual/b",
+            "0005:054C:03D5.0002",
+            "hidraw1",
+            "HID_ID=0005:0000054C:000003D5\n",
+        );
+
+        let results: Vec<Result<HIDDeviceInfo>> = hid_enumerate_sysfs(&sysfs.root).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap().path, "/dev/hidraw1");
+    }
+
+    #[test]
+    fn missing_class_directory_enumerates_nothing() {
+        let root = std::env::temp_dir().join(format!("hid_rs_missing_{}", std::process::id()));
+        assert_eq!(hid_enumerate_sysfs(&root).count(), 0);
+    }
+}
@@ -1,8 +1,11 @@
 use winapi::um::setupapi::{
     HDEVINFO,
+    SetupDiDestroyDeviceInfoList,
 };
 
-use std::io;
+use crate::error::{
+    Result,
+};
 
 use super::{HIDDeviceInfo};
 use super::sys::{
@@ -11,14 +14,14 @@ use super::sys::{
 };
 
 pub struct HIDDeviceInfoIter {
-    pub index: u32,
-    pub device_info_set: Option<HDEVINFO>,
+    index: u32,
+    device_info_set: Option<HDEVINFO>,
 }
 
 impl Iterator for HIDDeviceInfoIter {
-    type Item = io::Result<HIDDeviceInfo>;
+    type Item = Result<HIDDeviceInfo>;
 
-    fn next(&mut self) -> Option<io::Result<HIDDeviceInfo>> {
+    fn next(&mut self) -> Option<Result<HIDDeviceInfo>> {
         match self.device_info_set() {
             Ok(device_info_set) => {
                 match get_next_hid_device_info(device_info_set, self.index) {
@@ -41,8 +44,23 @@ impl Iterator for HIDDeviceInfoIter {
     }
 }
 
+impl Drop for HIDDeviceInfoIter {
+    fn drop(&mut self) {
+        if let Some(device_info_set) = self.device_info_set.take() {
+            unsafe { SetupDiDestroyDeviceInfoList(device_info_set) };
+        }
+    }
+}
+
 impl HIDDeviceInfoIter {
-    fn device_info_set(&mut self) -> io::Result<HDEVINFO> {
+    pub fn new() -> HIDDeviceInfoIter {
+        HIDDeviceInfoIter {
+            index: 0,
+            device_info_set: None,
+        }
+    }
+
+    fn device_info_set(&mut self) -> Result<HDEVINFO> {
         match self.device_info_set {
             None => {
                 match get_device_info_set() {
@@ -59,7 +77,7 @@ impl HIDDeviceInfoIter {
     }
 }
 
-fn get_next_hid_device_info(device_info_set: HDEVINFO, index: u32) -> (bool, u32, Option<io::Result<HIDDeviceInfo>>) {
+fn get_next_hid_device_info(device_info_set: HDEVINFO, index: u32) -> (bool, u32, Option<Result<HIDDeviceInfo>>) {
     // get device at index
     // open and get info
     // is it the correct vendor/product
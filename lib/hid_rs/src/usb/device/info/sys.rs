@@ -18,64 +18,83 @@ use winapi::um::handleapi::{
 };
 use winapi::shared::hidsdi::{
     HidD_GetAttributes,
+    HidD_GetManufacturerString,
+    HidD_GetProductString,
+    HidD_GetSerialNumberString,
     HIDD_ATTRIBUTES,
 };
+use winapi::um::winnt::{
+    BOOLEAN, HANDLE, PVOID,
+};
 use winapi::shared::guiddef::{GUID};
-use winapi::shared::minwindef::{TRUE};
+use winapi::shared::minwindef::{TRUE, ULONG};
 
-use std::io;
 use std::ptr;
 
 use super::{HIDDeviceInfo};
+use crate::error::{
+    Error, Result,
+};
 use crate::utils::{
     bytes_to_str, str_to_os_str,
 };
 use crate::usb::device::{
-    open_device, close_device,
+    HIDDevice,
+    open_device,
 };
+use crate::usb::hid::get_caps;
 
 // page 4 of https://www.ftdichip.com/Support/Documents/AppNotes/AN_152_Detecting_USB_%20Device_Insertion_and_Removal.pdf
-const GUID_DEVINTERFACE_USB: GUID = GUID {
+pub(crate) const GUID_DEVINTERFACE_HID: GUID = GUID {
     Data1: 0x4d1e55b2,
     Data2: 0xf16f,
     Data3: 0x11cf,
     Data4: [0x88, 0xcb, 0x00, 0x11, 0x11, 0x00, 0x00, 0x30],
 };
 
-pub fn get_device_info(device_info_set: HDEVINFO, device_index: u32) -> (bool, Option<io::Result<HIDDeviceInfo>>) {
+pub fn get_device_info(device_info_set: HDEVINFO, device_index: u32) -> (bool, Option<Result<HIDDeviceInfo>>) {
     let has_next;
     let mut device_interface_data = create_device_interface_data();
-    let mut device_interface_detail_data = create_device_interface_detail_data();
 
     has_next = has_more_devices(device_info_set, device_index, &mut device_interface_data);
     if !has_next {
         return (has_next, None);
     }
 
-    let detail_size = get_device_detail_size(device_info_set, &mut device_interface_data).unwrap();
+    // one odd device shouldn't stop the enumeration, report it and move on
+    let detail_size = match get_device_detail_size(device_info_set, &mut device_interface_data) {
+        Ok(detail_size) => detail_size,
+        Err(error) => return (has_next, Some(Err(error))),
+    };
 
-    get_device_detail(
+    // Bluetooth paths are well over a hundred characters, so sized to fit
+    let mut device_interface_detail_data = DeviceInterfaceDetailData::new(detail_size);
+    if let Err(error) = get_device_detail(
         device_info_set,
         &mut device_interface_data,
-        &mut device_interface_detail_data._native,
+        device_interface_detail_data.as_native(),
         detail_size
-    ).unwrap();
+    ) {
+        return (has_next, Some(Err(error)));
+    }
 
-    let device_path = device_interface_detail_data.device_path();
+    let device_path = match device_interface_detail_data.device_path() {
+        Ok(device_path) => device_path,
+        Err(error) => return (has_next, Some(Err(error))),
+    };
 
-    let has_hid = device_has_hid_driver_bound(device_info_set, device_index);
-    if !has_hid {
-        // this device does not have a HID driver bound
-        return (has_next, None);
-    }
+    let (class, driver_name) = match get_hid_driver(device_info_set, device_index) {
+        Some(hid_driver) => hid_driver,
+        None => return (has_next, None), // this device does not have a HID driver bound
+    };
 
-    let write_handle = match open_device(str_to_os_str(device_path).as_ptr(), true) {
+    let device = match open_device(str_to_os_str(device_path).as_ptr(), true) {
         Ok(handle) if handle == INVALID_HANDLE_VALUE => {
             // could not open device
-            let error = io::Error::new(io::ErrorKind::Other, "got invalid handle to device");
+            let error = Error::last_os_error();
             return (has_next, Some(Err(error)))
         }
-        Ok(handle) => handle, // success
+        Ok(handle) => unsafe { HIDDevice::from_raw(handle) }, // success, closed on drop
         Err(error) => return (has_next, Some(Err(error))),
     };
 
@@ -90,14 +109,30 @@ pub fn get_device_info(device_info_set: HDEVINFO, device_index: u32) -> (bool, O
     let mut hid_attribs: HIDD_ATTRIBUTES = unsafe { std::mem::zeroed() };
     hid_attribs.Size = std::mem::size_of::<HIDD_ATTRIBUTES>() as u32;
     unsafe {
-        HidD_GetAttributes(write_handle, &mut hid_attribs);
+        HidD_GetAttributes(device.as_raw(), &mut hid_attribs);
     }
     device_info.vendor_id = hid_attribs.VendorID;
     device_info.product_id = hid_attribs.ProductID;
     device_info.release_number = hid_attribs.VersionNumber;
     device_info.path = String::from(device_path);
-
-    close_device(write_handle).unwrap();
+    device_info.class = class;
+    device_info.driver_name = driver_name;
+    device_info.interface_number = get_interface_number(device_path);
+
+    // devices don't have to provide these, leave them empty if they don't
+    device_info.serial_number = get_hid_string(&device, HidD_GetSerialNumberString);
+    device_info.manufacturer_string = get_hid_string(&device, HidD_GetManufacturerString);
+    device_info.product_string = get_hid_string(&device, HidD_GetProductString);
+
+    // Windows gives every top-level collection its own device so these
+    // describe exactly this one
+    match get_caps(device.as_raw()) {
+        Ok(caps) => {
+            device_info.usage_page = caps.UsagePage;
+            device_info.usage = caps.Usage;
+        }
+        Err(error) => return (has_next, Some(Err(error))),
+    }
 
     // return device
     (has_next, Some(Ok(device_info)))
@@ -107,11 +142,11 @@ pub fn get_device_info(device_info_set: HDEVINFO, device_index: u32) -> (bool, O
 // unsafe winapi garbage
 // ------
 
-pub fn get_device_info_set() -> io::Result<HDEVINFO> {
+pub fn get_device_info_set() -> Result<HDEVINFO> {
     match unsafe {
-        SetupDiGetClassDevsA(&GUID_DEVINTERFACE_USB, ptr::null(), ptr::null_mut(), DIGCF_PRESENT | DIGCF_DEVICEINTERFACE)
+        SetupDiGetClassDevsA(&GUID_DEVINTERFACE_HID, ptr::null(), ptr::null_mut(), DIGCF_PRESENT | DIGCF_DEVICEINTERFACE)
     } {
-        d if d == INVALID_HANDLE_VALUE => Err(io::Error::last_os_error()),
+        d if d == INVALID_HANDLE_VALUE => Err(Error::last_os_error()),
         device_info_set => Ok(device_info_set),
     }
 }
@@ -121,7 +156,7 @@ fn has_more_devices(device_info_set: HDEVINFO, device_index: u32, device_interfa
         SetupDiEnumDeviceInterfaces(
             device_info_set,
             ptr::null_mut(),
-            &GUID_DEVINTERFACE_USB,
+            &GUID_DEVINTERFACE_HID,
             device_index,
             device_interface_data
         )
@@ -132,7 +167,7 @@ fn has_more_devices(device_info_set: HDEVINFO, device_index: u32, device_interfa
 // This will always return false even if there was no OS error
 // https://stackoverflow.com/questions/1054748/setupdigetdeviceinterfacedetail-unexplainable-error
 // https://docs.microsoft.com/en-us/windows/win32/api/setupapi/nf-setupapi-setupdigetdeviceinterfacedetaila#remarks
-fn get_device_detail_size(device_info_set: HDEVINFO, device_interface_data: &mut SP_DEVICE_INTERFACE_DATA) -> io::Result<u32> {
+fn get_device_detail_size(device_info_set: HDEVINFO, device_interface_data: &mut SP_DEVICE_INTERFACE_DATA) -> Result<u32> {
     let mut detail_size: u32 = 0;
     unsafe {
         SetupDiGetDeviceInterfaceDetailA(
@@ -148,7 +183,7 @@ fn get_device_detail_size(device_info_set: HDEVINFO, device_interface_data: &mut
     if detail_size > 0 {
         Ok(detail_size)
     } else {
-        Err(io::Error::last_os_error())
+        Err(Error::last_os_error())
     }
 }
 
@@ -157,7 +192,7 @@ fn get_device_detail(
     device_interface_data: &mut SP_DEVICE_INTERFACE_DATA,
     device_interface_detail_data: &mut SP_DEVICE_INTERFACE_DETAIL_DATA_A,
     detail_size: u32
-    ) -> io::Result<()> {
+    ) -> Result<()> {
     let result = TRUE == unsafe {
         SetupDiGetDeviceInterfaceDetailA(
             device_info_set,
@@ -172,20 +207,20 @@ fn get_device_detail(
     if result {
         Ok(())
     } else {
-        Err(io::Error::last_os_error())
+        Err(Error::last_os_error())
     }
 }
 
-// check device for bound HIDClass driver
+// The class and driver name of the device if it has a HIDClass driver bound
 // https://github.com/signal11/hidapi/blob/a6a622ffb680c55da0de787ff93b80280498330f/windows/hid.c#L345
-fn device_has_hid_driver_bound(device_info_set: HDEVINFO, info_index: u32) -> bool {
+fn get_hid_driver(device_info_set: HDEVINFO, info_index: u32) -> Option<(String, String)> {
     let mut driver_name: [u8; 256] = unsafe { std::mem::zeroed() };
     let mut dev_info_data = create_devinfo_data();
 
     let has_next_info = TRUE == unsafe { SetupDiEnumDeviceInfo(device_info_set, info_index, &mut dev_info_data) };
 
     if !has_next_info {
-        return false
+        return None
     }
 
     // get device class
@@ -203,13 +238,13 @@ fn device_has_hid_driver_bound(device_info_set: HDEVINFO, info_index: u32) -> bo
 
     if !result {
         // no device class so skip the name lookup
-        return false
+        return None
     }
 
-    let class_name = bytes_to_str(&driver_name);
-    if class_name != "HIDClass" {
-        return false
-    }
+    let class_name = match bytes_to_str(&driver_name) {
+        Ok(class_name) if class_name == "HIDClass" => class_name.to_string(),
+        _ => return None,
+    };
 
     // get device driver name
     let result = TRUE == unsafe {
@@ -225,45 +260,75 @@ fn device_has_hid_driver_bound(device_info_set: HDEVINFO, info_index: u32) -> bo
     };
 
     if result {
-        // TODO: return this in device_info
-        // let driver_name_str = bytes_to_str(&driver_name);
-        return true
+        match bytes_to_str(&driver_name) {
+            Ok(driver_name_str) => Some((class_name, driver_name_str.to_string())),
+            Err(_) => None,
+        }
     } else {
-        return false
+        None
+    }
+}
+
+// \\?\hid#vid_054c&pid_03d5&mi_00&col02#...
+// https://github.com/signal11/hidapi/blob/a6a622ffb680c55da0de787ff93b80280498330f/windows/hid.c#L481
+fn get_interface_number(device_path: &str) -> u16 {
+    let device_path = device_path.to_lowercase();
+    match device_path.find("&mi_") {
+        Some(index) => device_path.get(index + 4..index + 6)
+            .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+            .unwrap_or(0),
+        None => 0, // not a composite device
+    }
+}
+
+fn get_hid_string(device: &HIDDevice, get_string: unsafe extern "system" fn(HANDLE, PVOID, ULONG) -> BOOLEAN) -> String {
+    // USB string descriptors top out at 126 UTF-16 characters
+    let mut buffer = [0u16; 256];
+    let result = unsafe {
+        get_string(
+            device.as_raw(),
+            buffer.as_mut_ptr() as PVOID,
+            std::mem::size_of_val(&buffer) as ULONG
+        )
+    };
+    if result == 0 {
+        return String::new();
     }
+    let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    String::from_utf16_lossy(&buffer[..len])
 }
 
 // ------
 // My unsafe garbage
 // ------
 
+// SP_DEVICE_INTERFACE_DETAIL_DATA_A with DevicePath running on to the end of
+// the detail_size bytes Windows asked for. Held as u32s so cbSize is aligned.
 struct DeviceInterfaceDetailData {
-    _native: SP_DEVICE_INTERFACE_DETAIL_DATA_A,
-    _device_path_mem: [u8; 100], // could actually be 4 bytes smaller but meh
+    buffer: Vec<u32>,
+    len: usize,
 }
 
 impl DeviceInterfaceDetailData {
-    fn device_path(&self) -> &str {
-        // Remember when we did that thing there...
-        let device_path_bytes = unsafe {
-            // read all the way through the end of the _native part into the
-            // _device_path_mem region
-            std::slice::from_raw_parts(self._native.DevicePath.as_ptr() as *const u8, 100)
+    fn new(detail_size: u32) -> DeviceInterfaceDetailData {
+        let native_size = std::mem::size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_A>();
+        let len = (detail_size as usize).max(native_size);
+        let mut detail_data = DeviceInterfaceDetailData {
+            buffer: vec![0u32; len.div_ceil(4)],
+            len,
         };
-        bytes_to_str(device_path_bytes)
+        // the size of the fixed part, not of the whole buffer
+        detail_data.as_native().cbSize = native_size as u32;
+        detail_data
     }
-}
 
-fn create_device_interface_detail_data() -> DeviceInterfaceDetailData {
-    let device_interface_detail_data = SP_DEVICE_INTERFACE_DETAIL_DATA_A {
-        cbSize: std::mem::size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_A>() as u32,
-        DevicePath: [0],
-    };
+    fn as_native(&mut self) -> &mut SP_DEVICE_INTERFACE_DETAIL_DATA_A {
+        unsafe { &mut *(self.buffer.as_mut_ptr() as *mut SP_DEVICE_INTERFACE_DETAIL_DATA_A) }
+    }
 
-    // HACK!!! This is a really hacky way to get a chunk of memory for the device detail data
-    DeviceInterfaceDetailData {
-        _native: device_interface_detail_data,
-        _device_path_mem: unsafe { std::mem::zeroed() }, // the device_path will overflow and be stored here
+    fn device_path(&self) -> Result<&str> {
+        let bytes = unsafe { std::slice::from_raw_parts(self.buffer.as_ptr() as *const u8, self.len) };
+        bytes_to_str(&bytes[std::mem::offset_of!(SP_DEVICE_INTERFACE_DETAIL_DATA_A, DevicePath)..])
     }
 }
 
@@ -1,60 +1,514 @@
+#[cfg(windows)]
 use winapi::{
     shared::{
         hidclass::{
             IOCTL_HID_GET_FEATURE,
+            IOCTL_HID_SET_FEATURE,
+        },
+        hidpi::{
+            HidP_GetCaps,
+            HIDP_CAPS,
+            HIDP_STATUS_SUCCESS,
+            PHIDP_PREPARSED_DATA,
+        },
+        hidsdi::{
+            HidD_FreePreparsedData,
+            HidD_GetPreparsedData,
         },
         minwindef::{
             DWORD,
+            FALSE,
             TRUE,
+            LPCVOID,
             LPVOID,
         },
         winerror::{
             ERROR_IO_PENDING,
+            ERROR_OPERATION_ABORTED,
         },
     },
     um::{
+        errhandlingapi::{
+            GetLastError,
+        },
+        fileapi::{
+            ReadFile,
+            WriteFile,
+        },
+        handleapi::{
+            CloseHandle,
+        },
         ioapiset::{
+            CancelIo,
             DeviceIoControl,
             GetOverlappedResult,
         },
         minwinbase::{
             OVERLAPPED,
         },
+        synchapi::{
+            CreateEventA,
+            WaitForSingleObject,
+        },
+        winbase::{
+            INFINITE,
+            WAIT_OBJECT_0,
+        },
         winnt::{
             HANDLE,
         }
     },
 };
+#[cfg(windows)]
+use std::ptr;
+#[cfg(target_os = "linux")]
 use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{
+    RawFd,
+};
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::time::Instant;
+
+use crate::error::{
+    Error, Result,
+};
+
+// Report buffers follow hidapi's conventions: data[0] is the report id, or 0
+// for devices that don't use numbered reports, and the rest is the report.
+
+#[cfg(windows)]
+pub fn hid_get_feature_report(handle: HANDLE, data: &mut [u8]) -> Result<u32> {
+    let bytes_returned = device_io_control(
+        handle,
+        IOCTL_HID_GET_FEATURE,
+        data.as_mut_ptr() as LPVOID, data.len() as u32,
+        data.as_mut_ptr() as LPVOID, data.len() as u32,
+    )?;
+    // the count leaves out the report id in data[0], add it back like hidapi
+    // so both platforms return the same length
+    // https://github.com/signal11/hidapi/blob/a6a622ffb680c55da0de787ff93b80280498330f/windows/hid.c
+    Ok(bytes_returned + 1)
+}
+
+#[cfg(windows)]
+pub fn hid_set_feature_report(handle: HANDLE, data: &[u8]) -> Result<u32> {
+    device_io_control(
+        handle,
+        IOCTL_HID_SET_FEATURE,
+        data.as_ptr() as LPVOID, data.len() as u32,
+        ptr::null_mut(), 0,
+    )?;
+    // nothing comes back from a set, the whole report went out
+    Ok(data.len() as u32)
+}
 
-pub fn hid_get_feature_report(handle: HANDLE, data: &mut [u8]) -> io::Result<(u32)> {
-    let mut overlapped = OVERLAPPED::default();
-    let mut bytes_returned: DWORD = 0;
+// Read one input report into data. Waits up to timeout for it, forever when
+// timeout is None. Returns Ok(0) if no report arrived in time and
+// Error::Disconnected once the device is unplugged.
+#[cfg(windows)]
+pub fn hid_read_timeout(handle: HANDLE, data: &mut [u8], timeout: Option<Duration>) -> Result<u32> {
+    // reads must be as long as the longest input report
+    let caps = get_caps(handle)?;
+    let mut buffer = vec![0u8; caps.InputReportByteLength as usize];
+
+    let bytes_read = with_overlapped(|overlapped| read_overlapped(handle, &mut buffer, overlapped, timeout))? as usize;
+    if bytes_read == 0 {
+        return Ok(0);
+    }
+
+    // Windows puts a 0 report id in front of reports from devices that don't
+    // use numbered reports, drop it like hidapi does
+    // https://github.com/signal11/hidapi/blob/a6a622ffb680c55da0de787ff93b80280498330f/windows/hid.c#L761
+    let report = if buffer[0] == 0 { &buffer[1..bytes_read] } else { &buffer[..bytes_read] };
+    let len = report.len().min(data.len());
+    data[..len].copy_from_slice(&report[..len]);
+    Ok(len as u32)
+}
+
+#[cfg(windows)]
+fn read_overlapped(handle: HANDLE, buffer: &mut [u8], overlapped: &mut OVERLAPPED, timeout: Option<Duration>) -> Result<u32> {
+    let mut bytes_read: DWORD = 0;
+
+    let result = TRUE == unsafe {
+        ReadFile(
+            handle,
+            buffer.as_mut_ptr() as LPVOID, buffer.len() as u32,
+            ptr::null_mut(),
+            overlapped
+        )
+    };
+    if !result {
+        let error = unsafe { GetLastError() };
+        if error != ERROR_IO_PENDING {
+            return Err(Error::from_os_code(error as i32));
+        }
+    }
+
+    let wait_ms = match timeout {
+        None => INFINITE,
+        Some(timeout) => timeout.as_millis().min((INFINITE - 1) as u128) as DWORD,
+    };
+    if unsafe { WaitForSingleObject(overlapped.hEvent, wait_ms) } != WAIT_OBJECT_0 {
+        // no report yet, give up on this read. It may still complete before
+        // the cancel lands so the result below decides.
+        unsafe { CancelIo(handle) };
+    }
 
     if TRUE == unsafe {
-        DeviceIoControl(
+        GetOverlappedResult(
             handle,
-            IOCTL_HID_GET_FEATURE,
-            data.as_mut_ptr() as LPVOID, data.len() as u32,
-            data.as_mut_ptr() as LPVOID, data.len() as u32,
-            &mut bytes_returned,
-            &mut overlapped
+            overlapped,
+            &mut bytes_read,
+            TRUE // wait for the cancel
         )
     } {
+        Ok(bytes_read)
+    } else {
+        match unsafe { GetLastError() } {
+            ERROR_OPERATION_ABORTED => Ok(0), // timed out
+            error => Err(Error::from_os_code(error as i32)),
+        }
+    }
+}
+
+// Send an output report over the interrupt OUT endpoint (or the control pipe
+// if the device has none)
+#[cfg(windows)]
+pub fn hid_write(handle: HANDLE, data: &[u8]) -> Result<u32> {
+    // Windows wants a buffer as long as the longest output report even when
+    // this report is shorter
+    // https://github.com/signal11/hidapi/blob/a6a622ffb680c55da0de787ff93b80280498330f/windows/hid.c#L655
+    let output_report_length = get_caps(handle)?.OutputReportByteLength as usize;
+    let mut buffer = data.to_vec();
+    if buffer.len() < output_report_length {
+        buffer.resize(output_report_length, 0);
+    }
+
+    with_overlapped(|overlapped| {
+        let mut bytes_written: DWORD = 0;
+        let result = TRUE == unsafe {
+            WriteFile(
+                handle,
+                buffer.as_ptr() as LPCVOID, buffer.len() as u32,
+                ptr::null_mut(),
+                overlapped
+            )
+        };
+        if !result && unsafe { GetLastError() } != ERROR_IO_PENDING {
+            return Err(Error::last_os_error());
+        }
+
+        if TRUE == unsafe {
+            // wait for result
+            GetOverlappedResult(
+                handle,
+                overlapped,
+                &mut bytes_written,
+                TRUE // wait
+            )
+        } {
+            // bytes_written counts the padding, callers want their own length back
+            Ok(data.len() as u32)
+        } else {
+            Err(Error::last_os_error())
+        }
+    })
+}
+
+#[cfg(windows)]
+fn device_io_control(
+    handle: HANDLE,
+    io_control_code: DWORD,
+    in_buffer: LPVOID, in_buffer_size: u32,
+    out_buffer: LPVOID, out_buffer_size: u32,
+    ) -> Result<u32> {
+    with_overlapped(|overlapped| {
+        let mut bytes_returned: DWORD = 0;
+        let result = TRUE == unsafe {
+            DeviceIoControl(
+                handle,
+                io_control_code,
+                in_buffer, in_buffer_size,
+                out_buffer, out_buffer_size,
+                &mut bytes_returned,
+                overlapped
+            )
+        };
+        // the handle is opened with FILE_FLAG_OVERLAPPED so this may still be in flight
+        if !result && unsafe { GetLastError() } != ERROR_IO_PENDING {
+            return Err(Error::last_os_error());
+        }
+
         if TRUE == unsafe {
             // wait for result
             GetOverlappedResult(
                 handle,
-                &mut overlapped,
+                overlapped,
                 &mut bytes_returned,
                 TRUE // wait
             )
         } {
             Ok(bytes_returned)
         } else {
-            Err(io::Error::last_os_error())
+            Err(Error::last_os_error())
         }
+    })
+}
+
+// Run an overlapped call with its own event to wait on. Without one
+// GetOverlappedResult waits on the file handle, which any other call on the
+// same device may signal first.
+#[cfg(windows)]
+fn with_overlapped<T, F: FnOnce(&mut OVERLAPPED) -> Result<T>>(call: F) -> Result<T> {
+    let event = unsafe { CreateEventA(ptr::null_mut(), TRUE, FALSE, ptr::null()) };
+    if event.is_null() {
+        return Err(Error::last_os_error());
+    }
+    let mut overlapped = OVERLAPPED {
+        hEvent: event,
+        ..OVERLAPPED::default()
+    };
+    let result = call(&mut overlapped);
+    unsafe { CloseHandle(event) };
+    result
+}
+
+#[cfg(windows)]
+pub(crate) fn get_caps(handle: HANDLE) -> Result<HIDP_CAPS> {
+    let mut preparsed_data: PHIDP_PREPARSED_DATA = ptr::null_mut();
+    // returns a BOOLEAN rather than a BOOL
+    if 0 == unsafe { HidD_GetPreparsedData(handle, &mut preparsed_data) } {
+        return Err(Error::last_os_error());
+    }
+
+    let mut caps: HIDP_CAPS = unsafe { std::mem::zeroed() };
+    let status = unsafe { HidP_GetCaps(preparsed_data, &mut caps) };
+    unsafe { HidD_FreePreparsedData(preparsed_data) };
+
+    if status == HIDP_STATUS_SUCCESS {
+        Ok(caps)
     } else {
-        Err(io::Error::last_os_error())
+        Err(Error::invalid_descriptor("could not read HID capabilities"))
+    }
+}
+
+// data[0] must hold the report id, the report is read into data
+#[cfg(target_os = "linux")]
+pub fn hid_get_feature_report(handle: RawFd, data: &mut [u8]) -> Result<u32> {
+    // https://github.com/torvalds/linux/blob/master/include/uapi/linux/hidraw.h
+    let result = unsafe {
+        libc::ioctl(handle, hidiocgfeature(data.len()), data.as_mut_ptr())
+    };
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(result as u32)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn hid_set_feature_report(handle: RawFd, data: &[u8]) -> Result<u32> {
+    let result = unsafe {
+        libc::ioctl(handle, hidiocsfeature(data.len()), data.as_ptr())
+    };
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(result as u32)
+    }
+}
+
+// Same contract as the Windows version above
+#[cfg(target_os = "linux")]
+pub fn hid_read_timeout(handle: RawFd, data: &mut [u8], timeout: Option<Duration>) -> Result<u32> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut poll_fd = libc::pollfd {
+        fd: handle,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    loop {
+        let timeout_ms = match deadline {
+            None => -1,
+            // rounded up, poll would otherwise return just before the deadline
+            Some(deadline) => deadline.saturating_duration_since(Instant::now())
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .min(libc::c_int::MAX as u128) as libc::c_int,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                // a signal, wait again for whatever time is left
+                Some(libc::EINTR) => continue,
+                _ => return Err(Error::from(error)),
+            }
+        }
+        if ready == 0 {
+            return Ok(0); // no report yet
+        }
+        if poll_fd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+            return Err(Error::Disconnected { code: None });
+        }
+
+        let result = unsafe {
+            libc::read(handle, data.as_mut_ptr() as *mut libc::c_void, data.len())
+        };
+        if result >= 0 {
+            return Ok(result as u32);
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EAGAIN) | Some(libc::EINPROGRESS) => return Ok(0),
+            // hidraw answers reads on a removed device with EIO
+            Some(libc::EIO) | Some(libc::ENODEV) => return Err(Error::Disconnected { code: error.raw_os_error() }),
+            _ => return Err(Error::from(error)),
+        }
+    }
+}
+
+// hidraw strips the leading 0 itself for devices without numbered reports
+#[cfg(target_os = "linux")]
+pub fn hid_write(handle: RawFd, data: &[u8]) -> Result<u32> {
+    let result = unsafe {
+        libc::write(handle, data.as_ptr() as *const libc::c_void, data.len())
+    };
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(result as u32)
+    }
+}
+
+// The report descriptor exactly as the device sent it
+#[cfg(target_os = "linux")]
+pub fn hid_get_report_descriptor(handle: RawFd) -> Result<Vec<u8>> {
+    let mut size: libc::c_int = 0;
+    let result = unsafe {
+        libc::ioctl(handle, hidioc_read(0x01, std::mem::size_of::<libc::c_int>()), &mut size)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut descriptor = HidrawReportDescriptor {
+        size: size as u32,
+        value: [0u8; HID_MAX_DESCRIPTOR_SIZE],
+    };
+    let result = unsafe {
+        libc::ioctl(handle, hidioc_read(0x02, std::mem::size_of::<HidrawReportDescriptor>()), &mut descriptor)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+    let len = (descriptor.size as usize).min(HID_MAX_DESCRIPTOR_SIZE);
+    Ok(descriptor.value[..len].to_vec())
+}
+
+// struct hidraw_report_descriptor
+#[cfg(target_os = "linux")]
+const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct HidrawReportDescriptor {
+    size: u32,
+    value: [u8; HID_MAX_DESCRIPTOR_SIZE],
+}
+
+// _IOC(_IOC_WRITE|_IOC_READ, 'H', 0x07, len)
+#[cfg(target_os = "linux")]
+fn hidiocgfeature(len: usize) -> libc::c_ulong {
+    hidioc_read_write(0x07, len)
+}
+
+// _IOC(_IOC_WRITE|_IOC_READ, 'H', 0x06, len)
+#[cfg(target_os = "linux")]
+fn hidiocsfeature(len: usize) -> libc::c_ulong {
+    hidioc_read_write(0x06, len)
+}
+
+#[cfg(target_os = "linux")]
+const IOC_WRITE: libc::c_ulong = 1;
+#[cfg(target_os = "linux")]
+const IOC_READ: libc::c_ulong = 2;
+
+#[cfg(target_os = "linux")]
+fn hidioc_read_write(nr: libc::c_ulong, len: usize) -> libc::c_ulong {
+    hidioc(IOC_WRITE | IOC_READ, nr, len)
+}
+
+#[cfg(target_os = "linux")]
+fn hidioc_read(nr: libc::c_ulong, len: usize) -> libc::c_ulong {
+    hidioc(IOC_READ, nr, len)
+}
+
+#[cfg(target_os = "linux")]
+fn hidioc(direction: libc::c_ulong, nr: libc::c_ulong, len: usize) -> libc::c_ulong {
+    (direction << 30) | ((len as libc::c_ulong) << 16) | ((b'H' as libc::c_ulong) << 8) | nr
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    extern "C" fn ignore_signal(_: libc::c_int) {}
+
+    // A pipe stands in for hidraw, its poll and read behave the same. The
+    // read end is interrupted by SIGUSR1 after 20ms and written to after
+    // write_after, if at all.
+    fn interrupted_read(timeout: Option<Duration>, write_after: Option<Duration>) -> (Result<u32>, Duration) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = ignore_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut());
+        }
+
+        let reader = unsafe { libc::pthread_self() };
+        let writer = fds[1];
+        let signaller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            unsafe { libc::pthread_kill(reader, libc::SIGUSR1) };
+            if let Some(write_after) = write_after {
+                thread::sleep(write_after);
+                hid_write(writer, &[0x01, 0x02]).unwrap();
+            }
+        });
+
+        let start = Instant::now();
+        let mut data = [0u8; 8];
+        let result = hid_read_timeout(fds[0], &mut data, timeout);
+        let elapsed = start.elapsed();
+        signaller.join().unwrap();
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        (result, elapsed)
+    }
+
+    #[test]
+    fn blocking_read_survives_signals() {
+        let (result, _) = interrupted_read(None, Some(Duration::from_millis(50)));
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn timed_read_waits_out_its_timeout() {
+        let timeout = Duration::from_millis(150);
+        let (result, elapsed) = interrupted_read(Some(timeout), None);
+        assert_eq!(result.unwrap(), 0);
+        assert!(elapsed >= timeout, "returned after {:?}", elapsed);
+
+        let (result, _) = interrupted_read(Some(Duration::from_secs(5)), Some(Duration::from_millis(50)));
+        assert_eq!(result.unwrap(), 2);
     }
-}
\ No newline at end of file
+}
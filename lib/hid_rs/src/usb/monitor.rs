@@ -0,0 +1,33 @@
+#[cfg(target_os = "linux")]
+mod uevent;
+#[cfg(windows)]
+mod cfgmgr;
+
+#[cfg(target_os = "linux")]
+pub use uevent::{
+    HidMonitor,
+    UeventSource,
+    NetlinkSource,
+    FakeUeventSource,
+};
+#[cfg(windows)]
+pub use cfgmgr::HidMonitor;
+
+use super::device::HIDDeviceInfo;
+use crate::error::Result;
+
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    Added(HIDDeviceInfo),
+    // the path the device was enumerated with, it can't be opened anymore
+    Removed(String),
+}
+
+// Both monitors block for the next event when iterated
+pub(crate) fn transpose(event: Result<Option<DeviceEvent>>) -> Option<Result<DeviceEvent>> {
+    match event {
+        Ok(Some(event)) => Some(Ok(event)),
+        Ok(None) => None,
+        Err(error) => Some(Err(error)),
+    }
+}
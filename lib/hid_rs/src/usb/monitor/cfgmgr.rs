@@ -0,0 +1,162 @@
+use winapi::shared::minwindef::{
+    DWORD,
+};
+use winapi::shared::winerror::{
+    ERROR_SUCCESS,
+};
+use winapi::um::cfgmgr32::{
+    CONFIGRET,
+    CR_SUCCESS,
+    CM_NOTIFY_ACTION,
+    CM_NOTIFY_ACTION_DEVICEINTERFACEARRIVAL,
+    CM_NOTIFY_ACTION_DEVICEINTERFACEREMOVAL,
+    CM_NOTIFY_FILTER,
+    CM_NOTIFY_FILTER_TYPE_DEVICEINTERFACE,
+    HCMNOTIFICATION,
+    PCM_NOTIFY_CALLBACK,
+    PCM_NOTIFY_EVENT_DATA,
+    PCM_NOTIFY_FILTER,
+    PHCMNOTIFICATION,
+};
+use winapi::um::winnt::{
+    PVOID,
+};
+
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::mpsc::{
+    self, Receiver, RecvTimeoutError, Sender,
+};
+use std::time::Duration;
+
+use super::{
+    DeviceEvent,
+    transpose,
+};
+use crate::error::{
+    Error, Result,
+};
+use crate::usb::device::info::sys::GUID_DEVINTERFACE_HID;
+use crate::usb::hid_enumerate_all;
+
+// missing from winapi, Windows 8 and up
+// https://docs.microsoft.com/en-us/windows/win32/api/cfgmgr32/nf-cfgmgr32-cm_register_notification
+#[link(name = "cfgmgr32")]
+extern "system" {
+    fn CM_Register_Notification(
+        pFilter: PCM_NOTIFY_FILTER,
+        pContext: PVOID,
+        pCallback: PCM_NOTIFY_CALLBACK,
+        pNotifyContext: PHCMNOTIFICATION,
+    ) -> CONFIGRET;
+    fn CM_Unregister_Notification(
+        NotifyContext: HCMNOTIFICATION,
+    ) -> CONFIGRET;
+}
+
+// Device interface arrival/removal notifications for HID devices. They come in
+// on a thread pool thread and get queued up for next_event.
+pub struct HidMonitor {
+    events: Receiver<DeviceEvent>,
+    notification: HCMNOTIFICATION,
+    // handed to the callback as its context, has to outlive the notification
+    _sender: Box<Mutex<Sender<DeviceEvent>>>,
+}
+
+impl HidMonitor {
+    pub fn new() -> Result<HidMonitor> {
+        let (sender, events) = mpsc::channel();
+        let sender = Box::new(Mutex::new(sender));
+
+        let mut filter: CM_NOTIFY_FILTER = unsafe { std::mem::zeroed() };
+        filter.cbSize = std::mem::size_of::<CM_NOTIFY_FILTER>() as u32;
+        filter.FilterType = CM_NOTIFY_FILTER_TYPE_DEVICEINTERFACE;
+        unsafe { filter.u.DeviceInterface_mut().ClassGuid = GUID_DEVINTERFACE_HID };
+
+        let mut notification: HCMNOTIFICATION = ptr::null_mut();
+        let result = unsafe {
+            CM_Register_Notification(
+                &mut filter,
+                &*sender as *const Mutex<Sender<DeviceEvent>> as PVOID,
+                Some(on_notification),
+                &mut notification
+            )
+        };
+        if result != CR_SUCCESS {
+            return Err(Error::Os { code: result as i32 });
+        }
+
+        Ok(HidMonitor {
+            events,
+            notification,
+            _sender: sender,
+        })
+    }
+
+    // Wait up to timeout for a HID device to come or go, forever when timeout
+    // is None. Ok(None) means nothing happened in time.
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> Result<Option<DeviceEvent>> {
+        let event = match timeout {
+            None => self.events.recv().ok(),
+            Some(timeout) => match self.events.recv_timeout(timeout) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => None,
+            },
+        };
+        // we hold a sender so the channel can't close under us
+        Ok(event)
+    }
+}
+
+impl Iterator for HidMonitor {
+    type Item = Result<DeviceEvent>;
+
+    fn next(&mut self) -> Option<Result<DeviceEvent>> {
+        transpose(self.next_event(None))
+    }
+}
+
+impl Drop for HidMonitor {
+    fn drop(&mut self) {
+        // waits for callbacks in flight so the sender is safe to free after
+        unsafe { CM_Unregister_Notification(self.notification) };
+    }
+}
+
+unsafe extern "system" fn on_notification(
+    _notification: HCMNOTIFICATION,
+    context: PVOID,
+    action: CM_NOTIFY_ACTION,
+    event_data: PCM_NOTIFY_EVENT_DATA,
+    _event_data_size: DWORD,
+    ) -> DWORD {
+    let sender = &*(context as *const Mutex<Sender<DeviceEvent>>);
+    let symbolic_link = (*event_data).u.DeviceInterface().SymbolicLink.as_ptr();
+    // SetupDi hands out lower case paths, match them
+    let device_path = wide_to_string(symbolic_link).to_lowercase();
+
+    let event = match action {
+        CM_NOTIFY_ACTION_DEVICEINTERFACEARRIVAL => {
+            hid_enumerate_all()
+                .filter_map(|device_info| device_info.ok())
+                .find(|device_info| device_info.path.eq_ignore_ascii_case(&device_path))
+                .map(DeviceEvent::Added)
+        }
+        CM_NOTIFY_ACTION_DEVICEINTERFACEREMOVAL => Some(DeviceEvent::Removed(device_path)),
+        _ => None,
+    };
+    if let Some(event) = event {
+        // the monitor may be shutting down, nobody to tell
+        let _ = sender.lock().unwrap().send(event);
+    }
+    ERROR_SUCCESS
+}
+
+unsafe fn wide_to_string(wide: *const u16) -> String {
+    let mut len = 0;
+    while *wide.add(len) != 0 {
+        len += 1;
+    }
+    String::from_utf16_lossy(std::slice::from_raw_parts(wide, len))
+}
@@ -0,0 +1,417 @@
+use std::collections::{
+    HashMap, VecDeque,
+};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::{
+    Path, PathBuf,
+};
+use std::sync::{
+    Arc, Mutex,
+};
+use std::time::{
+    Duration, Instant,
+};
+
+use super::{
+    DeviceEvent,
+    transpose,
+};
+use crate::error::{
+    Error, Result,
+};
+use crate::usb::device::info::hidraw::{
+    get_device_info,
+    SYSFS_ROOT,
+};
+
+// udev re-broadcasts kernel uevents on this group once the device node is set
+// up, so by the time we hear about it it can be opened
+// https://github.com/systemd/systemd/blob/v245/src/libudev/libudev-monitor.c
+const UDEV_MONITOR_UDEV: u32 = 2;
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+// Where raw uevent messages come from, netlink in practice and a queue in tests
+pub trait UeventSource {
+    // One uevent message, Ok(None) if none arrived before timeout
+    fn recv_uevent(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>>;
+}
+
+pub struct HidMonitor<S = NetlinkSource> {
+    source: S,
+    sysfs_root: PathBuf,
+}
+
+impl HidMonitor<NetlinkSource> {
+    pub fn new() -> Result<HidMonitor<NetlinkSource>> {
+        Ok(HidMonitor::with_source(NetlinkSource::new()?, SYSFS_ROOT))
+    }
+}
+
+impl<S: UeventSource> HidMonitor<S> {
+    // sysfs_root is where added devices are looked up, see hid_enumerate_sysfs
+    pub fn with_source<P: AsRef<Path>>(source: S, sysfs_root: P) -> HidMonitor<S> {
+        HidMonitor {
+            source,
+            sysfs_root: sysfs_root.as_ref().to_path_buf(),
+        }
+    }
+
+    // Wait up to timeout for a hidraw device to come or go, forever when
+    // timeout is None. Ok(None) means nothing happened in time.
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> Result<Option<DeviceEvent>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let message = match self.source.recv_uevent(remaining)? {
+                Some(message) => message,
+                None => return Ok(None),
+            };
+            // everything that isn't a hidraw node coming or going is skipped
+            if let Some(event) = self.device_event(&parse_uevent(&message)) {
+                return event.map(Some);
+            }
+        }
+    }
+
+    fn device_event(&self, uevent: &HashMap<String, String>) -> Option<Result<DeviceEvent>> {
+        if uevent.get("SUBSYSTEM").map(String::as_str) != Some("hidraw") {
+            return None;
+        }
+        // the kernel sends hidraw0, udev /dev/hidraw0
+        let node_name = uevent.get("DEVNAME")?.rsplit('/').next()?;
+        match uevent.get("ACTION").map(String::as_str) {
+            Some("add") => {
+                let hidraw_node = self.sysfs_root.join("class").join("hidraw").join(node_name);
+                Some(get_device_info(&hidraw_node).map(DeviceEvent::Added))
+            }
+            Some("remove") => Some(Ok(DeviceEvent::Removed(format!("/dev/{}", node_name)))),
+            _ => None,
+        }
+    }
+}
+
+impl<S: UeventSource> Iterator for HidMonitor<S> {
+    type Item = Result<DeviceEvent>;
+
+    fn next(&mut self) -> Option<Result<DeviceEvent>> {
+        transpose(self.next_event(None))
+    }
+}
+
+// A NETLINK_KOBJECT_UEVENT socket listening to udev
+pub struct NetlinkSource {
+    fd: RawFd,
+}
+
+impl NetlinkSource {
+    pub fn new() -> Result<NetlinkSource> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT)
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        // closes fd if bind fails
+        let source = NetlinkSource { fd };
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = UDEV_MONITOR_UDEV;
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t
+            )
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(source)
+    }
+}
+
+impl Drop for NetlinkSource {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl UeventSource for NetlinkSource {
+    fn recv_uevent(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let timeout_ms = match deadline {
+                None => -1,
+                // rounded up, poll would otherwise return just before the deadline
+                Some(deadline) => deadline.saturating_duration_since(Instant::now())
+                    .as_nanos()
+                    .div_ceil(1_000_000)
+                    .min(libc::c_int::MAX as u128) as libc::c_int,
+            };
+            let mut poll_fd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+            if ready < 0 {
+                let error = io::Error::last_os_error();
+                match error.raw_os_error() {
+                    // a signal, wait again for whatever time is left
+                    Some(libc::EINTR) => continue,
+                    _ => return Err(Error::from(error)),
+                }
+            }
+            if ready == 0 {
+                return Ok(None);
+            }
+
+            let mut buffer = vec![0u8; UEVENT_BUFFER_SIZE];
+            let mut sender: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+            let mut sender_len = std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+            let len = unsafe {
+                libc::recvfrom(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(),
+                    0,
+                    &mut sender as *mut libc::sockaddr_nl as *mut libc::sockaddr,
+                    &mut sender_len
+                )
+            };
+            if len < 0 {
+                let error = io::Error::last_os_error();
+                match error.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    _ => return Err(Error::from(error)),
+                }
+            }
+            // any process can send to a netlink socket, only listen to the
+            // kernel and udev
+            if !is_trusted_sender(&sender) {
+                continue;
+            }
+            buffer.truncate(len as usize);
+            return Ok(Some(buffer));
+        }
+    }
+}
+
+// The kernel sends from port 0, udev to its multicast group. Unicasts from
+// other processes have neither.
+fn is_trusted_sender(sender: &libc::sockaddr_nl) -> bool {
+    sender.nl_pid == 0 || sender.nl_groups & UDEV_MONITOR_UDEV != 0
+}
+
+// Replays queued uevents, clones share the queue so events can be pushed after
+// the source is handed to a HidMonitor
+#[derive(Debug, Default, Clone)]
+pub struct FakeUeventSource {
+    uevents: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl FakeUeventSource {
+    pub fn new() -> FakeUeventSource {
+        FakeUeventSource::default()
+    }
+
+    // Queue a kernel style uevent for a hidraw node, e.g. ("add", "hidraw0")
+    pub fn push_hidraw(&self, action: &str, node_name: &str) {
+        let devpath = format!("/devices/virtual/hidraw/{}", node_name);
+        let message = format!(
+            "{}@{}\0ACTION={}\0DEVPATH={}\0SUBSYSTEM=hidraw\0DEVNAME={}\0SEQNUM=1\0",
+            action, devpath, action, devpath, node_name
+        );
+        self.push(message.as_bytes());
+    }
+
+    pub fn push(&self, message: &[u8]) {
+        self.uevents.lock().unwrap().push_back(message.to_vec());
+    }
+}
+
+impl UeventSource for FakeUeventSource {
+    fn recv_uevent(&mut self, _timeout: Option<Duration>) -> Result<Option<Vec<u8>>> {
+        Ok(self.uevents.lock().unwrap().pop_front())
+    }
+}
+
+// Kernel messages are "action@devpath\0KEY=value\0...", udev ones start with a
+// libudev header that points at the same KEY=value list
+fn parse_uevent(message: &[u8]) -> HashMap<String, String> {
+    let properties = if message.starts_with(b"libudev\0") && message.len() >= 24 {
+        let properties_off = read_u32(&message[16..20]) as usize;
+        let properties_len = read_u32(&message[20..24]) as usize;
+        message.get(properties_off..properties_off + properties_len).unwrap_or(&[])
+    } else {
+        // skip the action@devpath header
+        match message.iter().position(|&b| b == 0) {
+            Some(header_end) => &message[header_end + 1..],
+            None => &[],
+        }
+    };
+
+    properties.split(|&b| b == 0)
+        .filter_map(|property| {
+            let property = std::str::from_utf8(property).ok()?;
+            let mut parts = property.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => Some((key.to_string(), value.to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(bytes);
+    u32::from_ne_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // class/hidraw/<node>/device/uevent, all get_device_info needs for a
+    // Bluetooth device
+    struct FakeSysfs {
+        root: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new(name: &str) -> FakeSysfs {
+            let root = std::env::temp_dir().join(format!("hid_rs_uevent_{}_{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            FakeSysfs {
+                root,
+            }
+        }
+
+        fn add_hidraw(&self, node_name: &str, hid_id: &str) {
+            let hid_device = self.root.join("class").join("hidraw").join(node_name).join("device");
+            fs::create_dir_all(&hid_device).unwrap();
+            fs::write(hid_device.join("uevent"), format!("HID_ID={}\nHID_UNIQ=00:06:f7:a2:7e:01\n", hid_id)).unwrap();
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    // libudev header: magic, header and properties offsets and lengths, then
+    // filter fields we don't read
+    fn udev_message(properties: &str) -> Vec<u8> {
+        let header_size = 40u32;
+        let mut message = b"libudev\0".to_vec();
+        message.extend_from_slice(&0xfeed_cafe_u32.to_be_bytes());
+        message.extend_from_slice(&header_size.to_ne_bytes());
+        message.extend_from_slice(&header_size.to_ne_bytes());
+        message.extend_from_slice(&(properties.len() as u32).to_ne_bytes());
+        message.resize(header_size as usize, 0);
+        message.extend_from_slice(properties.as_bytes());
+        message
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let sysfs = FakeSysfs::new("add_remove");
+        sysfs.add_hidraw("hidraw3", "0005:0000054C:000003D5");
+        let source = FakeUeventSource::new();
+        let mut monitor = HidMonitor::with_source(source.clone(), &sysfs.root);
+
+        source.push_hidraw("add", "hidraw3");
+        source.push_hidraw("remove", "hidraw3");
+        match monitor.next_event(None).unwrap() {
+            Some(DeviceEvent::Added(device_info)) => {
+                assert_eq!(device_info.path, "/dev/hidraw3");
+                assert_eq!((device_info.vendor_id, device_info.product_id), (0x054c, 0x03d5));
+                assert_eq!(device_info.serial_number, "00:06:f7:a2:7e:01");
+            },
+            event => panic!("expected Added, got {:?}", event),
+        }
+        match monitor.next_event(None).unwrap() {
+            Some(DeviceEvent::Removed(path)) => assert_eq!(path, "/dev/hidraw3"),
+            event => panic!("expected Removed, got {:?}", event),
+        }
+        assert!(monitor.next_event(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn other_subsystems_and_actions_are_skipped() {
+        let source = FakeUeventSource::new();
+        let mut monitor = HidMonitor::with_source(source.clone(), "/nonexistent");
+
+        source.push(b"add@/devices/pci0000:00/usb1/1-1\0ACTION=add\0SUBSYSTEM=usb\0DEVNAME=bus/usb/001/002\0");
+        source.push_hidraw("change", "hidraw0");
+        source.push_hidraw("remove", "hidraw1");
+        match monitor.next().unwrap().unwrap() {
+            DeviceEvent::Removed(path) => assert_eq!(path, "/dev/hidraw1"),
+            event => panic!("expected Removed, got {:?}", event),
+        }
+        assert!(monitor.next().is_none());
+    }
+
+    #[test]
+    fn udev_messages() {
+        let source = FakeUeventSource::new();
+        let mut monitor = HidMonitor::with_source(source.clone(), "/nonexistent");
+
+        source.push(&udev_message("ACTION=remove\0SUBSYSTEM=hidraw\0DEVNAME=/dev/hidraw2\0SEQNUM=7\0"));
+        match monitor.next_event(None).unwrap() {
+            Some(DeviceEvent::Removed(path)) => assert_eq!(path, "/dev/hidraw2"),
+            event => panic!("expected Removed, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn added_device_missing_from_sysfs_is_an_error() {
+        let source = FakeUeventSource::new();
+        let mut monitor = HidMonitor::with_source(source.clone(), "/nonexistent");
+        source.push_hidraw("add", "hidraw0");
+        assert!(monitor.next_event(None).is_err());
+    }
+
+    #[test]
+    fn only_kernel_and_udev_are_trusted() {
+        let mut sender: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        sender.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        assert!(is_trusted_sender(&sender));
+
+        sender.nl_pid = 4242;
+        assert!(!is_trusted_sender(&sender));
+
+        sender.nl_groups = UDEV_MONITOR_UDEV;
+        assert!(is_trusted_sender(&sender));
+    }
+
+    extern "C" fn ignore_signal(_: libc::c_int) {}
+
+    // Listens on the real socket, so it assumes no device comes or goes in
+    // the 150ms it waits
+    #[test]
+    fn signals_do_not_end_the_wait() {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = ignore_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut());
+        }
+        let mut source = NetlinkSource::new().unwrap();
+        let receiver = unsafe { libc::pthread_self() };
+        let signaller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            unsafe { libc::pthread_kill(receiver, libc::SIGUSR2) };
+        });
+
+        let timeout = Duration::from_millis(150);
+        let start = Instant::now();
+        assert!(source.recv_uevent(Some(timeout)).unwrap().is_none());
+        assert!(start.elapsed() >= timeout, "returned after {:?}", start.elapsed());
+        signaller.join().unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+pub use regex::Regex;
+
+use super::backend::{
+    HidBackend,
+    NativeBackend,
+};
+use super::device::{
+    HIDDevice,
+    HIDDeviceInfo,
+};
+use crate::error::{
+    Error, Result,
+};
+
+// Describes the devices a caller accepts, every criteria that is set has to
+// match. Devices that fail to enumerate are skipped.
+//
+//     let device = HidQuery::new()
+//         .vendor_id(0x054c)
+//         .product_ids(&[0x03d5, 0x0c5e])
+//         .open_first()?;
+#[derive(Debug, Default, Clone)]
+pub struct HidQuery {
+    vendor_id: Option<u16>,
+    product_ids: Vec<u16>,
+    usage_page: Option<u16>,
+    usage: Option<u16>,
+    interface_number: Option<u16>,
+    serial_number: Option<String>,
+    path: Option<Regex>,
+    filters: Vec<fn(&HIDDeviceInfo) -> bool>,
+}
+
+impl HidQuery {
+    pub fn new() -> HidQuery {
+        HidQuery::default()
+    }
+
+    pub fn vendor_id(mut self, vendor_id: u16) -> HidQuery {
+        self.vendor_id = Some(vendor_id);
+        self
+    }
+
+    // may be called more than once, any of the product ids match
+    pub fn product_id(mut self, product_id: u16) -> HidQuery {
+        self.product_ids.push(product_id);
+        self
+    }
+
+    pub fn product_ids(mut self, product_ids: &[u16]) -> HidQuery {
+        self.product_ids.extend_from_slice(product_ids);
+        self
+    }
+
+    pub fn usage_page(mut self, usage_page: u16) -> HidQuery {
+        self.usage_page = Some(usage_page);
+        self
+    }
+
+    pub fn usage(mut self, usage: u16) -> HidQuery {
+        self.usage = Some(usage);
+        self
+    }
+
+    pub fn interface_number(mut self, interface_number: u16) -> HidQuery {
+        self.interface_number = Some(interface_number);
+        self
+    }
+
+    pub fn serial_number(mut self, serial_number: &str) -> HidQuery {
+        self.serial_number = Some(serial_number.to_string());
+        self
+    }
+
+    pub fn path(mut self, path: Regex) -> HidQuery {
+        self.path = Some(path);
+        self
+    }
+
+    // for anything the fields above can't express
+    pub fn filter(mut self, filter: fn(&HIDDeviceInfo) -> bool) -> HidQuery {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn matches(&self, device_info: &HIDDeviceInfo) -> bool {
+        self.vendor_id.is_none_or(|vendor_id| device_info.vendor_id == vendor_id) &&
+        (self.product_ids.is_empty() || self.product_ids.contains(&device_info.product_id)) &&
+        self.usage_page.is_none_or(|usage_page| device_info.usage_page == usage_page) &&
+        self.usage.is_none_or(|usage| device_info.usage == usage) &&
+        self.interface_number.is_none_or(|interface_number| device_info.interface_number == interface_number) &&
+        self.serial_number.as_ref().is_none_or(|serial_number| &device_info.serial_number == serial_number) &&
+        self.path.as_ref().is_none_or(|path| path.is_match(&device_info.path)) &&
+        self.filters.iter().all(|filter| filter(device_info))
+    }
+
+    pub fn find(&self) -> Vec<HIDDeviceInfo> {
+        self.find_with(&NativeBackend)
+    }
+
+    pub fn find_with<B: HidBackend>(&self, backend: &B) -> Vec<HIDDeviceInfo> {
+        backend.enumerate()
+            .filter_map(|device_info| device_info.ok())
+            .filter(|device_info| self.matches(device_info))
+            .collect()
+    }
+
+    pub fn open_first(&self) -> Result<HIDDevice> {
+        self.open_first_with(&NativeBackend)
+    }
+
+    // Open the first matching device that will open, if none do the last
+    // error is returned
+    pub fn open_first_with<B: HidBackend>(&self, backend: &B) -> Result<B::Device> {
+        let mut last_error = Error::NotFound { code: None };
+        for device_info in self.find_with(backend) {
+            match backend.open(&device_info.path) {
+                Ok(device) => return Ok(device),
+                Err(error) => last_error = error,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::backend::mock::{
+        MockBackend,
+        MockDevice,
+    };
+    use super::super::backend::HidDeviceIo;
+    use super::*;
+
+    fn device_info(path: &str, product_id: u16, usage_page: u16, usage: u16, interface_number: u16) -> HIDDeviceInfo {
+        HIDDeviceInfo {
+            path: path.to_string(),
+            vendor_id: 0x054c,
+            product_id,
+            usage_page,
+            usage,
+            interface_number,
+            ..HIDDeviceInfo::default()
+        }
+    }
+
+    // two collections of a ZCM1, a ZCM2 with a serial and someone else's mouse
+    fn backend() -> (MockBackend, Vec<MockDevice>) {
+        let mut backend = MockBackend::new();
+        let devices = vec![
+            backend.add_device(device_info("/dev/hidraw0", 0x03d5, 0x01, 0x04, 0)),
+            backend.add_device(device_info("/dev/hidraw1", 0x03d5, 0xff00, 0x01, 0)),
+            backend.add_device(HIDDeviceInfo {
+                serial_number: "00:06:f7:c1:33:8d".to_string(),
+                ..device_info("/dev/hidraw2", 0x0c5e, 0x01, 0x04, 1)
+            }),
+            backend.add_device(HIDDeviceInfo {
+                vendor_id: 0x046d,
+                ..device_info("/dev/hidraw3", 0xc077, 0x01, 0x02, 0)
+            }),
+        ];
+        (backend, devices)
+    }
+
+    fn paths(query: &HidQuery, backend: &MockBackend) -> Vec<String> {
+        query.find_with(backend).into_iter().map(|device_info| device_info.path).collect()
+    }
+
+    // which of devices handle is, by a report only that one has queued
+    fn which(handle: &MockDevice, devices: &[MockDevice]) -> usize {
+        for (index, device) in devices.iter().enumerate() {
+            device.push_input_report(&[index as u8]);
+        }
+        let mut report = [0u8; 1];
+        handle.read_input_report(&mut report).unwrap();
+        report[0] as usize
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        let (backend, _) = backend();
+        assert_eq!(paths(&HidQuery::new(), &backend).len(), 4);
+    }
+
+    #[test]
+    fn vendor_and_product_ids() {
+        let (backend, _) = backend();
+        assert_eq!(paths(&HidQuery::new().vendor_id(0x054c), &backend), vec!["/dev/hidraw0", "/dev/hidraw1", "/dev/hidraw2"]);
+        assert_eq!(paths(&HidQuery::new().vendor_id(0x054c).product_id(0x0c5e), &backend), vec!["/dev/hidraw2"]);
+        let both = HidQuery::new().product_id(0x0c5e).product_id(0xc077);
+        assert_eq!(paths(&both, &backend), vec!["/dev/hidraw2", "/dev/hidraw3"]);
+        // a product id of the wrong vendor
+        assert!(paths(&HidQuery::new().vendor_id(0x046d).product_ids(&[0x03d5]), &backend).is_empty());
+    }
+
+    #[test]
+    fn usage_interface_serial_and_path() {
+        let (backend, _) = backend();
+        let joysticks = HidQuery::new().usage_page(0x01).usage(0x04);
+        assert_eq!(paths(&joysticks, &backend), vec!["/dev/hidraw0", "/dev/hidraw2"]);
+        assert_eq!(paths(&HidQuery::new().usage_page(0xff00), &backend), vec!["/dev/hidraw1"]);
+        assert_eq!(paths(&HidQuery::new().interface_number(1), &backend), vec!["/dev/hidraw2"]);
+        assert_eq!(paths(&HidQuery::new().serial_number("00:06:f7:c1:33:8d"), &backend), vec!["/dev/hidraw2"]);
+        let path = HidQuery::new().path(Regex::new("hidraw[13]$").unwrap());
+        assert_eq!(paths(&path, &backend), vec!["/dev/hidraw1", "/dev/hidraw3"]);
+        let filter = HidQuery::new().vendor_id(0x054c).filter(|device_info| device_info.usage_page != 0x01);
+        assert_eq!(paths(&filter, &backend), vec!["/dev/hidraw1"]);
+    }
+
+    #[test]
+    fn open_first_match() {
+        let (backend, devices) = backend();
+        let device = HidQuery::new().product_id(0x0c5e).open_first_with(&backend).unwrap();
+        assert_eq!(which(&device, &devices), 2);
+        let device = HidQuery::new().usage(0x04).open_first_with(&backend).unwrap();
+        assert_eq!(which(&device, &devices), 0);
+    }
+
+    #[test]
+    fn open_without_a_match() {
+        let (backend, _) = backend();
+        let error = HidQuery::new().vendor_id(0x1234).open_first_with(&backend).unwrap_err();
+        assert!(matches!(error, Error::NotFound { .. }));
+    }
+
+    // The mock with one device that won't open and one that fails to
+    // enumerate
+    struct FlakyBackend {
+        backend: MockBackend,
+    }
+
+    impl HidBackend for FlakyBackend {
+        type Device = MockDevice;
+
+        fn enumerate(&self) -> Box<dyn Iterator<Item = Result<HIDDeviceInfo>>> {
+            let broken = std::iter::once(Err(Error::AccessDenied { code: None }));
+            Box::new(broken.chain(self.backend.enumerate()))
+        }
+
+        fn open(&self, device_path: &str) -> Result<MockDevice> {
+            if device_path == "/dev/hidraw0" {
+                return Err(Error::AccessDenied { code: None });
+            }
+            self.backend.open(device_path)
+        }
+    }
+
+    #[test]
+    fn open_skips_devices_that_fail() {
+        let (backend, devices) = backend();
+        let backend = FlakyBackend {
+            backend,
+        };
+        let query = HidQuery::new().vendor_id(0x054c);
+        assert_eq!(query.find_with(&backend).len(), 3);
+        let device = query.open_first_with(&backend).unwrap();
+        assert_eq!(which(&device, &devices), 1);
+
+        // the last error when nothing opens
+        let error = query.product_id(0x03d5).usage(0x04).open_first_with(&backend).unwrap_err();
+        assert!(matches!(error, Error::AccessDenied { .. }));
+    }
+}
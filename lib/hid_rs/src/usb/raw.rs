@@ -0,0 +1,73 @@
+// Plain USB access for interfaces no class driver speaks for, like the PSVR
+// processing unit's vendor interfaces. Only usbfs on Linux for now, on Windows
+// those interfaces first need WinUSB bound to them.
+
+pub mod descriptors;
+#[cfg(target_os = "linux")]
+mod usbfs;
+
+pub use descriptors::{
+    ConfigurationDescriptor,
+    DeviceDescriptor,
+    Direction,
+    EndpointDescriptor,
+    InterfaceDescriptor,
+    TransferType,
+};
+#[cfg(target_os = "linux")]
+pub use usbfs::{
+    usb_enumerate_all,
+    usb_enumerate_sysfs,
+    UsbDevice,
+    UsbDeviceInfoIter,
+};
+
+#[cfg(target_os = "linux")]
+use crate::error::Result;
+
+// bmRequestType bits, USB 2.0 spec section 9.3
+pub const REQUEST_TYPE_STANDARD: u8 = 0x00 << 5;
+pub const REQUEST_TYPE_CLASS: u8 = 0x01 << 5;
+pub const REQUEST_TYPE_VENDOR: u8 = 0x02 << 5;
+pub const RECIPIENT_DEVICE: u8 = 0x00;
+pub const RECIPIENT_INTERFACE: u8 = 0x01;
+pub const RECIPIENT_ENDPOINT: u8 = 0x02;
+
+#[derive(Debug, Default, Clone)]
+pub struct UsbDeviceInfo {
+    pub bus_number: u8,
+    pub address: u8,
+    // sysfs name, bus and hub ports, e.g. 1-2.3
+    pub port_path: String,
+    // what UsbDevice::open takes, /dev/bus/usb/001/004 on Linux
+    pub path: String,
+    pub manufacturer_string: String,
+    pub product_string: String,
+    pub serial_number: String,
+    pub device: DeviceDescriptor,
+    pub active_configuration: u8,
+    pub configurations: Vec<ConfigurationDescriptor>,
+}
+
+impl UsbDeviceInfo {
+    pub fn vendor_id(&self) -> u16 {
+        self.device.vendor_id
+    }
+
+    pub fn product_id(&self) -> u16 {
+        self.device.product_id
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn open(&self) -> Result<UsbDevice> {
+        UsbDevice::open(&self.path)
+    }
+
+    // Interfaces of the active configuration, all alternate settings
+    pub fn interfaces(&self) -> impl Iterator<Item = &InterfaceDescriptor> {
+        let active_configuration = self.active_configuration;
+        self.configurations.iter()
+            .filter(move |configuration| configuration.value == active_configuration)
+            .flat_map(|configuration| configuration.interfaces.iter())
+    }
+}
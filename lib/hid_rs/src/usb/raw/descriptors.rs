@@ -0,0 +1,368 @@
+use crate::error::{
+    Error, Result,
+};
+
+// Standard descriptors, USB 2.0 spec section 9.6
+// https://www.usb.org/document-library/usb-20-specification
+const DESCRIPTOR_DEVICE: u8 = 0x01;
+const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_INTERFACE: u8 = 0x04;
+const DESCRIPTOR_ENDPOINT: u8 = 0x05;
+
+const DEVICE_DESCRIPTOR_SIZE: usize = 18;
+const CONFIGURATION_DESCRIPTOR_SIZE: usize = 9;
+const INTERFACE_DESCRIPTOR_SIZE: usize = 9;
+const ENDPOINT_DESCRIPTOR_SIZE: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // device to host
+    In,
+    // host to device
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    // BCD, 0x0200 is USB 2.0
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet_size_0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub release_number: u16,
+    // string descriptor indexes, 0 for none
+    pub manufacturer_index: u8,
+    pub product_index: u8,
+    pub serial_number_index: u8,
+    pub num_configurations: u8,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigurationDescriptor {
+    pub value: u8,
+    pub string_index: u8,
+    pub attributes: u8,
+    // in 2mA units
+    pub max_power: u8,
+    // every alternate setting of every interface
+    pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub string_index: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+    // class specific descriptors that follow the interface, e.g. HID's
+    pub extra: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn number(&self) -> u8 {
+        self.address & 0x0f
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.address & 0x80 != 0 { Direction::In } else { Direction::Out }
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        match self.attributes & 0x03 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+}
+
+pub fn parse_device_descriptor(bytes: &[u8]) -> Result<DeviceDescriptor> {
+    let bytes = descriptor(bytes, DESCRIPTOR_DEVICE, DEVICE_DESCRIPTOR_SIZE)?;
+    Ok(DeviceDescriptor {
+        usb_version: read_u16(&bytes[2..4]),
+        class: bytes[4],
+        subclass: bytes[5],
+        protocol: bytes[6],
+        max_packet_size_0: bytes[7],
+        vendor_id: read_u16(&bytes[8..10]),
+        product_id: read_u16(&bytes[10..12]),
+        release_number: read_u16(&bytes[12..14]),
+        manufacturer_index: bytes[14],
+        product_index: bytes[15],
+        serial_number_index: bytes[16],
+        num_configurations: bytes[17],
+    })
+}
+
+// A configuration descriptor with everything wTotalLength says belongs to it,
+// the interfaces, their endpoints and any class descriptors
+pub fn parse_configuration_descriptor(bytes: &[u8]) -> Result<ConfigurationDescriptor> {
+    let header = descriptor(bytes, DESCRIPTOR_CONFIGURATION, CONFIGURATION_DESCRIPTOR_SIZE)?;
+    let total_len = read_u16(&header[2..4]) as usize;
+    if total_len < header.len() {
+        return Err(Error::invalid_descriptor("configuration descriptor is shorter than its header"));
+    }
+    let bytes = bytes.get(..total_len)
+        .ok_or_else(|| Error::invalid_descriptor("configuration descriptor is truncated"))?;
+
+    let mut configuration = ConfigurationDescriptor {
+        value: header[5],
+        string_index: header[6],
+        attributes: header[7],
+        max_power: header[8],
+        interfaces: Vec::new(),
+    };
+
+    let mut position = header[0] as usize;
+    while position < bytes.len() {
+        let rest = &bytes[position..];
+        let len = rest[0] as usize;
+        if len < 2 || len > rest.len() {
+            return Err(Error::invalid_descriptor(format!("bad descriptor length {} at byte {}", len, position)));
+        }
+        match rest[1] {
+            DESCRIPTOR_INTERFACE => {
+                let interface = descriptor(rest, DESCRIPTOR_INTERFACE, INTERFACE_DESCRIPTOR_SIZE)?;
+                configuration.interfaces.push(InterfaceDescriptor {
+                    number: interface[2],
+                    alternate_setting: interface[3],
+                    class: interface[5],
+                    subclass: interface[6],
+                    protocol: interface[7],
+                    string_index: interface[8],
+                    endpoints: Vec::new(),
+                    extra: Vec::new(),
+                });
+            }
+            DESCRIPTOR_ENDPOINT => {
+                let endpoint = descriptor(rest, DESCRIPTOR_ENDPOINT, ENDPOINT_DESCRIPTOR_SIZE)?;
+                let interface = configuration.interfaces.last_mut()
+                    .ok_or_else(|| Error::invalid_descriptor("endpoint outside of an interface"))?;
+                interface.endpoints.push(EndpointDescriptor {
+                    address: endpoint[2],
+                    attributes: endpoint[3],
+                    max_packet_size: read_u16(&endpoint[4..6]),
+                    interval: endpoint[6],
+                });
+            }
+            _ => {
+                // class or vendor specific, kept with the interface it follows
+                if let Some(interface) = configuration.interfaces.last_mut() {
+                    interface.extra.extend_from_slice(&rest[..len]);
+                }
+            }
+        }
+        position += len;
+    }
+
+    Ok(configuration)
+}
+
+// A device descriptor followed by its configurations, the layout of usbfs
+// device nodes and sysfs descriptors files
+pub fn parse_descriptors(bytes: &[u8]) -> Result<(DeviceDescriptor, Vec<ConfigurationDescriptor>)> {
+    let device = parse_device_descriptor(bytes)?;
+    let mut configurations = Vec::new();
+    let mut position = bytes[0] as usize;
+    while position < bytes.len() {
+        let configuration = parse_configuration_descriptor(&bytes[position..])?;
+        position += read_u16(&bytes[position + 2..position + 4]) as usize;
+        configurations.push(configuration);
+    }
+    Ok((device, configurations))
+}
+
+// The descriptor at the start of bytes, checked for type and minimum length
+fn descriptor(bytes: &[u8], descriptor_type: u8, min_len: usize) -> Result<&[u8]> {
+    match bytes {
+        [len, found_type, ..] if *found_type == descriptor_type && *len as usize >= min_len && bytes.len() >= *len as usize => {
+            Ok(&bytes[..*len as usize])
+        }
+        [_, found_type, ..] if *found_type != descriptor_type => Err(Error::invalid_descriptor(format!(
+            "expected descriptor type {:#04x}, found {:#04x}", descriptor_type, found_type
+        ))),
+        _ => Err(Error::invalid_descriptor(format!("descriptor type {:#04x} is truncated", descriptor_type))),
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Assembled by hand in the layout of a ZCM1 PS Move: one HID interface
+    // with an interrupt endpoint each way. Not captured from a device.
+    const DEVICE: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x4c, 0x05, 0xd5, 0x03, 0x00, 0x01, 0x01, 0x02, 0x00, 0x01,
+    ];
+    const CONFIGURATION: [u8; 41] = [
+        // configuration, 41 bytes in total, bus powered, 100mA
+        0x09, 0x02, 0x29, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+        // interface 0, HID
+        0x09, 0x04, 0x00, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00,
+        // HID 1.11 with a 178 byte report descriptor
+        0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0xb2, 0x00,
+        // interrupt IN 1 and OUT 2, 64 bytes every frame
+        0x07, 0x05, 0x81, 0x03, 0x40, 0x00, 0x01,
+        0x07, 0x05, 0x02, 0x03, 0x40, 0x00, 0x01,
+    ];
+
+    fn descriptors() -> Vec<u8> {
+        let mut bytes = DEVICE.to_vec();
+        bytes.extend_from_slice(&CONFIGURATION);
+        bytes
+    }
+
+    #[test]
+    fn device_descriptor() {
+        let device = parse_device_descriptor(&DEVICE).unwrap();
+        assert_eq!(device, DeviceDescriptor {
+            usb_version: 0x0200,
+            class: 0,
+            subclass: 0,
+            protocol: 0,
+            max_packet_size_0: 8,
+            vendor_id: 0x054c,
+            product_id: 0x03d5,
+            release_number: 0x0100,
+            manufacturer_index: 1,
+            product_index: 2,
+            serial_number_index: 0,
+            num_configurations: 1,
+        });
+    }
+
+    #[test]
+    fn configuration_descriptor() {
+        let configuration = parse_configuration_descriptor(&CONFIGURATION).unwrap();
+        assert_eq!((configuration.value, configuration.attributes, configuration.max_power), (1, 0x80, 0x32));
+        assert_eq!(configuration.interfaces.len(), 1);
+        let interface = &configuration.interfaces[0];
+        assert_eq!((interface.number, interface.alternate_setting, interface.class), (0, 0, 3));
+        assert_eq!(interface.extra, CONFIGURATION[18..27]);
+
+        let endpoints: Vec<_> = interface.endpoints.iter()
+            .map(|endpoint| (endpoint.number(), endpoint.direction(), endpoint.transfer_type(), endpoint.max_packet_size))
+            .collect();
+        assert_eq!(endpoints, vec![
+            (1, Direction::In, TransferType::Interrupt, 64),
+            (2, Direction::Out, TransferType::Interrupt, 64),
+        ]);
+    }
+
+    #[test]
+    fn alternate_settings_and_vendor_descriptors() {
+        let bytes = [
+            0x09, 0x02, 0x25, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            // vendor descriptor before any interface, dropped
+            0x03, 0xff, 0xaa,
+            0x09, 0x04, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00,
+            0x09, 0x04, 0x00, 0x01, 0x01, 0xff, 0x00, 0x00, 0x00,
+            0x07, 0x05, 0x83, 0x02, 0x00, 0x02, 0x00,
+        ];
+        let configuration = parse_configuration_descriptor(&bytes).unwrap();
+        let interfaces = &configuration.interfaces;
+        assert_eq!(interfaces.len(), 2);
+        assert!(interfaces[0].endpoints.is_empty() && interfaces[0].extra.is_empty());
+        assert_eq!(interfaces[1].alternate_setting, 1);
+        assert_eq!(interfaces[1].endpoints[0].transfer_type(), TransferType::Bulk);
+        assert_eq!(interfaces[1].endpoints[0].max_packet_size, 512);
+    }
+
+    #[test]
+    fn device_and_configurations() {
+        let (device, configurations) = parse_descriptors(&descriptors()).unwrap();
+        assert_eq!(device.product_id, 0x03d5);
+        assert_eq!(configurations, vec![parse_configuration_descriptor(&CONFIGURATION).unwrap()]);
+
+        // a second configuration straight after the first
+        let mut bytes = descriptors();
+        let mut second = CONFIGURATION;
+        second[5] = 2;
+        bytes.extend_from_slice(&second);
+        let (_, configurations) = parse_descriptors(&bytes).unwrap();
+        assert_eq!(configurations.iter().map(|c| c.value).collect::<Vec<_>>(), vec![1, 2]);
+
+        // wTotalLength past the end of what there is
+        let mut bytes = descriptors();
+        bytes.truncate(bytes.len() - 1);
+        assert!(matches!(parse_descriptors(&bytes), Err(Error::InvalidDescriptor(_))));
+    }
+
+    #[test]
+    fn truncated() {
+        for len in 0..DEVICE.len() {
+            assert!(matches!(parse_device_descriptor(&DEVICE[..len]), Err(Error::InvalidDescriptor(_))));
+        }
+        for len in 0..CONFIGURATION.len() {
+            assert!(matches!(parse_configuration_descriptor(&CONFIGURATION[..len]), Err(Error::InvalidDescriptor(_))));
+        }
+    }
+
+    #[test]
+    fn bad_lengths_and_types() {
+        let invalid = |bytes: &[u8]| matches!(parse_configuration_descriptor(bytes), Err(Error::InvalidDescriptor(_)));
+
+        // bLength of the device descriptor below the standard size
+        let mut device = DEVICE;
+        device[0] = 0x11;
+        assert!(matches!(parse_device_descriptor(&device), Err(Error::InvalidDescriptor(_))));
+        assert!(matches!(parse_device_descriptor(&CONFIGURATION), Err(Error::InvalidDescriptor(_))));
+        assert!(invalid(&DEVICE));
+
+        // wTotalLength shorter than the header
+        let mut bytes = CONFIGURATION;
+        bytes[2] = 0x08;
+        assert!(invalid(&bytes));
+
+        // a zero or one length descriptor would never advance
+        for len in [0x00, 0x01] {
+            let mut bytes = CONFIGURATION;
+            bytes[9] = len;
+            assert!(invalid(&bytes));
+        }
+
+        // a descriptor running past wTotalLength
+        let mut bytes = CONFIGURATION;
+        bytes[34] = 0x08;
+        assert!(invalid(&bytes));
+
+        // an endpoint shorter than the standard size
+        let mut bytes = CONFIGURATION;
+        bytes[34] = 0x06;
+        bytes[40] = 0x06;
+        assert!(invalid(&bytes));
+
+        // an endpoint before any interface
+        let mut bytes = CONFIGURATION.to_vec();
+        bytes.drain(9..27);
+        bytes[2] = bytes.len() as u8;
+        assert!(invalid(&bytes));
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs::{
+    self, File, OpenOptions,
+};
+use std::os::unix::io::{
+    AsRawFd, RawFd,
+};
+use std::path::{
+    Path, PathBuf,
+};
+use std::time::Duration;
+
+use super::descriptors::parse_descriptors;
+use super::UsbDeviceInfo;
+use crate::error::{
+    Error, Result,
+};
+use crate::usb::device::info::hidraw::SYSFS_ROOT;
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/usbdevice_fs.h
+#[repr(C)]
+struct UsbdevfsCtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    // milliseconds
+    timeout: u32,
+    data: *mut libc::c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsBulkTransfer {
+    endpoint: libc::c_uint,
+    length: libc::c_uint,
+    // milliseconds
+    timeout: libc::c_uint,
+    data: *mut libc::c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsIoctl {
+    interface: libc::c_int,
+    ioctl_code: libc::c_int,
+    data: *mut libc::c_void,
+}
+
+const IOC_NONE: libc::c_ulong = 0;
+const IOC_WRITE: libc::c_ulong = 1;
+const IOC_READ: libc::c_ulong = 2;
+
+pub struct UsbDeviceInfoIter {
+    devices: std::vec::IntoIter<PathBuf>,
+}
+
+pub fn usb_enumerate_all() -> UsbDeviceInfoIter {
+    usb_enumerate_sysfs(SYSFS_ROOT)
+}
+
+// walk <sysfs_root>/bus/usb/devices, e.g. a fake tree in tests
+pub fn usb_enumerate_sysfs<P: AsRef<Path>>(sysfs_root: P) -> UsbDeviceInfoIter {
+    let devices_dir = sysfs_root.as_ref().join("bus").join("usb").join("devices");
+    // interfaces (1-2:1.0) are listed next to devices, only devices have idVendor
+    let mut devices: Vec<PathBuf> = fs::read_dir(devices_dir)
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.join("idVendor").exists())
+                .collect()
+        })
+        .unwrap_or_default();
+    devices.sort();
+    UsbDeviceInfoIter {
+        devices: devices.into_iter(),
+    }
+}
+
+impl Iterator for UsbDeviceInfoIter {
+    type Item = Result<UsbDeviceInfo>;
+
+    fn next(&mut self) -> Option<Result<UsbDeviceInfo>> {
+        self.devices.next().map(|device| get_device_info(&device))
+    }
+}
+
+// https://www.kernel.org/doc/Documentation/ABI/stable/sysfs-bus-usb
+fn get_device_info(sysfs_device: &Path) -> Result<UsbDeviceInfo> {
+    let (device, configurations) = parse_descriptors(&fs::read(sysfs_device.join("descriptors"))?)?;
+    let bus_number: u8 = read_number(sysfs_device, "busnum")?;
+    let address: u8 = read_number(sysfs_device, "devnum")?;
+
+    Ok(UsbDeviceInfo {
+        bus_number,
+        address,
+        port_path: sysfs_device.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("")
+            .to_string(),
+        path: format!("/dev/bus/usb/{:03}/{:03}", bus_number, address),
+        manufacturer_string: read_attribute(sysfs_device, "manufacturer").unwrap_or_default(),
+        product_string: read_attribute(sysfs_device, "product").unwrap_or_default(),
+        serial_number: read_attribute(sysfs_device, "serial").unwrap_or_default(),
+        device,
+        // empty while the device is unconfigured
+        active_configuration: read_number(sysfs_device, "bConfigurationValue").unwrap_or(0),
+        configurations,
+    })
+}
+
+fn read_attribute(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|value| value.trim_end().to_string())
+}
+
+fn read_number(dir: &Path, name: &str) -> Result<u8> {
+    read_attribute(dir, name)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| Error::invalid_descriptor(format!("bad or missing {} in {}", name, dir.display())))
+}
+
+// An open usbfs device node, closing it releases any claimed interfaces
+#[derive(Debug)]
+pub struct UsbDevice {
+    file: File,
+}
+
+impl AsRawFd for UsbDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl UsbDevice {
+    pub fn open(path: &str) -> Result<UsbDevice> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Ok(UsbDevice {
+            file,
+        })
+    }
+
+    // Bulk and interrupt transfers need the interface claimed, which fails
+    // while a kernel driver has it
+    pub fn claim_interface(&self, interface: u8) -> Result<()> {
+        let mut interface = interface as libc::c_uint;
+        self.ioctl(usbdevfs(IOC_READ, 15, std::mem::size_of::<libc::c_uint>()), &mut interface as *mut _ as *mut libc::c_void)
+            .map(|_| ())
+    }
+
+    pub fn release_interface(&self, interface: u8) -> Result<()> {
+        let mut interface = interface as libc::c_uint;
+        self.ioctl(usbdevfs(IOC_READ, 16, std::mem::size_of::<libc::c_uint>()), &mut interface as *mut _ as *mut libc::c_void)
+            .map(|_| ())
+    }
+
+    // Unbind whatever kernel driver has the interface, e.g. usbhid
+    pub fn detach_kernel_driver(&self, interface: u8) -> Result<()> {
+        let mut command = UsbdevfsIoctl {
+            interface: interface as libc::c_int,
+            ioctl_code: usbdevfs(IOC_NONE, 22, 0) as libc::c_int, // USBDEVFS_DISCONNECT
+            data: std::ptr::null_mut(),
+        };
+        self.ioctl(usbdevfs(IOC_READ | IOC_WRITE, 18, std::mem::size_of::<UsbdevfsIoctl>()), &mut command as *mut _ as *mut libc::c_void)
+            .map(|_| ())
+    }
+
+    // request_type is one of the REQUEST_TYPE_ values with a RECIPIENT_,
+    // the device to host bit is set here
+    pub fn control_in(&self, request_type: u8, request: u8, value: u16, index: u16, data: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.control_transfer(request_type | 0x80, request, value, index, data.as_mut_ptr(), data.len(), timeout)
+    }
+
+    pub fn control_out(&self, request_type: u8, request: u8, value: u16, index: u16, data: &[u8], timeout: Duration) -> Result<usize> {
+        self.control_transfer(request_type & !0x80, request, value, index, data.as_ptr() as *mut u8, data.len(), timeout)
+    }
+
+    // endpoint is the endpoint address, 0x81 for IN endpoint 1
+    pub fn bulk_read(&self, endpoint: u8, data: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.bulk_transfer(endpoint | 0x80, data.as_mut_ptr(), data.len(), timeout)
+    }
+
+    pub fn bulk_write(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+        self.bulk_transfer(endpoint & !0x80, data.as_ptr() as *mut u8, data.len(), timeout)
+    }
+
+    // usbfs runs interrupt transfers through the bulk ioctl, it looks at the
+    // endpoint's type itself
+    pub fn interrupt_read(&self, endpoint: u8, data: &mut [u8], timeout: Duration) -> Result<usize> {
+        self.bulk_read(endpoint, data, timeout)
+    }
+
+    pub fn interrupt_write(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+        self.bulk_write(endpoint, data, timeout)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn control_transfer(&self, request_type: u8, request: u8, value: u16, index: u16, data: *mut u8, len: usize, timeout: Duration) -> Result<usize> {
+        if len > u16::MAX as usize {
+            return Err(Error::BufferTooSmall);
+        }
+        let mut transfer = UsbdevfsCtrlTransfer {
+            request_type,
+            request,
+            value,
+            index,
+            length: len as u16,
+            timeout: timeout_ms(timeout),
+            data: data as *mut libc::c_void,
+        };
+        self.ioctl(usbdevfs(IOC_READ | IOC_WRITE, 0, std::mem::size_of::<UsbdevfsCtrlTransfer>()), &mut transfer as *mut _ as *mut libc::c_void)
+    }
+
+    fn bulk_transfer(&self, endpoint: u8, data: *mut u8, len: usize, timeout: Duration) -> Result<usize> {
+        let mut transfer = UsbdevfsBulkTransfer {
+            endpoint: endpoint as libc::c_uint,
+            length: len as libc::c_uint,
+            timeout: timeout_ms(timeout),
+            data: data as *mut libc::c_void,
+        };
+        self.ioctl(usbdevfs(IOC_READ | IOC_WRITE, 2, std::mem::size_of::<UsbdevfsBulkTransfer>()), &mut transfer as *mut _ as *mut libc::c_void)
+    }
+
+    // transfers return how many bytes went across
+    fn ioctl(&self, request: libc::c_ulong, argument: *mut libc::c_void) -> Result<usize> {
+        let result = unsafe { libc::ioctl(self.file.as_raw_fd(), request, argument) };
+        if result < 0 {
+            let error = std::io::Error::last_os_error();
+            match error.raw_os_error() {
+                // the device went away mid transfer
+                Some(libc::ENODEV) | Some(libc::ESHUTDOWN) => Err(Error::Disconnected { code: error.raw_os_error() }),
+                _ => Err(Error::from(error)),
+            }
+        } else {
+            Ok(result as usize)
+        }
+    }
+}
+
+// 0 waits forever to usbfs
+fn timeout_ms(timeout: Duration) -> u32 {
+    timeout.as_millis().clamp(1, u32::MAX as u128) as u32
+}
+
+fn usbdevfs(direction: libc::c_ulong, nr: libc::c_ulong, len: usize) -> libc::c_ulong {
+    (direction << 30) | ((len as libc::c_ulong) << 16) | ((b'U' as libc::c_ulong) << 8) | nr
+}
@@ -1,8 +1,16 @@
-pub fn bytes_to_str(bytes: &[u8]) -> &str {
+#[cfg(windows)]
+use crate::error::{
+    Error, Result,
+};
+
+#[cfg(windows)]
+pub fn bytes_to_str(bytes: &[u8]) -> Result<&str> {
     let first_null = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
-    &std::str::from_utf8(bytes).unwrap()[0..first_null]
+    std::str::from_utf8(&bytes[0..first_null])
+        .map_err(|_| Error::invalid_descriptor("string is not valid UTF-8"))
 }
 
+#[cfg(windows)]
 pub fn str_to_os_str(s: &str) -> Vec<i8> {
     let append = vec![0i8]; // terminate with 0
     let mut s_bytes = vec![];
@@ -0,0 +1,143 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+// A Bluetooth device address in the order it is written, aa:bb:cc:dd:ee:ff is
+// [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]. HCI, the management interface and the
+// PS Move's feature reports send it the other way around, see from_le_bytes.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BdAddr(pub [u8; 6]);
+
+impl BdAddr {
+    pub fn from_le_bytes(bytes: [u8; 6]) -> BdAddr {
+        let mut address = bytes;
+        address.reverse();
+        BdAddr(address)
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 6] {
+        let mut bytes = self.0;
+        bytes.reverse();
+        bytes
+    }
+}
+
+// BLUETOOTH_ADDRESS on Windows, the low 48 bits
+impl From<u64> for BdAddr {
+    fn from(address: u64) -> BdAddr {
+        let bytes = address.to_be_bytes();
+        let mut address = [0u8; 6];
+        address.copy_from_slice(&bytes[2..]);
+        BdAddr(address)
+    }
+}
+
+impl From<BdAddr> for u64 {
+    fn from(address: BdAddr) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes[2..].copy_from_slice(&address.0);
+        u64::from_be_bytes(bytes)
+    }
+}
+
+impl fmt::Display for BdAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+impl fmt::Debug for BdAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BdAddr({})", self)
+    }
+}
+
+// six pairs of hex digits separated by colons, either case
+impl FromStr for BdAddr {
+    type Err = io::Error;
+
+    fn from_str(address: &str) -> io::Result<BdAddr> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid Bluetooth address {:?}", address));
+        let mut bytes = [0u8; 6];
+        let mut parts = address.split(':');
+        for b in bytes.iter_mut() {
+            let part = parts.next().ok_or_else(invalid)?;
+            // from_str_radix would also take a sign
+            if part.len() != 2 || !part.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            *b = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(BdAddr(bytes))
+    }
+}
+
+// as the colon string
+#[cfg(feature = "serde")]
+impl serde::Serialize for BdAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for BdAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<BdAddr, D::Error> {
+        let address = String::deserialize(deserializer)?;
+        address.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: BdAddr = BdAddr([0x00, 0x1b, 0xdc, 0x0f, 0xa2, 0x7e]);
+
+    #[test]
+    fn display_and_parse_round_trip() {
+        assert_eq!(ADDRESS.to_string(), "00:1b:dc:0f:a2:7e");
+        assert_eq!("00:1b:dc:0f:a2:7e".parse::<BdAddr>().unwrap(), ADDRESS);
+        assert_eq!("00:1B:DC:0F:A2:7E".parse::<BdAddr>().unwrap(), ADDRESS);
+        assert_eq!(ADDRESS.to_string().parse::<BdAddr>().unwrap(), ADDRESS);
+        assert_eq!(format!("{:?}", ADDRESS), "BdAddr(00:1b:dc:0f:a2:7e)");
+    }
+
+    #[test]
+    fn parse_rejects_bad_strings() {
+        for address in [
+            "",
+            "00:1b:dc:0f:a2",
+            "00:1b:dc:0f:a2:7e:01",
+            "00:1b:dc:0f:a2:",
+            "00-1b-dc-0f-a2-7e",
+            "001b:dc:0f:a2:7e",
+            "0:1b:dc:0f:a2:7e",
+            "00:1b:dc:0f:a2:7g",
+            "+0:1b:dc:0f:a2:7e",
+            " 00:1b:dc:0f:a2:7e",
+        ].iter() {
+            let error = address.parse::<BdAddr>().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn le_bytes_are_reversed() {
+        let bytes = [0x7e, 0xa2, 0x0f, 0xdc, 0x1b, 0x00];
+        assert_eq!(BdAddr::from_le_bytes(bytes), ADDRESS);
+        assert_eq!(ADDRESS.to_le_bytes(), bytes);
+    }
+
+    #[test]
+    fn u64_round_trip() {
+        assert_eq!(u64::from(ADDRESS), 0x001b_dc0f_a27e);
+        assert_eq!(BdAddr::from(0x001b_dc0f_a27e_u64), ADDRESS);
+        // only the low 48 bits are an address
+        assert_eq!(BdAddr::from(0xffff_001b_dc0f_a27e_u64), ADDRESS);
+    }
+}
@@ -0,0 +1,163 @@
+// HID over Bluetooth (HIDP). A HID device talks over two L2CAP channels:
+// control for handshakes, GET_REPORT and SET_REPORT, interrupt for input and
+// output reports. Every message starts with a header byte, the transaction
+// type in the high nibble and a parameter in the low one.
+// https://www.bluetooth.com/specifications/specs/human-interface-device-profile-1-1-1/
+
+#[cfg(target_os = "linux")]
+mod l2cap;
+
+#[cfg(target_os = "linux")]
+pub use l2cap::{
+    BluetoothHidDevice,
+    BluetoothHidListener,
+};
+
+use hid_rs::usb::descriptor::{
+    ReportType,
+};
+
+use std::io;
+
+pub const PSM_HID_CONTROL: u16 = 0x11;
+pub const PSM_HID_INTERRUPT: u16 = 0x13;
+
+const HIDP_HANDSHAKE: u8 = 0x00;
+const HIDP_CONTROL: u8 = 0x10;
+const HIDP_GET_REPORT: u8 = 0x40;
+const HIDP_SET_REPORT: u8 = 0x50;
+const HIDP_DATA: u8 = 0xa0;
+// GET_REPORT parameter bit, a buffer size follows the report id
+const HIDP_GET_REPORT_SIZE: u8 = 0x08;
+// HID_CONTROL parameter, the device is going away for good
+pub const HIDP_CONTROL_VIRTUAL_CABLE_UNPLUG: u8 = 0x05;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HidpMessage {
+    // result code of the last request on the control channel, 0 is success
+    Handshake(u8),
+    Control(u8),
+    // report id first like hidapi buffers
+    Data(ReportType, Vec<u8>),
+}
+
+// SET_REPORT on the control channel, answered with a handshake
+pub fn set_report(report_type: ReportType, report: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(report.len() + 1);
+    packet.push(HIDP_SET_REPORT | report_type_param(report_type));
+    packet.extend_from_slice(report);
+    packet
+}
+
+// GET_REPORT on the control channel, answered with DATA or a handshake on
+// error. buffer_size is the most the device may send back, report id included.
+pub fn get_report(report_type: ReportType, report_id: u8, buffer_size: u16) -> Vec<u8> {
+    let mut packet = vec![HIDP_GET_REPORT | HIDP_GET_REPORT_SIZE | report_type_param(report_type), report_id];
+    packet.extend_from_slice(&buffer_size.to_le_bytes());
+    packet
+}
+
+// DATA on the interrupt channel, how output reports go out unasked
+pub fn data(report_type: ReportType, report: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(report.len() + 1);
+    packet.push(HIDP_DATA | report_type_param(report_type));
+    packet.extend_from_slice(report);
+    packet
+}
+
+pub fn parse(packet: &[u8]) -> io::Result<HidpMessage> {
+    let header = *packet.first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty HIDP packet"))?;
+    let param = header & 0x0f;
+    match header & 0xf0 {
+        HIDP_HANDSHAKE => Ok(HidpMessage::Handshake(param)),
+        HIDP_CONTROL => Ok(HidpMessage::Control(param)),
+        HIDP_DATA => {
+            let report_type = match param & 0x03 {
+                1 => ReportType::Input,
+                2 => ReportType::Output,
+                3 => ReportType::Feature,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "HIDP data without a report type")),
+            };
+            Ok(HidpMessage::Data(report_type, packet[1..].to_vec()))
+        },
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected HIDP transaction {:#04x}", header)
+        )),
+    }
+}
+
+// Ok for a successful handshake, otherwise what the device didn't like
+pub fn handshake_result(result: u8) -> io::Result<()> {
+    let message = match result {
+        0x00 => return Ok(()),
+        0x01 => "device not ready",
+        0x02 => "invalid report id",
+        0x03 => "unsupported request",
+        0x04 => "invalid parameter",
+        0x0f => "fatal error",
+        _ => "unknown error",
+    };
+    Err(io::Error::other(format!("HIDP handshake {:#04x}: {}", result, message)))
+}
+
+fn report_type_param(report_type: ReportType) -> u8 {
+    match report_type {
+        ReportType::Input => 1,
+        ReportType::Output => 2,
+        ReportType::Feature => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_report_packet() {
+        assert_eq!(set_report(ReportType::Feature, &[0x05, 0xaa, 0xbb]), vec![0x53, 0x05, 0xaa, 0xbb]);
+        assert_eq!(set_report(ReportType::Output, &[0x06]), vec![0x52, 0x06]);
+    }
+
+    #[test]
+    fn get_report_packet() {
+        // buffer size little endian after the report id
+        assert_eq!(get_report(ReportType::Feature, 0x10, 49), vec![0x4b, 0x10, 0x31, 0x00]);
+        assert_eq!(get_report(ReportType::Input, 0x01, 0x1234), vec![0x49, 0x01, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn data_packet() {
+        assert_eq!(data(ReportType::Output, &[0x06, 0x00, 0xff]), vec![0xa2, 0x06, 0x00, 0xff]);
+        assert_eq!(data(ReportType::Input, &[]), vec![0xa1]);
+    }
+
+    #[test]
+    fn parse_packets() {
+        assert_eq!(parse(&[0x00]).unwrap(), HidpMessage::Handshake(0));
+        assert_eq!(parse(&[0x03]).unwrap(), HidpMessage::Handshake(3));
+        assert_eq!(parse(&[0x15]).unwrap(), HidpMessage::Control(HIDP_CONTROL_VIRTUAL_CABLE_UNPLUG));
+        assert_eq!(parse(&[0xa1, 0x01, 0x02]).unwrap(), HidpMessage::Data(ReportType::Input, vec![0x01, 0x02]));
+        assert_eq!(parse(&[0xa3, 0x10]).unwrap(), HidpMessage::Data(ReportType::Feature, vec![0x10]));
+        // what we send parses back
+        let report = [0x04, 0x8d, 0x33];
+        assert_eq!(parse(&data(ReportType::Output, &report)).unwrap(), HidpMessage::Data(ReportType::Output, report.to_vec()));
+    }
+
+    #[test]
+    fn parse_rejects_bad_packets() {
+        for packet in [&[][..], &[0xa0, 0x01][..], &[0x40, 0x01][..], &[0x70][..]].iter() {
+            assert_eq!(parse(packet).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", packet);
+        }
+    }
+
+    #[test]
+    fn handshake_results() {
+        assert!(handshake_result(0x00).is_ok());
+        let error = handshake_result(0x02).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert_eq!(error.to_string(), "HIDP handshake 0x02: invalid report id");
+        assert_eq!(handshake_result(0x0e).unwrap_err().to_string(), "HIDP handshake 0x0e: unknown error");
+    }
+}
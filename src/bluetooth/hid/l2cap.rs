@@ -0,0 +1,331 @@
+use hid_rs::usb::backend::{
+    HidDeviceIo,
+};
+use hid_rs::usb::descriptor::{
+    ReportType,
+};
+use hid_rs::{
+    Error,
+};
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::{
+    Duration, Instant,
+};
+
+use super::{
+    get_report,
+    handshake_result,
+    parse,
+    set_report,
+    HidpMessage,
+    HIDP_CONTROL_VIRTUAL_CABLE_UNPLUG,
+    PSM_HID_CONTROL,
+    PSM_HID_INTERRUPT,
+};
+use crate::bluetooth::BdAddr;
+
+const AF_BLUETOOTH: libc::c_int = 31;
+const BTPROTO_L2CAP: libc::c_int = 0;
+// a device opens the interrupt channel right after the control one
+const HID_INTERRUPT_TIMEOUT: Duration = Duration::from_secs(5);
+// replies to GET_REPORT and SET_REPORT
+const HID_CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+// bigger than any L2CAP MTU a HID device negotiates
+const HID_MAX_PACKET_SIZE: usize = 1024;
+
+// https://github.com/bluez/bluez/blob/master/lib/l2cap.h
+#[repr(C)]
+struct SockaddrL2 {
+    l2_family: libc::sa_family_t,
+    l2_psm: u16,
+    l2_bdaddr: [u8; 6],
+    l2_cid: u16,
+    l2_bdaddr_type: u8,
+}
+
+// Waits for paired controllers to connect, the way they do once the PS
+// button is pressed. bluetoothd's input plugin listens on the same PSMs, so it
+// has to be disabled (bluetoothd --noplugin=input), and binding PSMs this low
+// needs CAP_NET_BIND_SERVICE.
+pub struct BluetoothHidListener {
+    control: L2capSocket,
+    interrupt: L2capSocket,
+    // interrupt channels that came in while waiting for another device's,
+    // until their control channel is accepted
+    pending: HashMap<BdAddr, (L2capSocket, Instant)>,
+}
+
+impl BluetoothHidListener {
+    // adapter None listens on every adapter
+    pub fn bind(adapter: Option<BdAddr>) -> io::Result<BluetoothHidListener> {
+        let adapter = adapter.unwrap_or_default();
+        Ok(BluetoothHidListener {
+            control: L2capSocket::listen(adapter, PSM_HID_CONTROL)?,
+            interrupt: L2capSocket::listen(adapter, PSM_HID_INTERRUPT)?,
+            pending: HashMap::new(),
+        })
+    }
+
+    // Block until a device has opened both channels
+    pub fn accept(&mut self) -> io::Result<BluetoothHidDevice> {
+        loop {
+            let (control, address) = self.control.accept(None)?;
+            // devices that gave up half way don't get to keep theirs
+            self.pending.retain(|_, (_, accepted)| accepted.elapsed() < HID_INTERRUPT_TIMEOUT);
+            if let Some((interrupt, _)) = self.pending.remove(&address) {
+                return Ok(BluetoothHidDevice::new(address, control, interrupt));
+            }
+
+            let deadline = Instant::now() + HID_INTERRUPT_TIMEOUT;
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match self.interrupt.accept(Some(timeout)) {
+                    Ok((interrupt, interrupt_address)) if interrupt_address == address => {
+                        return Ok(BluetoothHidDevice::new(address, control, interrupt));
+                    },
+                    // another device connecting at the same time, its control
+                    // channel is next in line
+                    Ok((interrupt, interrupt_address)) => {
+                        self.pending.insert(interrupt_address, (interrupt, Instant::now()));
+                    },
+                    // the device gave up half way, wait for the next one
+                    Err(ref error) if error.kind() == io::ErrorKind::TimedOut => break,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+    }
+}
+
+// A connected Bluetooth HID device with the same report interface as a USB
+// one, report buffers have the report id first
+pub struct BluetoothHidDevice {
+    address: BdAddr,
+    control: L2capSocket,
+    interrupt: L2capSocket,
+    blocking: bool,
+}
+
+impl BluetoothHidDevice {
+    fn new(address: BdAddr, control: L2capSocket, interrupt: L2capSocket) -> BluetoothHidDevice {
+        BluetoothHidDevice {
+            address,
+            control,
+            interrupt,
+            blocking: true,
+        }
+    }
+
+    pub fn address(&self) -> BdAddr {
+        self.address
+    }
+
+    pub fn set_blocking(&mut self, blocking: bool) {
+        self.blocking = blocking;
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        self.blocking
+    }
+
+    // Read one input report into data, blocking until it arrives unless the
+    // device is in non-blocking mode. Ok(0) means there was no report to read.
+    pub fn read(&self, data: &mut [u8]) -> io::Result<usize> {
+        let timeout = if self.blocking { None } else { Some(Duration::from_millis(0)) };
+        self.read_input(data, timeout)
+    }
+
+    // Like read but gives up with Ok(0) after timeout
+    pub fn read_timeout(&self, data: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.read_input(data, Some(timeout))
+    }
+
+    fn read_input(&self, data: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let mut packet = [0u8; HID_MAX_PACKET_SIZE];
+        let len = match self.interrupt.recv(&mut packet, timeout) {
+            Ok(len) => len,
+            Err(ref error) if error.kind() == io::ErrorKind::TimedOut => return Ok(0),
+            Err(error) => return Err(error),
+        };
+        match parse(&packet[..len])? {
+            HidpMessage::Data(ReportType::Input, report) => Ok(copy_report(&report, data)),
+            HidpMessage::Control(HIDP_CONTROL_VIRTUAL_CABLE_UNPLUG) => Err(disconnected()),
+            // nothing else is meant for us on this channel
+            _ => Ok(0),
+        }
+    }
+
+    // Send a request on the control channel and wait for its answer
+    fn control_request(&self, request: &[u8]) -> io::Result<HidpMessage> {
+        self.control.send(request)?;
+        let mut packet = [0u8; HID_MAX_PACKET_SIZE];
+        loop {
+            let len = self.control.recv(&mut packet, Some(HID_CONTROL_TIMEOUT))?;
+            match parse(&packet[..len])? {
+                HidpMessage::Control(HIDP_CONTROL_VIRTUAL_CABLE_UNPLUG) => return Err(disconnected()),
+                HidpMessage::Control(_) => continue,
+                message => return Ok(message),
+            }
+        }
+    }
+}
+
+impl HidDeviceIo for BluetoothHidDevice {
+    fn get_feature_report(&self, data: &mut [u8]) -> hid_rs::Result<usize> {
+        let report_id = *data.first().ok_or(Error::BufferTooSmall)?;
+        let buffer_size = data.len().min(u16::MAX as usize) as u16;
+        match self.control_request(&get_report(ReportType::Feature, report_id, buffer_size))? {
+            HidpMessage::Data(ReportType::Feature, report) => Ok(copy_report(&report, data)),
+            HidpMessage::Handshake(result) => {
+                handshake_result(result)?;
+                Err(Error::from(io::Error::new(io::ErrorKind::InvalidData, "GET_REPORT answered without data")))
+            },
+            message => Err(Error::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected answer to GET_REPORT {:?}", message)
+            ))),
+        }
+    }
+
+    fn set_feature_report(&self, data: &[u8]) -> hid_rs::Result<usize> {
+        match self.control_request(&set_report(ReportType::Feature, data))? {
+            HidpMessage::Handshake(result) => {
+                handshake_result(result)?;
+                Ok(data.len())
+            },
+            message => Err(Error::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected answer to SET_REPORT {:?}", message)
+            ))),
+        }
+    }
+
+    fn read_input_report(&self, data: &mut [u8]) -> hid_rs::Result<usize> {
+        Ok(self.read(data)?)
+    }
+
+    fn write_output_report(&self, data: &[u8]) -> hid_rs::Result<usize> {
+        self.interrupt.send(&super::data(ReportType::Output, data))?;
+        Ok(data.len())
+    }
+}
+
+// as much as fits, a short buffer cuts the report off like hidraw does
+fn copy_report(report: &[u8], data: &mut [u8]) -> usize {
+    let len = report.len().min(data.len());
+    data[..len].copy_from_slice(&report[..len]);
+    len
+}
+
+fn disconnected() -> io::Error {
+    io::Error::from(Error::Disconnected { code: None })
+}
+
+// A SOCK_SEQPACKET L2CAP socket, each send and recv is one packet
+struct L2capSocket {
+    fd: RawFd,
+}
+
+impl L2capSocket {
+    fn listen(adapter: BdAddr, psm: u16) -> io::Result<L2capSocket> {
+        let fd = unsafe { libc::socket(AF_BLUETOOTH, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, BTPROTO_L2CAP) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // closed on drop from here on
+        let socket = L2capSocket {
+            fd,
+        };
+
+        let address = SockaddrL2 {
+            l2_family: AF_BLUETOOTH as libc::sa_family_t,
+            l2_psm: psm.to_le(),
+            l2_bdaddr: adapter.to_le_bytes(),
+            l2_cid: 0,
+            l2_bdaddr_type: 0, // BR/EDR
+        };
+        let result = unsafe {
+            libc::bind(
+                socket.fd,
+                &address as *const SockaddrL2 as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrL2>() as libc::socklen_t
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::listen(socket.fd, 5) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    fn accept(&self, timeout: Option<Duration>) -> io::Result<(L2capSocket, BdAddr)> {
+        self.wait(timeout)?;
+        let mut address: SockaddrL2 = unsafe { std::mem::zeroed() };
+        let mut address_len = std::mem::size_of::<SockaddrL2>() as libc::socklen_t;
+        let fd = unsafe {
+            libc::accept4(
+                self.fd,
+                &mut address as *mut SockaddrL2 as *mut libc::sockaddr,
+                &mut address_len,
+                libc::SOCK_CLOEXEC
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((L2capSocket { fd }, BdAddr::from_le_bytes(address.l2_bdaddr)))
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let sent = unsafe { libc::send(self.fd, packet.as_ptr() as *const libc::c_void, packet.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, packet: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        self.wait(timeout)?;
+        let len = unsafe { libc::recv(self.fd, packet.as_mut_ptr() as *mut libc::c_void, packet.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // the other end closed the channel
+        if len == 0 {
+            return Err(disconnected());
+        }
+        Ok(len as usize)
+    }
+
+    // until the socket is readable, TimedOut if that takes longer than timeout
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout_ms = match timeout {
+            None => -1,
+            Some(timeout) => timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+        };
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if ready == 0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for the Bluetooth device"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for L2capSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,254 @@
+use std::io;
+use std::os::unix::io::RawFd;
+
+use super::{
+    BdAddr,
+    BluetoothAdapter,
+};
+
+// Adapters are asked through the kernel's Bluetooth management interface, the
+// same one bluetoothd uses. Reading is allowed without root.
+// https://git.kernel.org/pub/scm/bluetooth/bluez.git/tree/doc/mgmt-api.txt
+const AF_BLUETOOTH: libc::c_int = 31;
+const BTPROTO_HCI: libc::c_int = 1;
+const HCI_DEV_NONE: u16 = 0xffff;
+const HCI_CHANNEL_CONTROL: u16 = 3;
+
+const MGMT_OP_READ_INDEX_LIST: u16 = 0x0003;
+const MGMT_OP_READ_INFO: u16 = 0x0004;
+const MGMT_EV_CMD_COMPLETE: u16 = 0x0001;
+const MGMT_EV_CMD_STATUS: u16 = 0x0002;
+const MGMT_HEADER_SIZE: usize = 6;
+const MGMT_SETTING_POWERED: u32 = 1 << 0;
+const MGMT_TIMEOUT_MS: libc::c_int = 1000;
+const MGMT_MAX_NAME_LENGTH: usize = 249;
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+pub fn list_adapters() -> io::Result<Vec<BluetoothAdapter>> {
+    let socket = match MgmtSocket::open() {
+        Ok(socket) => socket,
+        // no Bluetooth in this kernel, so no adapters either
+        Err(ref error) if error.raw_os_error() == Some(libc::EAFNOSUPPORT) => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+
+    let index_list = socket.command(MGMT_OP_READ_INDEX_LIST, HCI_DEV_NONE, &[])?;
+    parse_index_list(&index_list)?
+        .into_iter()
+        .map(|index| parse_info(index, &socket.command(MGMT_OP_READ_INFO, index, &[])?))
+        .collect()
+}
+
+// a count, then that many controller indexes
+fn parse_index_list(index_list: &[u8]) -> io::Result<Vec<u16>> {
+    if index_list.len() < 2 {
+        return Err(truncated_reply());
+    }
+    let count = u16::from_le_bytes([index_list[0], index_list[1]]) as usize;
+    if index_list.len() < 2 + count * 2 {
+        return Err(truncated_reply());
+    }
+    Ok(index_list[2..2 + count * 2]
+        .chunks(2)
+        .map(|index| u16::from_le_bytes([index[0], index[1]]))
+        .collect())
+}
+
+// address, version, manufacturer, supported and current settings, class,
+// name, short name
+fn parse_info(index: u16, info: &[u8]) -> io::Result<BluetoothAdapter> {
+    if info.len() < 20 + MGMT_MAX_NAME_LENGTH {
+        return Err(truncated_reply());
+    }
+    let mut address = [0u8; 6];
+    address.copy_from_slice(&info[0..6]);
+    let current_settings = u32::from_le_bytes([info[13], info[14], info[15], info[16]]);
+    let name = &info[20..20 + MGMT_MAX_NAME_LENGTH];
+    let name_len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+
+    Ok(BluetoothAdapter {
+        id: format!("hci{}", index),
+        address: BdAddr::from_le_bytes(address),
+        name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+        powered: current_settings & MGMT_SETTING_POWERED != 0,
+    })
+}
+
+fn truncated_reply() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated Bluetooth management reply")
+}
+
+struct MgmtSocket {
+    fd: RawFd,
+}
+
+impl MgmtSocket {
+    fn open() -> io::Result<MgmtSocket> {
+        let fd = unsafe { libc::socket(AF_BLUETOOTH, libc::SOCK_RAW | libc::SOCK_CLOEXEC, BTPROTO_HCI) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // closed on drop from here on
+        let socket = MgmtSocket {
+            fd,
+        };
+
+        let address = SockaddrHci {
+            hci_family: AF_BLUETOOTH as libc::sa_family_t,
+            hci_dev: HCI_DEV_NONE,
+            hci_channel: HCI_CHANNEL_CONTROL,
+        };
+        let result = unsafe {
+            libc::bind(
+                socket.fd,
+                &address as *const SockaddrHci as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrHci>() as libc::socklen_t
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    // Send a command and wait for its reply, returns the reply's parameters.
+    // Events for anything else that arrive in the meantime are skipped.
+    fn command(&self, opcode: u16, index: u16, params: &[u8]) -> io::Result<Vec<u8>> {
+        let mut request = Vec::with_capacity(MGMT_HEADER_SIZE + params.len());
+        request.extend_from_slice(&opcode.to_le_bytes());
+        request.extend_from_slice(&index.to_le_bytes());
+        request.extend_from_slice(&(params.len() as u16).to_le_bytes());
+        request.extend_from_slice(params);
+        let written = unsafe { libc::write(self.fd, request.as_ptr() as *const libc::c_void, request.len()) };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buffer = vec![0u8; 1024];
+        loop {
+            let mut poll_fd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, MGMT_TIMEOUT_MS) };
+            if ready < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if ready == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no reply to Bluetooth management command {:#06x}", opcode)
+                ));
+            }
+
+            let len = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let event = &buffer[..len as usize];
+            // event code, controller index, length, then for command replies
+            // the opcode and a status
+            if event.len() < MGMT_HEADER_SIZE + 3 {
+                continue;
+            }
+            let code = u16::from_le_bytes([event[0], event[1]]);
+            let event_index = u16::from_le_bytes([event[2], event[3]]);
+            let reply_opcode = u16::from_le_bytes([event[6], event[7]]);
+            let status = event[8];
+            if (code != MGMT_EV_CMD_COMPLETE && code != MGMT_EV_CMD_STATUS) ||
+                reply_opcode != opcode || event_index != index {
+                continue;
+            }
+            if status != 0 {
+                return Err(io::Error::other(
+                    format!("Bluetooth management command {:#06x} failed with status {:#04x}", opcode, status)
+                ));
+            }
+            return Ok(event[MGMT_HEADER_SIZE + 3..].to_vec());
+        }
+    }
+}
+
+impl Drop for MgmtSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // READ_INDEX_LIST reply with hci0 and hci2
+    const INDEX_LIST: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x02, 0x00];
+
+    // READ_INFO reply laid out like a CSR USB dongle's: 4.0, powered,
+    // connectable, bondable, BR/EDR and LE, named "desktop"
+    fn info_reply() -> Vec<u8> {
+        let mut info = vec![
+            0x13, 0x71, 0xda, 0x7d, 0x1a, 0x00, // address
+            0x06, // version
+            0x0a, 0x00, // manufacturer
+            0xff, 0xbf, 0x01, 0x00, // supported settings
+            0x93, 0x02, 0x00, 0x00, // current settings
+            0x0c, 0x01, 0x1c, // class of device
+        ];
+        let mut name = [0u8; MGMT_MAX_NAME_LENGTH];
+        name[..7].copy_from_slice(b"desktop");
+        info.extend_from_slice(&name);
+        info.extend_from_slice(&[0u8; 11]); // short name
+        info
+    }
+
+    #[test]
+    fn index_list() {
+        assert_eq!(parse_index_list(&INDEX_LIST).unwrap(), vec![0, 2]);
+        assert_eq!(parse_index_list(&[0x00, 0x00]).unwrap(), Vec::<u16>::new());
+        // only what the count says
+        assert_eq!(parse_index_list(&[0x01, 0x00, 0x01, 0x00, 0x05, 0x00]).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn truncated_index_list() {
+        for reply in [&[][..], &[0x02][..], &INDEX_LIST[..5]].iter() {
+            assert_eq!(parse_index_list(reply).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn info() {
+        let adapter = parse_info(2, &info_reply()).unwrap();
+        assert_eq!(adapter, BluetoothAdapter {
+            id: "hci2".to_string(),
+            address: "00:1a:7d:da:71:13".parse().unwrap(),
+            name: "desktop".to_string(),
+            powered: true,
+        });
+    }
+
+    #[test]
+    fn info_powered_off_with_a_full_length_name() {
+        let mut info = info_reply();
+        info[13] &= !(MGMT_SETTING_POWERED as u8);
+        for c in info[20..20 + MGMT_MAX_NAME_LENGTH].iter_mut() {
+            *c = b'a';
+        }
+        let adapter = parse_info(0, &info).unwrap();
+        assert!(!adapter.powered);
+        assert_eq!(adapter.name.len(), MGMT_MAX_NAME_LENGTH);
+    }
+
+    #[test]
+    fn truncated_info() {
+        let info = info_reply();
+        let error = parse_info(0, &info[..20 + MGMT_MAX_NAME_LENGTH - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
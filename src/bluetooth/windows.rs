@@ -0,0 +1,86 @@
+use winapi::shared::minwindef::{
+    FALSE,
+};
+use winapi::shared::winerror::{
+    ERROR_NO_MORE_ITEMS,
+    ERROR_SUCCESS,
+};
+use winapi::um::bluetoothapis::{
+    BLUETOOTH_FIND_RADIO_PARAMS,
+    BLUETOOTH_RADIO_INFO,
+    BluetoothFindFirstRadio,
+    BluetoothFindNextRadio,
+    BluetoothFindRadioClose,
+    BluetoothGetRadioInfo,
+    BluetoothIsConnectable,
+};
+use winapi::um::handleapi::{
+    CloseHandle,
+};
+use winapi::um::winnt::{
+    HANDLE,
+};
+
+use std::io;
+
+use super::{
+    BdAddr,
+    BluetoothAdapter,
+};
+
+// Windows has no names like hci0 for radios, they are numbered in the order
+// BluetoothFindNextRadio returns them
+pub fn list_adapters() -> io::Result<Vec<BluetoothAdapter>> {
+    let radio_params = BLUETOOTH_FIND_RADIO_PARAMS {
+        dwSize: std::mem::size_of::<BLUETOOTH_FIND_RADIO_PARAMS>() as u32
+    };
+    let mut radio: HANDLE = std::ptr::null_mut();
+    let h_find = unsafe { BluetoothFindFirstRadio(&radio_params, &mut radio) };
+    if h_find.is_null() {
+        let error = io::Error::last_os_error();
+        // no radio is an empty list, not an error
+        return match error.raw_os_error() {
+            Some(code) if code == ERROR_NO_MORE_ITEMS as i32 => Ok(Vec::new()),
+            _ => Err(error),
+        };
+    }
+
+    let mut adapters = Vec::new();
+    let result = loop {
+        let radio_info = get_radio_info(radio);
+        // a radio that is switched off is still found, connectable is the
+        // closest the API gets to telling, see BluetoothAdapter::powered
+        let powered = unsafe { BluetoothIsConnectable(radio) } != FALSE;
+        unsafe { CloseHandle(radio) };
+        match radio_info {
+            Ok(radio_info) => adapters.push(BluetoothAdapter {
+                id: format!("radio{}", adapters.len()),
+                address: BdAddr::from(radio_info.address),
+                name: wide_to_string(&radio_info.szName),
+                powered,
+            }),
+            Err(error) => break Err(error),
+        }
+        if unsafe { BluetoothFindNextRadio(h_find, &mut radio) } == FALSE {
+            break Ok(());
+        }
+    };
+    unsafe { BluetoothFindRadioClose(h_find) };
+
+    result.map(|_| adapters)
+}
+
+fn get_radio_info(radio: HANDLE) -> io::Result<BLUETOOTH_RADIO_INFO> {
+    let mut radio_info = BLUETOOTH_RADIO_INFO::default();
+    radio_info.dwSize = std::mem::size_of::<BLUETOOTH_RADIO_INFO>() as u32;
+    let result = unsafe { BluetoothGetRadioInfo(radio, &mut radio_info) };
+    if result != ERROR_SUCCESS {
+        return Err(io::Error::from_raw_os_error(result as i32));
+    }
+    Ok(radio_info)
+}
+
+fn wide_to_string(wide: &[u16]) -> String {
+    let len = wide.iter().position(|&c| c == 0).unwrap_or(wide.len());
+    String::from_utf16_lossy(&wide[..len])
+}
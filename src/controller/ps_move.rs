@@ -1,37 +1,281 @@
+pub mod calibration;
+pub mod gyro_bias;
+pub mod imu;
+pub mod input;
+pub mod magnetometer;
+pub mod output;
+
+pub use calibration::{
+    load_calibration,
+    PSMoveCalibration,
+};
+pub use gyro_bias::{
+    load_gyro_bias,
+    save_gyro_bias,
+    GyroBiasEstimator,
+};
+pub use imu::{
+    ImuSample,
+    PSMoveImu,
+};
+pub use input::{
+    PSMoveBattery,
+    PSMoveButton,
+    PSMoveInputReport,
+};
+pub use magnetometer::{
+    load_mag_calibration,
+    save_mag_calibration,
+    MagCalibration,
+    MagCalibrator,
+};
+pub use output::{
+    PSMoveOutput,
+    PSMoveOutputScheduler,
+};
+
+use hid_rs::usb::backend::{
+    HidDeviceIo,
+};
 use hid_rs::usb::device::{
-    HIDDevice,
+    HIDDeviceInfo,
 };
-use hid_rs::usb::hid::{
-    hid_get_feature_report,
+use hid_rs::usb::{
+    HidQuery,
 };
 
 use std::io;
 
-use crate::utils::{
-    address_bytes_to_string,
+use crate::bluetooth::{
+    BdAddr,
 };
 
 pub const PS_MOVE_VID: u16 = 0x054c;
-pub const PS_MOVE_PID: u16 = 0x03d5; // PSMove ZCM1
+pub const PS_MOVE_ZCM1_PID: u16 = 0x03d5; // CECH-ZC1, PS3
+pub const PS_MOVE_ZCM2_PID: u16 = 0x0c5e; // CECH-ZCM2, PS4
 pub const PSMOVE_BTADDR_GET_ZCM1_SIZE: usize = 16;
 pub const PSMOVE_BTADDR_GET_ZCM2_SIZE: usize = 21;
 pub const PSMOVE_BTADDR_GET_MAX_SIZE: usize = PSMOVE_BTADDR_GET_ZCM2_SIZE;
+pub const PSMOVE_BTADDR_SET_SIZE: usize = 23;
+// Bluetooth HID devices on Windows have the HID service class in their path
+const BLUETOOTH_HID_SERVICE: &str = "00001124-0000-1000-8000-00805f9b34fb";
+// The Move's report descriptor has two top-level collections and Windows makes
+// each one its own device (&col01#, &col02#). Input and output reports go
+// through the joystick collection, feature reports (BT address, calibration)
+// through the other one. hidraw has a single node for both.
+pub const PS_MOVE_INPUT_USAGE_PAGE: u16 = 0x01; // Generic Desktop
+pub const PS_MOVE_INPUT_USAGE: u16 = 0x04; // Joystick
 
 pub enum PSMoveRequestType {
+    GetInput = 0x01,
     GetBTAddr = 0x04,
+    SetBTAddr = 0x05,
+    SetLEDs = 0x06,
+    GetCalibration = 0x10,
+}
+
+// The original Move and the one sold with the PS4, they lay out some reports
+// differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PSMoveModel {
+    ZCM1,
+    ZCM2,
+}
+
+impl PSMoveModel {
+    pub fn from_product_id(product_id: u16) -> Option<PSMoveModel> {
+        match product_id {
+            PS_MOVE_ZCM1_PID => Some(PSMoveModel::ZCM1),
+            PS_MOVE_ZCM2_PID => Some(PSMoveModel::ZCM2),
+            _ => None,
+        }
+    }
+
+    pub fn from_device_info(device_info: &HIDDeviceInfo) -> io::Result<PSMoveModel> {
+        PSMoveModel::from_product_id(device_info.product_id)
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:04x}:{:04x} is not a PS Move", device_info.vendor_id, device_info.product_id)
+            ))
+    }
+
+    pub fn product_id(self) -> u16 {
+        match self {
+            PSMoveModel::ZCM1 => PS_MOVE_ZCM1_PID,
+            PSMoveModel::ZCM2 => PS_MOVE_ZCM2_PID,
+        }
+    }
+
+    fn btaddr_get_size(self) -> usize {
+        match self {
+            PSMoveModel::ZCM1 => PSMOVE_BTADDR_GET_ZCM1_SIZE,
+            PSMoveModel::ZCM2 => PSMOVE_BTADDR_GET_ZCM2_SIZE,
+        }
+    }
+}
+
+// the devices this module can talk to
+// https://github.com/psmoveservice/PSMoveService/blob/edbb31417/src/psmoveservice/PSMoveController/PSMoveController.cpp#L1057
+pub fn ps_move_query() -> HidQuery {
+    HidQuery::new()
+        .vendor_id(PS_MOVE_VID)
+        .product_ids(&[PS_MOVE_ZCM1_PID, PS_MOVE_ZCM2_PID])
+        .filter(is_feature_collection)
+}
+
+// the devices to read input from and send output reports to
+pub fn ps_move_input_query() -> HidQuery {
+    HidQuery::new()
+        .vendor_id(PS_MOVE_VID)
+        .product_ids(&[PS_MOVE_ZCM1_PID, PS_MOVE_ZCM2_PID])
+        .filter(is_input_collection)
+}
+
+pub fn is_feature_collection(device_info: &HIDDeviceInfo) -> bool {
+    if cfg!(windows) {
+        !(device_info.usage_page == PS_MOVE_INPUT_USAGE_PAGE && device_info.usage == PS_MOVE_INPUT_USAGE)
+    } else {
+        true
+    }
 }
 
-pub fn get_controller_pair(device: &HIDDevice) -> io::Result<(String, String)> {
-    let mut data = vec![0u8; PSMOVE_BTADDR_GET_MAX_SIZE];
+pub fn is_input_collection(device_info: &HIDDeviceInfo) -> bool {
+    if cfg!(windows) {
+        device_info.usage_page == PS_MOVE_INPUT_USAGE_PAGE && device_info.usage == PS_MOVE_INPUT_USAGE
+    } else {
+        true
+    }
+}
+
+// Pairing only works over USB. Linux gives Bluetooth devices their address as
+// serial number, USB Moves have none.
+// https://github.com/thp/psmoveapi/blob/4.0.12/src/psmove.c#L520
+pub fn is_usb_connection(device_info: &HIDDeviceInfo) -> bool {
+    if cfg!(windows) {
+        !device_info.path.to_lowercase().contains(BLUETOOTH_HID_SERVICE)
+    } else {
+        device_info.serial_number.is_empty()
+    }
+}
+
+// (host, controller) addresses
+pub fn get_controller_pair<D: HidDeviceIo>(device: &D, model: PSMoveModel) -> io::Result<(BdAddr, BdAddr)> {
+    let mut data = vec![0u8; model.btaddr_get_size()];
     data[0] = PSMoveRequestType::GetBTAddr as u8;
-    hid_get_feature_report(device.handle, &mut data).unwrap();
-    let mut cont_addr = Vec::from(&data[1..7]);
-    cont_addr.reverse();
-    let mut host_addr = Vec::from(&data[10..16]);
-    host_addr.reverse();
-
-    Ok((
-        address_bytes_to_string(host_addr.as_slice()),
-        address_bytes_to_string(cont_addr.as_slice())
-    ))
-}
\ No newline at end of file
+    // ZCM2 has a longer report but the addresses are in the same place. Only
+    // check they came back like psmove_get_btaddr does, backends don't agree
+    // on what the count includes.
+    let len = device.get_feature_report(&mut data)?;
+    if len < PSMOVE_BTADDR_GET_ZCM1_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} Bluetooth address report is {} bytes, expected at least {}", model, len, PSMOVE_BTADDR_GET_ZCM1_SIZE)
+        ));
+    }
+    let mut cont_addr = [0u8; 6];
+    cont_addr.copy_from_slice(&data[1..7]);
+    let mut host_addr = [0u8; 6];
+    host_addr.copy_from_slice(&data[10..16]);
+
+    Ok((BdAddr::from_le_bytes(host_addr), BdAddr::from_le_bytes(cont_addr)))
+}
+
+// Make the controller connect to host_addr over Bluetooth once it is unplugged.
+// The new host is read back to check the controller took it.
+pub fn pair_controller<D: HidDeviceIo>(device: &D, model: PSMoveModel, host_addr: BdAddr) -> io::Result<()> {
+    let (cur_host_addr, _) = get_controller_pair(device, model)?;
+    if cur_host_addr == host_addr {
+        return Ok(());
+    }
+
+    let mut data = vec![0u8; PSMOVE_BTADDR_SET_SIZE];
+    data[0] = PSMoveRequestType::SetBTAddr as u8;
+    data[1..7].copy_from_slice(&host_addr.to_le_bytes());
+    device.set_feature_report(&data)?;
+
+    let (new_host_addr, _) = get_controller_pair(device, model)?;
+    if new_host_addr != host_addr {
+        return Err(io::Error::other(
+            format!("controller is paired with {} instead of {}", new_host_addr, host_addr)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hid_rs::usb::backend::mock::{
+        MockDevice,
+    };
+
+    use super::*;
+
+    const HOST: BdAddr = BdAddr([0x00, 0x1b, 0xdc, 0x0f, 0xa2, 0x7e]);
+    const NEW_HOST: BdAddr = BdAddr([0x5c, 0xf3, 0x70, 0x61, 0x2b, 0x04]);
+    const CONTROLLER: BdAddr = BdAddr([0x00, 0x06, 0xf7, 0xc1, 0x33, 0x8d]);
+
+    // GetBTAddr reply: controller address, three bytes we don't use, host address
+    fn btaddr_report(model: PSMoveModel, host: BdAddr) -> Vec<u8> {
+        let mut report = vec![0u8; model.btaddr_get_size()];
+        report[0] = PSMoveRequestType::GetBTAddr as u8;
+        report[1..7].copy_from_slice(&CONTROLLER.to_le_bytes());
+        report[7..10].copy_from_slice(&[0x08, 0x25, 0x00]);
+        report[10..16].copy_from_slice(&host.to_le_bytes());
+        report
+    }
+
+    #[test]
+    fn controller_pair_for_both_models() {
+        for &model in [PSMoveModel::ZCM1, PSMoveModel::ZCM2].iter() {
+            let device = MockDevice::default();
+            device.set_feature_report_reply(&btaddr_report(model, HOST));
+            assert_eq!(get_controller_pair(&device, model).unwrap(), (HOST, CONTROLLER));
+        }
+    }
+
+    #[test]
+    fn controller_pair_takes_a_short_count() {
+        // the addresses end at byte 16, what comes after doesn't matter
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&btaddr_report(PSMoveModel::ZCM2, HOST)[..PSMOVE_BTADDR_GET_ZCM1_SIZE]);
+        assert_eq!(get_controller_pair(&device, PSMoveModel::ZCM2).unwrap(), (HOST, CONTROLLER));
+    }
+
+    #[test]
+    fn controller_pair_rejects_short_report() {
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&btaddr_report(PSMoveModel::ZCM1, HOST)[..10]);
+        let error = get_controller_pair(&device, PSMoveModel::ZCM1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "ZCM1 Bluetooth address report is 10 bytes, expected at least 16");
+    }
+
+    #[test]
+    fn pair_writes_host_and_reads_it_back() {
+        let device = MockDevice::default();
+        device.push_feature_report_reply(&btaddr_report(PSMoveModel::ZCM2, HOST));
+        device.set_feature_report_reply(&btaddr_report(PSMoveModel::ZCM2, NEW_HOST));
+        pair_controller(&device, PSMoveModel::ZCM2, NEW_HOST).unwrap();
+
+        let mut expected = vec![0u8; PSMOVE_BTADDR_SET_SIZE];
+        expected[0] = PSMoveRequestType::SetBTAddr as u8;
+        expected[1..7].copy_from_slice(&[0x04, 0x2b, 0x61, 0x70, 0xf3, 0x5c]);
+        assert_eq!(device.feature_writes(), vec![expected]);
+    }
+
+    #[test]
+    fn pair_with_current_host_writes_nothing() {
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&btaddr_report(PSMoveModel::ZCM1, HOST));
+        pair_controller(&device, PSMoveModel::ZCM1, HOST).unwrap();
+        assert!(device.feature_writes().is_empty());
+    }
+
+    #[test]
+    fn pair_fails_when_controller_keeps_old_host() {
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&btaddr_report(PSMoveModel::ZCM1, HOST));
+        assert!(pair_controller(&device, PSMoveModel::ZCM1, NEW_HOST).is_err());
+        assert_eq!(device.feature_writes().len(), 1);
+    }
+}
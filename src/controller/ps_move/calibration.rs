@@ -0,0 +1,383 @@
+use hid_rs::usb::backend::{
+    HidDeviceIo,
+};
+
+use std::env;
+use std::f32::consts::PI;
+use std::fs;
+use std::io;
+use std::path::{
+    Path, PathBuf,
+};
+
+use super::input::decode_vector;
+use super::{
+    get_controller_pair,
+    PSMoveModel,
+    PSMoveRequestType,
+};
+
+// The factory calibration comes in several feature reports, the second byte
+// numbers them and has the high bit set on the last one. Each part after the
+// first repeats the 2 byte header, which is dropped when putting them together.
+// https://github.com/thp/psmoveapi/blob/4.0.12/src/psmove.c
+// https://github.com/nitsch/moveonpc/wiki/Calibration-data
+pub const PSMOVE_CALIBRATION_SIZE: usize = 49;
+const PSMOVE_CALIBRATION_HEADER_SIZE: usize = 2;
+const PSMOVE_ZCM1_CALIBRATION_PARTS: usize = 3;
+const PSMOVE_ZCM2_CALIBRATION_PARTS: usize = 2;
+// the rigs spin the controllers at these speeds to record the gyro readings
+const PSMOVE_ZCM1_GYRO_RPM: f32 = 80.0;
+const PSMOVE_ZCM2_GYRO_RPM: f32 = 90.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PSMoveCalibration {
+    pub model: PSMoveModel,
+    // raw accelerometer reading with each axis pointing down (-1g) and up (+1g)
+    pub accel_min: [i16; 3],
+    pub accel_max: [i16; 3],
+    // raw gyro reading at rest
+    pub gyro_bias: [i16; 3],
+    // rad/s per raw count once the bias is taken off
+    pub gyro_gain: [f32; 3],
+}
+
+impl PSMoveCalibration {
+    // blob is the reassembled calibration, see get_calibration_blob
+    pub fn parse(model: PSMoveModel, blob: &[u8]) -> io::Result<PSMoveCalibration> {
+        if blob.len() != calibration_blob_size(model) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} calibration is {} bytes, expected {}", model, blob.len(), calibration_blob_size(model))
+            ));
+        }
+
+        // Raw readings in six orientations from 0x04, which orientation has
+        // which axis pointing up or down differs between the models. The
+        // offsets are the ones on the moveonpc calibration page, they count
+        // in the reassembled blob.
+        let orientation = |index: usize| decode_vector(model, &blob[0x04 + 6 * index..]);
+        let (accel_min, accel_max) = match model {
+            PSMoveModel::ZCM1 => (
+                [orientation(1)[0], orientation(5)[1], orientation(2)[2]],
+                [orientation(3)[0], orientation(4)[1], orientation(0)[2]],
+            ),
+            PSMoveModel::ZCM2 => (
+                [orientation(1)[0], orientation(3)[1], orientation(5)[2]],
+                [orientation(0)[0], orientation(2)[1], orientation(4)[2]],
+            ),
+        };
+
+        // one reading per axis with the controller spinning around that axis
+        let (gyro_bias, rotation, rpm) = match model {
+            PSMoveModel::ZCM1 => (
+                decode_vector(model, &blob[0x30..]),
+                [
+                    decode_vector(model, &blob[0x46..])[0],
+                    decode_vector(model, &blob[0x50..])[1],
+                    decode_vector(model, &blob[0x5a..])[2],
+                ],
+                PSMOVE_ZCM1_GYRO_RPM,
+            ),
+            PSMoveModel::ZCM2 => (
+                decode_vector(model, &blob[0x28..]),
+                [
+                    decode_vector(model, &blob[0x30..])[0],
+                    decode_vector(model, &blob[0x36..])[1],
+                    decode_vector(model, &blob[0x3c..])[2],
+                ],
+                PSMOVE_ZCM2_GYRO_RPM,
+            ),
+        };
+        let rad_s = rpm * 2.0 * PI / 60.0;
+        let mut gyro_gain = [0f32; 3];
+        for axis in 0..3 {
+            let counts = rotation[axis] as f32 - gyro_bias[axis] as f32;
+            if counts == 0.0 || accel_max[axis] == accel_min[axis] {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "calibration has no range on an axis"));
+            }
+            gyro_gain[axis] = rad_s / counts;
+        }
+
+        Ok(PSMoveCalibration {
+            model,
+            accel_min,
+            accel_max,
+            gyro_bias,
+            gyro_gain,
+        })
+    }
+
+    // in g, -1 to 1 along an axis pointing down to up
+    pub fn accel_g(&self, raw: [i16; 3]) -> [f32; 3] {
+        let mut accel = [0f32; 3];
+        for axis in 0..3 {
+            let min = self.accel_min[axis] as f32;
+            let max = self.accel_max[axis] as f32;
+            accel[axis] = 2.0 * (raw[axis] as f32 - min) / (max - min) - 1.0;
+        }
+        accel
+    }
+
+    pub fn gyro_rad_s(&self, raw: [i16; 3]) -> [f32; 3] {
+        let mut gyro = [0f32; 3];
+        for axis in 0..3 {
+            gyro[axis] = (raw[axis] as f32 - self.gyro_bias[axis] as f32) * self.gyro_gain[axis];
+        }
+        gyro
+    }
+}
+
+fn calibration_parts(model: PSMoveModel) -> usize {
+    match model {
+        PSMoveModel::ZCM1 => PSMOVE_ZCM1_CALIBRATION_PARTS,
+        PSMoveModel::ZCM2 => PSMOVE_ZCM2_CALIBRATION_PARTS,
+    }
+}
+
+pub fn calibration_blob_size(model: PSMoveModel) -> usize {
+    PSMOVE_CALIBRATION_SIZE + (calibration_parts(model) - 1) * (PSMOVE_CALIBRATION_SIZE - PSMOVE_CALIBRATION_HEADER_SIZE)
+}
+
+// Read every part of the calibration and put them together. The controller
+// hands out the next part on each read, so parts can come in any order.
+pub fn get_calibration_blob<D: HidDeviceIo>(device: &D, model: PSMoveModel) -> io::Result<Vec<u8>> {
+    let parts = calibration_parts(model);
+    let mut blob = vec![0u8; calibration_blob_size(model)];
+    let mut received = vec![false; parts];
+
+    // a controller that keeps repeating a part shouldn't keep us here forever
+    for _ in 0..parts * 2 {
+        if received.iter().all(|&part| part) {
+            break;
+        }
+        let mut data = [0u8; PSMOVE_CALIBRATION_SIZE];
+        data[0] = PSMoveRequestType::GetCalibration as u8;
+        // hidraw counts the report id, some backends leave it out
+        let len = device.get_feature_report(&mut data)?;
+        if len < PSMOVE_CALIBRATION_SIZE - 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("calibration report is {} bytes, expected {}", len, PSMOVE_CALIBRATION_SIZE)
+            ));
+        }
+
+        let part = (data[1] & 0x0f) as usize;
+        if part >= parts {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected calibration part {:#04x}", data[1])
+            ));
+        }
+        if part == 0 {
+            blob[..PSMOVE_CALIBRATION_SIZE].copy_from_slice(&data);
+        } else {
+            let start = PSMOVE_CALIBRATION_SIZE + (part - 1) * (PSMOVE_CALIBRATION_SIZE - PSMOVE_CALIBRATION_HEADER_SIZE);
+            blob[start..start + PSMOVE_CALIBRATION_SIZE - PSMOVE_CALIBRATION_HEADER_SIZE]
+                .copy_from_slice(&data[PSMOVE_CALIBRATION_HEADER_SIZE..]);
+        }
+        received[part] = true;
+    }
+
+    if !received.iter().all(|&part| part) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "controller did not send every calibration part"));
+    }
+    Ok(blob)
+}
+
+// Where calibrations are kept between runs, None if there is no home to put
+// them in
+pub fn calibration_cache_dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
+    base.map(|base| base.join("rsvr").join("calibration"))
+}
+
+// The calibration never changes so it is only read from the controller the
+// first time, then from cache_dir. The blob is cached as is, keyed by the
+// controller's Bluetooth address since that is what we know it by over both
+// USB and Bluetooth.
+pub fn load_calibration<D: HidDeviceIo>(device: &D, model: PSMoveModel, cache_dir: &Path) -> io::Result<PSMoveCalibration> {
+    let (_, controller_addr) = get_controller_pair(device, model)?;
+    let cache_file = cache_dir.join(format!("{}.bin", controller_addr.to_string().replace(':', "")));
+
+    if let Ok(blob) = fs::read(&cache_file) {
+        if let Ok(calibration) = PSMoveCalibration::parse(model, &blob) {
+            return Ok(calibration);
+        }
+    }
+
+    let blob = get_calibration_blob(device, model)?;
+    let calibration = PSMoveCalibration::parse(model, &blob)?;
+    fs::create_dir_all(cache_dir)?;
+    fs::write(&cache_file, &blob)?;
+    Ok(calibration)
+}
+
+#[cfg(test)]
+mod tests {
+    use hid_rs::usb::backend::mock::{
+        MockDevice,
+    };
+
+    use super::*;
+
+    // a calibration part with every data byte set to fill
+    fn part(header: u8, fill: u8) -> Vec<u8> {
+        let mut report = vec![fill; PSMOVE_CALIBRATION_SIZE];
+        report[0] = PSMoveRequestType::GetCalibration as u8;
+        report[1] = header;
+        report
+    }
+
+    fn expected_blob(parts: &[Vec<u8>]) -> Vec<u8> {
+        let mut blob = parts[0].clone();
+        for part in parts[1..].iter() {
+            blob.extend_from_slice(&part[PSMOVE_CALIBRATION_HEADER_SIZE..]);
+        }
+        blob
+    }
+
+    #[test]
+    fn zcm1_blob_from_parts_in_any_order() {
+        let device = MockDevice::default();
+        let parts = [part(0x00, 0xa0), part(0x01, 0xa1), part(0x82, 0xa2)];
+        for &index in [1, 2, 0].iter() {
+            device.push_feature_report_reply(&parts[index]);
+        }
+        let blob = get_calibration_blob(&device, PSMoveModel::ZCM1).unwrap();
+        assert_eq!(blob.len(), calibration_blob_size(PSMoveModel::ZCM1));
+        assert_eq!(blob, expected_blob(&parts));
+    }
+
+    #[test]
+    fn zcm2_blob_has_two_parts() {
+        let device = MockDevice::default();
+        let parts = [part(0x00, 0xb0), part(0x81, 0xb1)];
+        device.push_feature_report_reply(&parts[0]);
+        device.push_feature_report_reply(&parts[1]);
+        let blob = get_calibration_blob(&device, PSMoveModel::ZCM2).unwrap();
+        assert_eq!(blob.len(), calibration_blob_size(PSMoveModel::ZCM2));
+        assert_eq!(blob, expected_blob(&parts));
+    }
+
+    // a vector the way the model stores it, see decode_vector
+    fn put_vector(blob: &mut [u8], model: PSMoveModel, offset: usize, vector: [i16; 3]) {
+        for (axis, &value) in vector.iter().enumerate() {
+            let raw = match model {
+                PSMoveModel::ZCM1 => (value as i32 + 0x8000) as u16,
+                PSMoveModel::ZCM2 => value as u16,
+            };
+            blob[offset + axis * 2..offset + axis * 2 + 2].copy_from_slice(&raw.to_le_bytes());
+        }
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    // Each orientation only has its own axis set, so a value that comes from
+    // the wrong offset or axis shows up as 0
+    #[test]
+    fn parse_zcm1() {
+        let model = PSMoveModel::ZCM1;
+        let mut blob = vec![0u8; calibration_blob_size(model)];
+        put_vector(&mut blob, model, 0x04, [0, 0, 4400]);   // z up
+        put_vector(&mut blob, model, 0x0a, [-4200, 0, 0]);  // x down
+        put_vector(&mut blob, model, 0x10, [0, 0, -3900]);  // z down
+        put_vector(&mut blob, model, 0x16, [4300, 0, 0]);   // x up
+        put_vector(&mut blob, model, 0x1c, [0, 4250, 0]);   // y up
+        put_vector(&mut blob, model, 0x22, [0, -4150, 0]);  // y down
+        put_vector(&mut blob, model, 0x30, [5, -3, 2]);     // gyro at rest
+        put_vector(&mut blob, model, 0x46, [2005, 0, 0]);   // spinning about x
+        put_vector(&mut blob, model, 0x50, [0, 2097, 0]);   // about y
+        put_vector(&mut blob, model, 0x5a, [0, 0, 1902]);   // about z
+
+        let calibration = PSMoveCalibration::parse(model, &blob).unwrap();
+        assert_eq!(calibration.accel_min, [-4200, -4150, -3900]);
+        assert_eq!(calibration.accel_max, [4300, 4250, 4400]);
+        assert_eq!(calibration.gyro_bias, [5, -3, 2]);
+        let rad_s = PSMOVE_ZCM1_GYRO_RPM * 2.0 * PI / 60.0;
+        assert_close(calibration.gyro_gain, [rad_s / 2000.0, rad_s / 2100.0, rad_s / 1900.0]);
+
+        assert_close(calibration.accel_g([4300, -4150, 250]), [1.0, -1.0, 0.0]);
+        assert_close(calibration.gyro_rad_s([2005, -3, 2]), [rad_s, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn parse_zcm2() {
+        let model = PSMoveModel::ZCM2;
+        let mut blob = vec![0u8; calibration_blob_size(model)];
+        put_vector(&mut blob, model, 0x04, [4110, 0, 0]);   // x up
+        put_vector(&mut blob, model, 0x0a, [-4080, 0, 0]);  // x down
+        put_vector(&mut blob, model, 0x10, [0, 4095, 0]);   // y up
+        put_vector(&mut blob, model, 0x16, [0, -4101, 0]);  // y down
+        put_vector(&mut blob, model, 0x1c, [0, 0, 4120]);   // z up
+        put_vector(&mut blob, model, 0x22, [0, 0, -4070]);  // z down
+        put_vector(&mut blob, model, 0x28, [-7, 4, 1]);     // gyro at rest
+        put_vector(&mut blob, model, 0x30, [1593, 0, 0]);   // spinning about x
+        put_vector(&mut blob, model, 0x36, [0, 1604, 0]);   // about y
+        put_vector(&mut blob, model, 0x3c, [0, 0, -1599]);  // about z, the other way
+
+        let calibration = PSMoveCalibration::parse(model, &blob).unwrap();
+        assert_eq!(calibration.accel_min, [-4080, -4101, -4070]);
+        assert_eq!(calibration.accel_max, [4110, 4095, 4120]);
+        assert_eq!(calibration.gyro_bias, [-7, 4, 1]);
+        let rad_s = PSMOVE_ZCM2_GYRO_RPM * 2.0 * PI / 60.0;
+        assert_close(calibration.gyro_gain, [rad_s / 1600.0, rad_s / 1600.0, rad_s / -1600.0]);
+
+        assert_close(calibration.accel_g([15, -4101, 4120]), [0.0, -1.0, 1.0]);
+        assert_close(calibration.gyro_rad_s([-7, 1604, -1599]), [0.0, rad_s, rad_s]);
+    }
+
+    #[test]
+    fn parse_rejects_bad_blobs() {
+        let model = PSMoveModel::ZCM2;
+        let blob = vec![0u8; calibration_blob_size(model)];
+        // every reading the same, nothing to scale by
+        assert_eq!(PSMoveCalibration::parse(model, &blob).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let error = PSMoveCalibration::parse(PSMoveModel::ZCM1, &blob).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn count_without_report_id() {
+        let device = MockDevice::default();
+        let mut parts = [part(0x00, 0xb0), part(0x81, 0xb1)];
+        for part in parts.iter_mut() {
+            part[PSMOVE_CALIBRATION_SIZE - 1] = 0;
+            device.push_feature_report_reply(&part[..PSMOVE_CALIBRATION_SIZE - 1]);
+        }
+        let blob = get_calibration_blob(&device, PSMoveModel::ZCM2).unwrap();
+        assert_eq!(blob, expected_blob(&parts));
+    }
+
+    #[test]
+    fn repeated_part_gives_up() {
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&part(0x00, 0xa0));
+        let error = get_calibration_blob(&device, PSMoveModel::ZCM2).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unexpected_part_number() {
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&part(0x82, 0xa0));
+        assert!(get_calibration_blob(&device, PSMoveModel::ZCM2).is_err());
+    }
+
+    #[test]
+    fn short_report() {
+        let device = MockDevice::default();
+        device.set_feature_report_reply(&part(0x00, 0xa0)[..20]);
+        assert!(get_calibration_blob(&device, PSMoveModel::ZCM1).is_err());
+    }
+}
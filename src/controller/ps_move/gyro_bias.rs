@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{
+    Path, PathBuf,
+};
+use std::time::Duration;
+
+use super::ImuSample;
+use crate::bluetooth::{
+    BdAddr,
+};
+
+// How long the controller has to sit still for a bias estimate
+pub const GYRO_BIAS_WINDOW: Duration = Duration::from_secs(1);
+// Standard deviations below these count as sitting still, a bit above the
+// sensors' noise on a table
+pub const GYRO_BIAS_ACCEL_STILL_STDDEV: f32 = 0.1; // m/s^2
+pub const GYRO_BIAS_GYRO_STILL_STDDEV: f32 = 0.02; // rad/s
+// What's left after the factory bias is small, anything larger is slow steady
+// turning rather than drift
+const GYRO_BIAS_MAX: f32 = 0.1; // rad/s
+// how much of each new estimate goes into the bias once there is one
+const GYRO_BIAS_SMOOTHING: f32 = 0.2;
+
+// Tracks the gyro bias left over after the factory calibration, which moves
+// with temperature. Every window the controller spends lying still gives a
+// new estimate.
+#[derive(Debug)]
+pub struct GyroBiasEstimator {
+    window: Duration,
+    samples: VecDeque<ImuSample>,
+    bias: Option<[f32; 3]>,
+    still: bool,
+}
+
+impl GyroBiasEstimator {
+    pub fn new() -> GyroBiasEstimator {
+        GyroBiasEstimator {
+            window: GYRO_BIAS_WINDOW,
+            samples: VecDeque::new(),
+            bias: None,
+            still: false,
+        }
+    }
+
+    // start from a bias found earlier, e.g. with load_gyro_bias
+    pub fn with_bias(bias: [f32; 3]) -> GyroBiasEstimator {
+        GyroBiasEstimator {
+            bias: Some(bias),
+            ..GyroBiasEstimator::new()
+        }
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+        self.samples.clear();
+    }
+
+    // rad/s, zero until the first estimate
+    pub fn bias(&self) -> [f32; 3] {
+        self.bias.unwrap_or([0.0; 3])
+    }
+
+    // No estimate yet, the controller has to be put down for a moment
+    pub fn is_calibrating(&self) -> bool {
+        self.bias.is_none()
+    }
+
+    // whether the last full window was still
+    pub fn is_still(&self) -> bool {
+        self.still
+    }
+
+    // the sample's gyro with the bias taken off
+    pub fn correct(&self, gyro: [f32; 3]) -> [f32; 3] {
+        let bias = self.bias();
+        [gyro[0] - bias[0], gyro[1] - bias[1], gyro[2] - bias[2]]
+    }
+
+    // Feed every sample, returns whether the bias changed
+    pub fn update(&mut self, sample: &ImuSample) -> bool {
+        // a gap in the timestamps starts the window over
+        if let Some(last) = self.samples.back() {
+            if sample.timestamp < last.timestamp || sample.timestamp - last.timestamp > self.window {
+                self.samples.clear();
+            }
+        }
+        self.samples.push_back(*sample);
+
+        let first = self.samples.front().map_or(sample.timestamp, |first| first.timestamp);
+        if sample.timestamp - first < self.window {
+            return false;
+        }
+
+        let (_, accel_variance) = mean_variance(&self.samples, |sample| sample.accel_m_s2);
+        let (gyro_mean, gyro_variance) = mean_variance(&self.samples, |sample| sample.gyro_rad_s);
+        self.still = (0..3).all(|axis| {
+            accel_variance[axis].sqrt() < GYRO_BIAS_ACCEL_STILL_STDDEV &&
+            gyro_variance[axis].sqrt() < GYRO_BIAS_GYRO_STILL_STDDEV &&
+            gyro_mean[axis].abs() < GYRO_BIAS_MAX
+        });
+        // each window is used once
+        self.samples.clear();
+
+        if !self.still {
+            return false;
+        }
+        self.bias = Some(match self.bias {
+            Some(bias) => [
+                bias[0] + GYRO_BIAS_SMOOTHING * (gyro_mean[0] - bias[0]),
+                bias[1] + GYRO_BIAS_SMOOTHING * (gyro_mean[1] - bias[1]),
+                bias[2] + GYRO_BIAS_SMOOTHING * (gyro_mean[2] - bias[2]),
+            ],
+            None => gyro_mean,
+        });
+        true
+    }
+}
+
+impl Default for GyroBiasEstimator {
+    fn default() -> GyroBiasEstimator {
+        GyroBiasEstimator::new()
+    }
+}
+
+// Kept next to the factory calibration, keyed by controller address, as a
+// line of three numbers
+pub fn load_gyro_bias(cache_dir: &Path, controller_addr: BdAddr) -> Option<[f32; 3]> {
+    let text = fs::read_to_string(gyro_bias_file(cache_dir, controller_addr)).ok()?;
+    let mut values = text.split_whitespace().map(|value| value.parse::<f32>());
+    let mut bias = [0f32; 3];
+    for axis in bias.iter_mut() {
+        *axis = values.next()?.ok()?;
+    }
+    Some(bias)
+}
+
+pub fn save_gyro_bias(cache_dir: &Path, controller_addr: BdAddr, bias: [f32; 3]) -> io::Result<()> {
+    fs::create_dir_all(cache_dir)?;
+    fs::write(
+        gyro_bias_file(cache_dir, controller_addr),
+        format!("{} {} {}\n", bias[0], bias[1], bias[2])
+    )
+}
+
+fn gyro_bias_file(cache_dir: &Path, controller_addr: BdAddr) -> PathBuf {
+    cache_dir.join(format!("{}.gyro", controller_addr.to_string().replace(':', "")))
+}
+
+// two passes, gravity would swamp the noise in a sum of squares
+fn mean_variance<F: Fn(&ImuSample) -> [f32; 3]>(samples: &VecDeque<ImuSample>, value: F) -> ([f32; 3], [f32; 3]) {
+    let mut mean = [0f32; 3];
+    let mut variance = [0f32; 3];
+    if samples.is_empty() {
+        return (mean, variance);
+    }
+    let count = samples.len() as f32;
+    for sample in samples {
+        let value = value(sample);
+        for axis in 0..3 {
+            mean[axis] += value[axis] / count;
+        }
+    }
+    for sample in samples {
+        let value = value(sample);
+        for axis in 0..3 {
+            variance[axis] += (value[axis] - mean[axis]).powi(2) / count;
+        }
+    }
+    (mean, variance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIAS: [f32; 3] = [0.01, -0.02, 0.005];
+    const HALF_FRAME: Duration = Duration::from_micros(5_500);
+
+    // n-th sample of a controller on a table, with a bit of noise
+    fn still_sample(n: u32, start: Duration) -> ImuSample {
+        let noise = if n.is_multiple_of(2) { 0.002 } else { -0.002 };
+        ImuSample {
+            timestamp: start + HALF_FRAME * n,
+            accel_m_s2: [noise, -noise, 9.8 + noise],
+            gyro_rad_s: [BIAS[0] + noise, BIAS[1] - noise, BIAS[2] + noise],
+            mag: None,
+        }
+    }
+
+    // feed samples from start for duration, returns how often the bias changed
+    fn feed<F: Fn(u32, Duration) -> ImuSample>(estimator: &mut GyroBiasEstimator, start: Duration, duration: Duration, sample: F) -> usize {
+        let count = (duration.as_micros() / HALF_FRAME.as_micros()) as u32;
+        (0..count).filter(|&n| estimator.update(&sample(n, start))).count()
+    }
+
+    fn assert_bias(estimator: &GyroBiasEstimator, expected: [f32; 3]) {
+        for (bias, expected) in estimator.bias().iter().zip(expected.iter()) {
+            assert!((bias - expected).abs() < 1e-4, "{:?}", estimator.bias());
+        }
+    }
+
+    #[test]
+    fn still_gives_the_bias() {
+        let mut estimator = GyroBiasEstimator::new();
+        assert!(estimator.is_calibrating());
+        assert_eq!(estimator.bias(), [0.0; 3]);
+        assert_eq!(feed(&mut estimator, Duration::from_secs(0), Duration::from_millis(1_100), still_sample), 1);
+        assert!(!estimator.is_calibrating());
+        assert!(estimator.is_still());
+        assert_bias(&estimator, BIAS);
+        let corrected = estimator.correct([BIAS[0] + 0.5, BIAS[1], BIAS[2]]);
+        assert!((corrected[0] - 0.5).abs() < 1e-4 && corrected[1].abs() < 1e-4 && corrected[2].abs() < 1e-4);
+    }
+
+    #[test]
+    fn moving_keeps_the_bias() {
+        let old_bias = [0.001, 0.002, 0.003];
+        let mut estimator = GyroBiasEstimator::with_bias(old_bias);
+        let turning = |n: u32, start: Duration| ImuSample {
+            gyro_rad_s: [(n as f32 * 0.1).sin(), 0.3, 0.0],
+            ..still_sample(n, start)
+        };
+        assert_eq!(feed(&mut estimator, Duration::from_secs(0), Duration::from_secs(3), turning), 0);
+        assert!(!estimator.is_still());
+        assert_eq!(estimator.bias(), old_bias);
+    }
+
+    #[test]
+    fn timestamp_gap_restarts_the_window() {
+        let mut estimator = GyroBiasEstimator::new();
+        assert_eq!(feed(&mut estimator, Duration::from_secs(0), Duration::from_millis(600), still_sample), 0);
+        // 600ms before and after the gap would make a window without it
+        let after_gap = Duration::from_secs(5);
+        assert_eq!(feed(&mut estimator, after_gap, Duration::from_millis(600), still_sample), 0);
+        assert!(estimator.is_calibrating());
+        // going back in time starts over too
+        assert_eq!(feed(&mut estimator, Duration::from_secs(1), Duration::from_millis(1_100), still_sample), 1);
+        assert_bias(&estimator, BIAS);
+    }
+
+    #[test]
+    fn later_estimates_are_smoothed() {
+        let mut estimator = GyroBiasEstimator::with_bias([0.0; 3]);
+        assert_eq!(feed(&mut estimator, Duration::from_secs(0), Duration::from_millis(1_100), still_sample), 1);
+        assert_bias(&estimator, [
+            BIAS[0] * GYRO_BIAS_SMOOTHING,
+            BIAS[1] * GYRO_BIAS_SMOOTHING,
+            BIAS[2] * GYRO_BIAS_SMOOTHING,
+        ]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let cache_dir = std::env::temp_dir().join(format!("rsvr_gyro_bias_{}", std::process::id()));
+        let controller_addr = BdAddr([0x00, 0x06, 0xf7, 0xc1, 0x33, 0x8d]);
+        let other_addr = BdAddr([0x00, 0x06, 0xf7, 0xc1, 0x33, 0x8e]);
+
+        assert_eq!(load_gyro_bias(&cache_dir, controller_addr), None);
+        save_gyro_bias(&cache_dir, controller_addr, BIAS).unwrap();
+        assert_eq!(load_gyro_bias(&cache_dir, controller_addr), Some(BIAS));
+        assert_eq!(load_gyro_bias(&cache_dir, other_addr), None);
+
+        fs::write(gyro_bias_file(&cache_dir, other_addr), "0.1 nope 0.3\n").unwrap();
+        assert_eq!(load_gyro_bias(&cache_dir, other_addr), None);
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
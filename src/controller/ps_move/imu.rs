@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use super::{
+    PSMoveCalibration,
+    PSMoveInputReport,
+};
+
+pub const STANDARD_GRAVITY: f32 = 9.80665;
+// The report timestamp is a 16 bit counter of these, it wraps about every 65ms
+// so it only times back to back reports, gaps are timed by the sequence number
+pub const PSMOVE_TIMESTAMP_TICK: Duration = Duration::from_micros(1);
+// until two reports in a row tell us better, reports come at about 90Hz with
+// two half-frames each
+const PSMOVE_NOMINAL_HALF_FRAME: Duration = Duration::from_micros(5_500);
+// the magnetometer is 12 bit
+const PSMOVE_MAG_RANGE: f32 = 2048.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImuSample {
+    // since the older half-frame of the first report
+    pub timestamp: Duration,
+    pub accel_m_s2: [f32; 3],
+    pub gyro_rad_s: [f32; 3],
+    // ZCM1 only, -1 to 1 of the sensor's range
+    pub mag: Option<[f32; 3]>,
+}
+
+// Turns the input reports of one controller into IMU samples. Feed it every
+// report in the order they were read.
+#[derive(Debug)]
+pub struct PSMoveImu {
+    calibration: PSMoveCalibration,
+    // timestamp and sequence number of the last report
+    last: Option<(u16, u8)>,
+    timestamp: Duration,
+    half_frame: Duration,
+    last_dropped: u8,
+    dropped: u64,
+}
+
+impl PSMoveImu {
+    pub fn new(calibration: PSMoveCalibration) -> PSMoveImu {
+        PSMoveImu {
+            calibration,
+            last: None,
+            timestamp: Duration::from_secs(0),
+            half_frame: PSMOVE_NOMINAL_HALF_FRAME,
+            last_dropped: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn calibration(&self) -> &PSMoveCalibration {
+        &self.calibration
+    }
+
+    // reports missed right before the last one given to samples
+    pub fn last_dropped(&self) -> u8 {
+        self.last_dropped
+    }
+
+    // reports missed since the start, only gaps shorter than the 16 reports
+    // the sequence number counts are seen
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // The older half-frame first. The report only timestamps the newer one,
+    // the older one was sampled halfway between it and the previous report.
+    pub fn samples(&mut self, report: &PSMoveInputReport) -> [ImuSample; 2] {
+        match self.last {
+            Some((last_timestamp, last_sequence)) => {
+                self.last_dropped = report.sequence.wrapping_sub(last_sequence.wrapping_add(1)) & 0x0f;
+                self.dropped += self.last_dropped as u64;
+                let elapsed = if self.last_dropped == 0 {
+                    let ticks = report.timestamp.wrapping_sub(last_timestamp);
+                    let elapsed = PSMOVE_TIMESTAMP_TICK * ticks as u32;
+                    // a half-frame is only known from back to back reports
+                    if ticks > 0 {
+                        self.half_frame = elapsed / 2;
+                    }
+                    elapsed
+                } else {
+                    // the timestamp may have wrapped during the gap
+                    self.half_frame * 2 * (self.last_dropped as u32 + 1)
+                };
+                self.timestamp += elapsed;
+            }
+            None => {
+                self.last_dropped = 0;
+                // so the older half-frame is at 0
+                self.timestamp = self.half_frame;
+            }
+        }
+        self.last = Some((report.timestamp, report.sequence));
+
+        let older = self.timestamp.saturating_sub(self.half_frame);
+        [
+            self.sample(report, 0, older),
+            self.sample(report, 1, self.timestamp),
+        ]
+    }
+
+    fn sample(&self, report: &PSMoveInputReport, half_frame: usize, timestamp: Duration) -> ImuSample {
+        let accel_g = self.calibration.accel_g(report.accel[half_frame]);
+        ImuSample {
+            timestamp,
+            accel_m_s2: [
+                accel_g[0] * STANDARD_GRAVITY,
+                accel_g[1] * STANDARD_GRAVITY,
+                accel_g[2] * STANDARD_GRAVITY,
+            ],
+            gyro_rad_s: self.calibration.gyro_rad_s(report.gyro[half_frame]),
+            mag: report.mag.map(|mag| [
+                mag[0] as f32 / PSMOVE_MAG_RANGE,
+                mag[1] as f32 / PSMOVE_MAG_RANGE,
+                mag[2] as f32 / PSMOVE_MAG_RANGE,
+            ]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        PSMoveBattery,
+        PSMoveModel,
+    };
+    use super::*;
+
+    fn calibration() -> PSMoveCalibration {
+        PSMoveCalibration {
+            model: PSMoveModel::ZCM2,
+            accel_min: [-4096; 3],
+            accel_max: [4096; 3],
+            gyro_bias: [0; 3],
+            gyro_gain: [0.001; 3],
+        }
+    }
+
+    fn report(sequence: u8, timestamp: u16) -> PSMoveInputReport {
+        PSMoveInputReport {
+            buttons: 0,
+            trigger: [0; 2],
+            accel: [[0, 0, 4096]; 2],
+            gyro: [[0; 3]; 2],
+            mag: None,
+            battery: PSMoveBattery::Level(5),
+            temperature: 0,
+            sequence,
+            timestamp,
+            ext_data: [0; 5],
+        }
+    }
+
+    fn timestamps(samples: [ImuSample; 2]) -> [Duration; 2] {
+        [samples[0].timestamp, samples[1].timestamp]
+    }
+
+    fn micros(micros: u64) -> Duration {
+        Duration::from_micros(micros)
+    }
+
+    #[test]
+    fn first_report_half_frames_are_apart() {
+        let mut imu = PSMoveImu::new(calibration());
+        assert_eq!(timestamps(imu.samples(&report(3, 40_000))), [micros(0), micros(5_500)]);
+    }
+
+    #[test]
+    fn back_to_back_reports_set_the_half_frame() {
+        let mut imu = PSMoveImu::new(calibration());
+        imu.samples(&report(15, 65_000));
+        // the timestamp wraps between these two
+        assert_eq!(timestamps(imu.samples(&report(0, 11_464))), [micros(11_500), micros(17_500)]);
+        assert_eq!(timestamps(imu.samples(&report(1, 21_464))), [micros(22_500), micros(27_500)]);
+        assert_eq!(imu.dropped(), 0);
+        let sample = imu.samples(&report(2, 31_464))[1];
+        assert!((sample.accel_m_s2[2] - STANDARD_GRAVITY).abs() < 1e-4);
+    }
+
+    #[test]
+    fn gap_is_timed_by_the_sequence_number() {
+        let mut imu = PSMoveImu::new(calibration());
+        imu.samples(&report(0, 0));
+        imu.samples(&report(1, 12_000));
+        // 13 reports missed, 14 * 12ms is more than the timestamp counts
+        let samples = imu.samples(&report(15, 37_000));
+        assert_eq!(imu.last_dropped(), 13);
+        assert_eq!(imu.dropped(), 13);
+        assert_eq!(timestamps(samples), [micros(179_500), micros(185_500)]);
+        // and back to the timestamp once reports come in order again
+        assert_eq!(timestamps(imu.samples(&report(0, 49_000))), [micros(191_500), micros(197_500)]);
+    }
+}
@@ -0,0 +1,245 @@
+use std::io;
+
+use super::{
+    PSMoveModel,
+    PSMoveRequestType,
+};
+
+// Input report layout, PSMove_Data_Input in psmoveapi
+// https://github.com/thp/psmoveapi/blob/4.0.12/src/psmove.c
+pub const PSMOVE_INPUT_REPORT_SIZE: usize = 49;
+const PSMOVE_EXT_DATA_SIZE: usize = 5;
+
+// The bits of PSMoveInputReport::buttons
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PSMoveButton {
+    Triangle = 1 << 4,
+    Circle = 1 << 5,
+    Cross = 1 << 6,
+    Square = 1 << 7,
+    Select = 1 << 8,
+    Start = 1 << 11,
+    PS = 1 << 16,
+    Move = 1 << 19,
+    T = 1 << 20,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PSMoveBattery {
+    // 0 (empty) to 5 (full) on battery
+    Level(u8),
+    // on USB
+    Charging,
+    Charged,
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PSMoveInputReport {
+    pub buttons: u32,
+    // 0 released to 255 fully pressed, the report carries two half-frames
+    // for the trigger and the IMU, the older one first
+    pub trigger: [u8; 2],
+    // raw counts centered on 0, see calibration for units
+    pub accel: [[i16; 3]; 2],
+    pub gyro: [[i16; 3]; 2],
+    // ZCM1 only, 12 bit signed counts
+    pub mag: Option<[i16; 3]>,
+    pub battery: PSMoveBattery,
+    // raw 12 bit sensor reading
+    pub temperature: u16,
+    // 4 bit counter, goes up by one each report so gaps are dropped reports
+    pub sequence: u8,
+    // 16 bit tick counter of when the second half-frame was sampled
+    pub timestamp: u16,
+    // from whatever is plugged into the EXT port
+    pub ext_data: [u8; PSMOVE_EXT_DATA_SIZE],
+}
+
+impl PSMoveInputReport {
+    // data is the report as read from the device, report id first
+    pub fn parse(model: PSMoveModel, data: &[u8]) -> io::Result<PSMoveInputReport> {
+        if data.len() < PSMOVE_INPUT_REPORT_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("PS Move input report is {} bytes, expected {}", data.len(), PSMOVE_INPUT_REPORT_SIZE)
+            ));
+        }
+        if data[0] != PSMoveRequestType::GetInput as u8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not a PS Move input report, report id {:#04x}", data[0])
+            ));
+        }
+
+        let mut ext_data = [0u8; PSMOVE_EXT_DATA_SIZE];
+        ext_data.copy_from_slice(&data[44..44 + PSMOVE_EXT_DATA_SIZE]);
+
+        Ok(PSMoveInputReport {
+            buttons: decode_buttons(data),
+            trigger: [data[5], data[6]],
+            accel: [
+                decode_vector(model, &data[13..19]),
+                decode_vector(model, &data[19..25]),
+            ],
+            gyro: [
+                decode_vector(model, &data[25..31]),
+                decode_vector(model, &data[31..37]),
+            ],
+            mag: match model {
+                PSMoveModel::ZCM1 => Some(decode_magnetometer(data)),
+                PSMoveModel::ZCM2 => None,
+            },
+            battery: match data[12] {
+                level @ 0x00..=0x05 => PSMoveBattery::Level(level),
+                0xee => PSMoveBattery::Charging,
+                0xef => PSMoveBattery::Charged,
+                value => PSMoveBattery::Unknown(value),
+            },
+            temperature: ((data[37] as u16) << 4) | ((data[38] as u16 & 0xf0) >> 4),
+            sequence: data[4] & 0x0f,
+            timestamp: ((data[11] as u16) << 8) | data[43] as u16,
+            ext_data,
+        })
+    }
+
+    pub fn is_pressed(&self, button: PSMoveButton) -> bool {
+        self.buttons & button as u32 != 0
+    }
+}
+
+// Spread over four bytes, the low nibble of the last one is the sequence
+// number. Put together the way psmoveapi does so the bits match its enum.
+fn decode_buttons(data: &[u8]) -> u32 {
+    (data[2] as u32) |
+    ((data[1] as u32) << 8) |
+    ((data[3] as u32 & 0x01) << 16) |
+    ((data[4] as u32 & 0xf0) << 13)
+}
+
+// x, y, z little endian. ZCM1 sends them offset by 0x8000, ZCM2 as plain
+// two's complement. The calibration stores its readings the same way.
+pub(super) fn decode_vector(model: PSMoveModel, data: &[u8]) -> [i16; 3] {
+    let mut vector = [0i16; 3];
+    for (axis, value) in vector.iter_mut().enumerate() {
+        let raw = u16::from_le_bytes([data[axis * 2], data[axis * 2 + 1]]);
+        *value = match model {
+            PSMoveModel::ZCM1 => (raw as i32 - 0x8000) as i16,
+            PSMoveModel::ZCM2 => raw as i16,
+        };
+    }
+    vector
+}
+
+// Three 12 bit values packed around the temperature
+fn decode_magnetometer(data: &[u8]) -> [i16; 3] {
+    let x = ((data[38] as u16 & 0x0f) << 8) | data[39] as u16;
+    let y = ((data[40] as u16) << 4) | ((data[41] as u16 & 0xf0) >> 4);
+    let z = ((data[41] as u16 & 0x0f) << 8) | data[42] as u16;
+    [twelve_bit_signed(x), twelve_bit_signed(y), twelve_bit_signed(z)]
+}
+
+fn twelve_bit_signed(value: u16) -> i16 {
+    ((value << 4) as i16) >> 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fixtures are written by hand from the layout above, not captured
+    // from a controller. Swap in real dumps of these poses when there are
+    // some.
+
+    // Lying on its back with Cross, Move and T held and the trigger all the
+    // way in, battery full. Something on the EXT port sends 5 bytes.
+    const ZCM1_REPORT: [u8; PSMOVE_INPUT_REPORT_SIZE] = [
+        0x01, 0x00, 0x40, 0x00, 0xc7, 0xfe, 0xff, 0x00,
+        0x00, 0x00, 0x00, 0x3a, 0x05, 0xf4, 0x7f, 0x22,
+        0x80, 0x1b, 0x90, 0xf6, 0x7f, 0x1e, 0x80, 0x18,
+        0x90, 0x03, 0x80, 0xfb, 0x7f, 0x02, 0x80, 0x04,
+        0x80, 0xfa, 0x7f, 0x01, 0x80, 0x5a, 0x3f, 0x24,
+        0x09, 0x6c, 0x18, 0x5c, 0x11, 0x22, 0x33, 0x44,
+        0x55,
+    ];
+
+    // Standing on its end with Start, Triangle and PS held, charging on USB
+    const ZCM2_REPORT: [u8; PSMOVE_INPUT_REPORT_SIZE] = [
+        0x01, 0x08, 0x10, 0x01, 0x0f, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xff, 0xee, 0x78, 0x00, 0x06,
+        0xf0, 0x23, 0x00, 0x76, 0x00, 0x03, 0xf0, 0x21,
+        0x00, 0xfe, 0xff, 0x07, 0x00, 0x00, 0x00, 0xff,
+        0xff, 0x06, 0x00, 0x01, 0x00, 0x61, 0x20, 0x00,
+        0x00, 0x00, 0x00, 0xf0, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    const ALL_BUTTONS: [PSMoveButton; 9] = [
+        PSMoveButton::Triangle,
+        PSMoveButton::Circle,
+        PSMoveButton::Cross,
+        PSMoveButton::Square,
+        PSMoveButton::Select,
+        PSMoveButton::Start,
+        PSMoveButton::PS,
+        PSMoveButton::Move,
+        PSMoveButton::T,
+    ];
+
+    fn pressed(report: &PSMoveInputReport) -> Vec<PSMoveButton> {
+        ALL_BUTTONS.iter().cloned().filter(|&button| report.is_pressed(button)).collect()
+    }
+
+    #[test]
+    fn zcm1_report() {
+        let report = PSMoveInputReport::parse(PSMoveModel::ZCM1, &ZCM1_REPORT).unwrap();
+        assert_eq!(pressed(&report), vec![PSMoveButton::Cross, PSMoveButton::Move, PSMoveButton::T]);
+        assert_eq!(report.trigger, [0xfe, 0xff]);
+        assert_eq!(report.accel, [[-12, 34, 4123], [-10, 30, 4120]]);
+        assert_eq!(report.gyro, [[3, -5, 2], [4, -6, 1]]);
+        assert_eq!(report.mag, Some([-220, 150, -1000]));
+        assert_eq!(report.battery, PSMoveBattery::Level(5));
+        assert_eq!(report.temperature, 0x5a3);
+        assert_eq!(report.sequence, 7);
+        assert_eq!(report.timestamp, 0x3a5c);
+        assert_eq!(report.ext_data, [0x11, 0x22, 0x33, 0x44, 0x55]);
+    }
+
+    #[test]
+    fn zcm2_report() {
+        let report = PSMoveInputReport::parse(PSMoveModel::ZCM2, &ZCM2_REPORT).unwrap();
+        assert_eq!(pressed(&report), vec![PSMoveButton::Triangle, PSMoveButton::Start, PSMoveButton::PS]);
+        assert_eq!(report.trigger, [0, 0]);
+        assert_eq!(report.accel, [[120, -4090, 35], [118, -4093, 33]]);
+        assert_eq!(report.gyro, [[-2, 7, 0], [-1, 6, 1]]);
+        assert_eq!(report.mag, None);
+        assert_eq!(report.battery, PSMoveBattery::Charging);
+        assert_eq!(report.temperature, 0x612);
+        assert_eq!(report.sequence, 15);
+        assert_eq!(report.timestamp, 0xfff0);
+        assert_eq!(report.ext_data, [0; PSMOVE_EXT_DATA_SIZE]);
+    }
+
+    #[test]
+    fn battery_states() {
+        let mut data = ZCM2_REPORT;
+        for &(value, battery) in [
+            (0x00, PSMoveBattery::Level(0)),
+            (0xef, PSMoveBattery::Charged),
+            (0x42, PSMoveBattery::Unknown(0x42)),
+        ].iter() {
+            data[12] = value;
+            assert_eq!(PSMoveInputReport::parse(PSMoveModel::ZCM2, &data).unwrap().battery, battery);
+        }
+    }
+
+    #[test]
+    fn rejects_short_and_foreign_reports() {
+        let error = PSMoveInputReport::parse(PSMoveModel::ZCM1, &ZCM1_REPORT[..40]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut data = ZCM1_REPORT;
+        data[0] = PSMoveRequestType::GetCalibration as u8;
+        assert!(PSMoveInputReport::parse(PSMoveModel::ZCM1, &data).is_err());
+    }
+}
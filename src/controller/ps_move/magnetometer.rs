@@ -0,0 +1,388 @@
+use std::fs;
+use std::io;
+use std::path::{
+    Path, PathBuf,
+};
+
+use crate::bluetooth::{
+    BdAddr,
+};
+
+// Readings closer than this to one already collected add nothing to the fit,
+// in the -1 to 1 units of ImuSample::mag
+const MAG_MIN_SAMPLE_DISTANCE: f32 = 0.02;
+// too few and the fit follows the noise
+pub const MAG_MIN_SAMPLES: usize = 50;
+
+// Maps magnetometer readings onto a unit sphere. The controller's own iron
+// and currents shift the sphere the earth's field should trace (hard iron)
+// and squash it into an ellipsoid (soft iron).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagCalibration {
+    pub offset: [f32; 3],
+    pub soft_iron: [[f32; 3]; 3],
+    // RMS distance of the calibrated samples from the unit sphere, under 0.05
+    // is a good fit
+    pub fit_error: f32,
+}
+
+impl MagCalibration {
+    pub fn apply(&self, mag: [f32; 3]) -> [f32; 3] {
+        let centered = [mag[0] - self.offset[0], mag[1] - self.offset[1], mag[2] - self.offset[2]];
+        let mut calibrated = [0f32; 3];
+        for (row, value) in self.soft_iron.iter().zip(calibrated.iter_mut()) {
+            *value = row[0] * centered[0] + row[1] * centered[1] + row[2] * centered[2];
+        }
+        calibrated
+    }
+}
+
+// Collects readings while the user turns the controller through every
+// orientation, then fits an ellipsoid to them
+#[derive(Debug, Default)]
+pub struct MagCalibrator {
+    samples: Vec<[f32; 3]>,
+}
+
+impl MagCalibrator {
+    pub fn new() -> MagCalibrator {
+        MagCalibrator::default()
+    }
+
+    // returns whether the reading was kept
+    pub fn add(&mut self, mag: [f32; 3]) -> bool {
+        let near = self.samples.iter().any(|sample| {
+            let d = [sample[0] - mag[0], sample[1] - mag[1], sample[2] - mag[2]];
+            (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt() < MAG_MIN_SAMPLE_DISTANCE
+        });
+        if near {
+            return false;
+        }
+        self.samples.push(mag);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    // Least squares fit of a x^2 + b y^2 + c z^2 + 2d xy + 2e xz + 2f yz +
+    // 2g x + 2h y + 2i z = 1 to the samples.
+    // https://www.st.com/resource/en/design_tip/dt0059-ellipsoid-or-sphere-fitting-for-sensor-calibration-stmicroelectronics.pdf
+    pub fn fit(&self) -> io::Result<MagCalibration> {
+        if self.samples.len() < MAG_MIN_SAMPLES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} magnetometer samples, need at least {}", self.samples.len(), MAG_MIN_SAMPLES)
+            ));
+        }
+        let fit_failed = || io::Error::new(
+            io::ErrorKind::InvalidData,
+            "magnetometer samples don't fit an ellipsoid, turn the controller through more orientations"
+        );
+
+        // normal equations
+        let mut ata = [[0f64; 9]; 9];
+        let mut atb = [0f64; 9];
+        for sample in self.samples.iter() {
+            let [x, y, z] = [sample[0] as f64, sample[1] as f64, sample[2] as f64];
+            let row = [x * x, y * y, z * z, 2.0 * x * y, 2.0 * x * z, 2.0 * y * z, 2.0 * x, 2.0 * y, 2.0 * z];
+            for i in 0..9 {
+                for j in 0..9 {
+                    ata[i][j] += row[i] * row[j];
+                }
+                atb[i] += row[i];
+            }
+        }
+        let p = solve(ata, atb).ok_or_else(fit_failed)?;
+
+        let m = [
+            [p[0], p[3], p[4]],
+            [p[3], p[1], p[5]],
+            [p[4], p[5], p[2]],
+        ];
+        let g = [p[6], p[7], p[8]];
+        // the center is where the gradient of the quadric vanishes
+        let m_inverse = invert3(m).ok_or_else(fit_failed)?;
+        let mut center = [0f64; 3];
+        for i in 0..3 {
+            center[i] = -(m_inverse[i][0] * g[0] + m_inverse[i][1] * g[1] + m_inverse[i][2] * g[2]);
+        }
+        // (x - center)' M (x - center) = 1 + center' M center
+        let mut scale = 1.0;
+        for i in 0..3 {
+            for j in 0..3 {
+                scale += center[i] * m[i][j] * center[j];
+            }
+        }
+        if scale <= 0.0 {
+            return Err(fit_failed());
+        }
+
+        // the soft iron matrix is the square root of M / scale
+        let (values, vectors) = eigen3(m);
+        if values.iter().any(|&value| value / scale <= 0.0) {
+            return Err(fit_failed());
+        }
+        let mut soft_iron = [[0f32; 3]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                let mut value = 0.0;
+                for k in 0..3 {
+                    value += vectors[i][k] * (values[k] / scale).sqrt() * vectors[j][k];
+                }
+                soft_iron[i][j] = value as f32;
+            }
+        }
+
+        let mut calibration = MagCalibration {
+            offset: [center[0] as f32, center[1] as f32, center[2] as f32],
+            soft_iron,
+            fit_error: 0.0,
+        };
+        let squared_error: f32 = self.samples.iter()
+            .map(|&sample| {
+                let v = calibration.apply(sample);
+                ((v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt() - 1.0).powi(2)
+            })
+            .sum();
+        calibration.fit_error = (squared_error / self.samples.len() as f32).sqrt();
+        Ok(calibration)
+    }
+}
+
+// Kept next to the factory calibration, keyed by controller address: offset,
+// soft iron rows and fit error on one line
+pub fn load_mag_calibration(cache_dir: &Path, controller_addr: BdAddr) -> Option<MagCalibration> {
+    let text = fs::read_to_string(mag_calibration_file(cache_dir, controller_addr)).ok()?;
+    let values = text.split_whitespace()
+        .map(|value| value.parse::<f32>().ok())
+        .collect::<Option<Vec<f32>>>()?;
+    if values.len() != 13 {
+        return None;
+    }
+    Some(MagCalibration {
+        offset: [values[0], values[1], values[2]],
+        soft_iron: [
+            [values[3], values[4], values[5]],
+            [values[6], values[7], values[8]],
+            [values[9], values[10], values[11]],
+        ],
+        fit_error: values[12],
+    })
+}
+
+pub fn save_mag_calibration(cache_dir: &Path, controller_addr: BdAddr, calibration: &MagCalibration) -> io::Result<()> {
+    let mut values = calibration.offset.to_vec();
+    for row in calibration.soft_iron.iter() {
+        values.extend_from_slice(row);
+    }
+    values.push(calibration.fit_error);
+    let line: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+
+    fs::create_dir_all(cache_dir)?;
+    fs::write(mag_calibration_file(cache_dir, controller_addr), line.join(" ") + "\n")
+}
+
+fn mag_calibration_file(cache_dir: &Path, controller_addr: BdAddr) -> PathBuf {
+    cache_dir.join(format!("{}.mag", controller_addr.to_string().replace(':', "")))
+}
+
+// Gaussian elimination with partial pivoting, None if singular
+fn solve(mut a: [[f64; 9]; 9], mut b: [f64; 9]) -> Option<[f64; 9]> {
+    for column in 0..9 {
+        let pivot = (column..9).max_by(|&i, &j| a[i][column].abs().partial_cmp(&a[j][column].abs()).unwrap())?;
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        for row in column + 1..9 {
+            let factor = a[row][column] / a[column][column];
+            let (above, below) = a.split_at_mut(row);
+            for (value, pivot_value) in below[0][column..].iter_mut().zip(above[column][column..].iter()) {
+                *value -= factor * pivot_value;
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+    let mut x = [0f64; 9];
+    for row in (0..9).rev() {
+        let known: f64 = a[row][row + 1..].iter().zip(x[row + 1..].iter()).map(|(a, x)| a * x).sum();
+        x[row] = (b[row] - known) / a[row][row];
+    }
+    Some(x)
+}
+
+fn invert3(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let determinant = m[0][0] * cofactor(1, 2, 1, 2) - m[0][1] * cofactor(1, 2, 0, 2) + m[0][2] * cofactor(1, 2, 0, 1);
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    let adjugate = [
+        [cofactor(1, 2, 1, 2), -cofactor(0, 2, 1, 2), cofactor(0, 1, 1, 2)],
+        [-cofactor(1, 2, 0, 2), cofactor(0, 2, 0, 2), -cofactor(0, 1, 0, 2)],
+        [cofactor(1, 2, 0, 1), -cofactor(0, 2, 0, 1), cofactor(0, 1, 0, 1)],
+    ];
+    let mut inverse = [[0f64; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            inverse[i][j] = adjugate[i][j] / determinant;
+        }
+    }
+    Some(inverse)
+}
+
+// Jacobi eigenvalue iteration for a symmetric matrix, eigenvectors are the
+// columns of the second value
+fn eigen3(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..50 {
+        let off_diagonal = a[0][1].abs() + a[0][2].abs() + a[1][2].abs();
+        if off_diagonal < 1e-15 {
+            break;
+        }
+        for &(p, q) in [(0, 1), (0, 2), (1, 2)].iter() {
+            if a[p][q].abs() < 1e-30 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let c = 1.0 / (t * t + 1.0).sqrt();
+            let s = t * c;
+            // a = J' a J with the rotation J in the p, q plane
+            for row in a.iter_mut() {
+                let akp = row[p];
+                let akq = row[q];
+                row[p] = c * akp - s * akq;
+                row[q] = s * akp + c * akq;
+            }
+            let (above, below) = a.split_at_mut(q);
+            for (apk, aqk) in above[p].iter_mut().zip(below[0].iter_mut()) {
+                let (old_apk, old_aqk) = (*apk, *aqk);
+                *apk = c * old_apk - s * old_aqk;
+                *aqk = s * old_apk + c * old_aqk;
+            }
+            for row in v.iter_mut() {
+                let vkp = row[p];
+                let vkq = row[q];
+                row[p] = c * vkp - s * vkq;
+                row[q] = s * vkp + c * vkq;
+            }
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSET: [f32; 3] = [0.12, -0.3, 0.05];
+    // symmetric like the fit gives it
+    const SOFT_IRON: [[f32; 3]; 3] = [
+        [2.2, 0.3, -0.1],
+        [0.3, 1.6, 0.2],
+        [-0.1, 0.2, 2.8],
+    ];
+
+    // Readings a controller with OFFSET and SOFT_IRON would give turning
+    // through every orientation, the unit vectors spread evenly by a
+    // Fibonacci spiral
+    fn distorted_sphere(count: usize) -> Vec<[f32; 3]> {
+        let mut soft_iron = [[0f64; 3]; 3];
+        for (row, soft_iron_row) in soft_iron.iter_mut().zip(SOFT_IRON.iter()) {
+            for (value, &soft_iron_value) in row.iter_mut().zip(soft_iron_row.iter()) {
+                *value = soft_iron_value as f64;
+            }
+        }
+        let distortion = invert3(soft_iron).unwrap();
+        let golden_angle = std::f64::consts::PI * (3.0 - 5f64.sqrt());
+        (0..count).map(|i| {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / count as f64;
+            let r = (1.0 - z * z).sqrt();
+            let (sin, cos) = (golden_angle * i as f64).sin_cos();
+            let u = [r * cos, r * sin, z];
+            let mut reading = [0f32; 3];
+            for (axis, value) in reading.iter_mut().enumerate() {
+                let row = distortion[axis];
+                *value = OFFSET[axis] + (row[0] * u[0] + row[1] * u[1] + row[2] * u[2]) as f32;
+            }
+            reading
+        }).collect()
+    }
+
+    #[test]
+    fn fit_recovers_the_distortion() {
+        let mut calibrator = MagCalibrator::new();
+        for reading in distorted_sphere(200) {
+            assert!(calibrator.add(reading));
+        }
+        let calibration = calibrator.fit().unwrap();
+        for (offset, expected) in calibration.offset.iter().zip(OFFSET.iter()) {
+            assert!((offset - expected).abs() < 1e-3, "{:?}", calibration.offset);
+        }
+        for (row, expected_row) in calibration.soft_iron.iter().zip(SOFT_IRON.iter()) {
+            for (value, expected) in row.iter().zip(expected_row.iter()) {
+                assert!((value - expected).abs() < 1e-3, "{:?}", calibration.soft_iron);
+            }
+        }
+        assert!(calibration.fit_error < 1e-3);
+
+        let on_sphere = calibration.apply(distorted_sphere(7)[3]);
+        let length = (on_sphere[0] * on_sphere[0] + on_sphere[1] * on_sphere[1] + on_sphere[2] * on_sphere[2]).sqrt();
+        assert!((length - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fit_error_grows_with_noise() {
+        let mut calibrator = MagCalibrator::new();
+        for (i, reading) in distorted_sphere(200).into_iter().enumerate() {
+            let noise = if i.is_multiple_of(2) { 0.02 } else { -0.02 };
+            calibrator.add([reading[0] + noise, reading[1] - noise, reading[2] + noise]);
+        }
+        let fit_error = calibrator.fit().unwrap().fit_error;
+        assert!(fit_error > 0.01 && fit_error < 0.2, "{}", fit_error);
+    }
+
+    #[test]
+    fn too_few_or_too_close_samples() {
+        let mut calibrator = MagCalibrator::new();
+        assert!(calibrator.add([0.5, 0.0, 0.0]));
+        assert!(!calibrator.add([0.51, 0.0, 0.0]));
+        assert_eq!(calibrator.len(), 1);
+        assert_eq!(calibrator.fit().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // a flat circle says nothing about z
+        calibrator.clear();
+        for i in 0..100 {
+            let (sin, cos) = (i as f32 * 0.0628).sin_cos();
+            calibrator.add([cos * 0.5, sin * 0.5, 0.0]);
+        }
+        assert_eq!(calibrator.fit().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let cache_dir = std::env::temp_dir().join(format!("rsvr_mag_calibration_{}", std::process::id()));
+        let controller_addr = BdAddr([0x00, 0x06, 0xf7, 0xc1, 0x33, 0x8d]);
+        let calibration = MagCalibration {
+            offset: OFFSET,
+            soft_iron: SOFT_IRON,
+            fit_error: 0.01,
+        };
+        assert_eq!(load_mag_calibration(&cache_dir, controller_addr), None);
+        save_mag_calibration(&cache_dir, controller_addr, &calibration).unwrap();
+        assert_eq!(load_mag_calibration(&cache_dir, controller_addr), Some(calibration));
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
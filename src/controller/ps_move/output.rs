@@ -0,0 +1,180 @@
+use hid_rs::usb::backend::{
+    HidDeviceIo,
+};
+
+use std::io;
+use std::time::{
+    Duration, Instant,
+};
+
+use super::PSMoveRequestType;
+
+// PSMove_Data_LEDs in psmoveapi, the rest of the report must be zero
+// https://github.com/thp/psmoveapi/blob/4.0.12/src/psmove.c
+pub const PSMOVE_OUTPUT_REPORT_SIZE: usize = 49;
+// the sphere goes dark if it doesn't hear from us for about this long
+pub const PSMOVE_LED_TIMEOUT: Duration = Duration::from_secs(4);
+// early enough that a late update or a retransmit doesn't let it time out
+pub const PSMOVE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(3000);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PSMoveOutput {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    // 0 off to 255 full speed
+    pub rumble: u8,
+}
+
+impl PSMoveOutput {
+    pub fn new(red: u8, green: u8, blue: u8, rumble: u8) -> PSMoveOutput {
+        PSMoveOutput {
+            red,
+            green,
+            blue,
+            rumble,
+        }
+    }
+
+    pub fn to_report(self) -> [u8; PSMOVE_OUTPUT_REPORT_SIZE] {
+        let mut report = [0u8; PSMOVE_OUTPUT_REPORT_SIZE];
+        report[0] = PSMoveRequestType::SetLEDs as u8;
+        report[2] = self.red;
+        report[3] = self.green;
+        report[4] = self.blue;
+        report[6] = self.rumble;
+        report
+    }
+}
+
+// Holds the output state the controller should be showing and sends it when
+// it changes or when the controller would otherwise time the LED out. Call
+// update from the loop that reads the controller, or sleep until next_update.
+#[derive(Debug)]
+pub struct PSMoveOutputScheduler {
+    output: PSMoveOutput,
+    keep_alive_interval: Duration,
+    last_sent: Option<(PSMoveOutput, Instant)>,
+}
+
+impl PSMoveOutputScheduler {
+    pub fn new() -> PSMoveOutputScheduler {
+        PSMoveOutputScheduler::with_interval(PSMOVE_KEEP_ALIVE_INTERVAL)
+    }
+
+    pub fn with_interval(keep_alive_interval: Duration) -> PSMoveOutputScheduler {
+        PSMoveOutputScheduler {
+            output: PSMoveOutput::default(),
+            keep_alive_interval,
+            last_sent: None,
+        }
+    }
+
+    pub fn output(&self) -> PSMoveOutput {
+        self.output
+    }
+
+    // goes out on the next update
+    pub fn set_output(&mut self, output: PSMoveOutput) {
+        self.output = output;
+    }
+
+    pub fn set_color(&mut self, red: u8, green: u8, blue: u8) {
+        self.output.red = red;
+        self.output.green = green;
+        self.output.blue = blue;
+    }
+
+    pub fn set_rumble(&mut self, rumble: u8) {
+        self.output.rumble = rumble;
+    }
+
+    // When the state has to go out next, now if it changed since it was sent
+    pub fn next_update(&self) -> Instant {
+        match self.last_sent {
+            Some((sent, at)) if sent == self.output => at + self.keep_alive_interval,
+            _ => Instant::now(),
+        }
+    }
+
+    // Send the output report if it is due, returns whether one was sent
+    pub fn update<D: HidDeviceIo>(&mut self, device: &D) -> io::Result<bool> {
+        self.update_at(device, Instant::now())
+    }
+
+    pub fn update_at<D: HidDeviceIo>(&mut self, device: &D, now: Instant) -> io::Result<bool> {
+        let due = match self.last_sent {
+            Some((sent, at)) => sent != self.output || now.saturating_duration_since(at) >= self.keep_alive_interval,
+            None => true,
+        };
+        if !due {
+            return Ok(false);
+        }
+        device.write_output_report(&self.output.to_report())?;
+        self.last_sent = Some((self.output, now));
+        Ok(true)
+    }
+}
+
+impl Default for PSMoveOutputScheduler {
+    fn default() -> PSMoveOutputScheduler {
+        PSMoveOutputScheduler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hid_rs::usb::backend::mock::{
+        MockDevice,
+    };
+
+    use super::*;
+
+    #[test]
+    fn leds_and_rumble_report() {
+        let report = PSMoveOutput::new(0x12, 0x34, 0x56, 0x78).to_report();
+        assert_eq!(report.len(), PSMOVE_OUTPUT_REPORT_SIZE);
+        assert_eq!(report[..7], [0x06, 0x00, 0x12, 0x34, 0x56, 0x00, 0x78]);
+        assert!(report[7..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn first_update_sends() {
+        let device = MockDevice::default();
+        let mut scheduler = PSMoveOutputScheduler::new();
+        scheduler.set_color(0, 0, 255);
+        let now = Instant::now();
+        assert!(scheduler.next_update() <= Instant::now());
+        assert!(scheduler.update_at(&device, now).unwrap());
+        assert_eq!(device.output_writes(), vec![PSMoveOutput::new(0, 0, 255, 0).to_report().to_vec()]);
+        assert_eq!(scheduler.next_update(), now + PSMOVE_KEEP_ALIVE_INTERVAL);
+    }
+
+    #[test]
+    fn resends_on_change_or_keep_alive() {
+        let device = MockDevice::default();
+        let mut scheduler = PSMoveOutputScheduler::with_interval(Duration::from_secs(3));
+        let start = Instant::now();
+        assert!(scheduler.update_at(&device, start).unwrap());
+
+        // nothing new and not due yet
+        assert!(!scheduler.update_at(&device, start + Duration::from_secs(1)).unwrap());
+        // setting the same state again is no change
+        scheduler.set_output(PSMoveOutput::default());
+        assert!(!scheduler.update_at(&device, start + Duration::from_secs(2)).unwrap());
+        assert_eq!(device.output_writes().len(), 1);
+
+        // a change goes out right away and restarts the interval
+        scheduler.set_rumble(200);
+        assert!(scheduler.update_at(&device, start + Duration::from_millis(2_500)).unwrap());
+        assert_eq!(device.output_writes()[1][6], 200);
+        assert!(!scheduler.update_at(&device, start + Duration::from_secs(5)).unwrap());
+
+        // the keep-alive repeats the same report
+        assert!(scheduler.update_at(&device, start + Duration::from_millis(5_500)).unwrap());
+        let writes = device.output_writes();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[2], writes[1]);
+        assert_eq!(scheduler.output(), PSMoveOutput::new(0, 0, 0, 200));
+    }
+}
@@ -0,0 +1,254 @@
+// Orientation from IMU samples (AHRS). Vectors are x, y, z in the sensor's
+// frame, gyro in rad/s, accelerometer and magnetometer in any unit since only
+// their direction is used. The earth frame has z pointing up, and an
+// orientation rotates vectors from the sensor frame into the earth frame.
+
+pub mod complementary;
+pub mod madgwick;
+
+pub use complementary::Complementary;
+pub use madgwick::Madgwick;
+
+use std::ops::Mul;
+
+pub trait Ahrs {
+    // dt is the time since the previous sample in seconds
+    fn update(&mut self, gyro: [f32; 3], accel: [f32; 3], mag: Option<[f32; 3]>, dt: f32);
+    fn orientation(&self) -> Quaternion;
+    // back to identity, e.g. when the device was re-centered
+    fn reset(&mut self);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Quaternion {
+    pub const IDENTITY: Quaternion = Quaternion {
+        w: 1.0,
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    pub fn new(w: f32, x: f32, y: f32, z: f32) -> Quaternion {
+        Quaternion {
+            w,
+            x,
+            y,
+            z,
+        }
+    }
+
+    // axis doesn't need to be normalized, angle in radians
+    pub fn from_axis_angle(axis: [f32; 3], angle: f32) -> Quaternion {
+        let [x, y, z] = normalize(axis);
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        Quaternion::new(cos, x * sin, y * sin, z * sin)
+    }
+
+    pub fn conjugate(self) -> Quaternion {
+        Quaternion::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    pub fn norm(self) -> f32 {
+        (self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    pub fn normalize(self) -> Quaternion {
+        let norm = self.norm();
+        if norm == 0.0 {
+            return Quaternion::IDENTITY;
+        }
+        Quaternion::new(self.w / norm, self.x / norm, self.y / norm, self.z / norm)
+    }
+
+    pub fn rotate(self, v: [f32; 3]) -> [f32; 3] {
+        let rotated = self * Quaternion::new(0.0, v[0], v[1], v[2]) * self.conjugate();
+        [rotated.x, rotated.y, rotated.z]
+    }
+
+    // smallest angle in radians that takes one orientation to the other
+    pub fn angle_to(self, other: Quaternion) -> f32 {
+        let dot = self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z;
+        2.0 * dot.abs().min(1.0).acos()
+    }
+
+    // step along the rate of change from rotating at gyro rad/s for dt
+    pub(crate) fn integrate(self, gyro: [f32; 3], dt: f32) -> Quaternion {
+        let derivative = self.derivative(gyro);
+        Quaternion::new(
+            self.w + derivative.w * dt,
+            self.x + derivative.x * dt,
+            self.y + derivative.y * dt,
+            self.z + derivative.z * dt,
+        ).normalize()
+    }
+
+    pub(crate) fn derivative(self, gyro: [f32; 3]) -> Quaternion {
+        let rate = self * Quaternion::new(0.0, gyro[0], gyro[1], gyro[2]);
+        Quaternion::new(rate.w * 0.5, rate.x * 0.5, rate.y * 0.5, rate.z * 0.5)
+    }
+}
+
+impl Default for Quaternion {
+    fn default() -> Quaternion {
+        Quaternion::IDENTITY
+    }
+}
+
+impl Mul for Quaternion {
+    type Output = Quaternion;
+
+    fn mul(self, other: Quaternion) -> Quaternion {
+        Quaternion::new(
+            self.w * other.w - self.x * other.x - self.y * other.y - self.z * other.z,
+            self.w * other.x + self.x * other.w + self.y * other.z - self.z * other.y,
+            self.w * other.y - self.x * other.z + self.y * other.w + self.z * other.x,
+            self.w * other.z + self.x * other.y - self.y * other.x + self.z * other.w,
+        )
+    }
+}
+
+// zero stays zero, callers treat that as no reading
+pub(crate) fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let norm = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if norm == 0.0 {
+        return v;
+    }
+    [v[0] / norm, v[1] / norm, v[2] / norm]
+}
+
+pub(crate) fn is_zero(v: [f32; 3]) -> bool {
+    v[0] == 0.0 && v[1] == 0.0 && v[2] == 0.0
+}
+
+pub(crate) fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    // pointing north, down like it is in Europe
+    pub(super) const EARTH_FIELD: [f32; 3] = [0.4, 0.0, -0.9];
+    pub(super) const DT: f32 = 0.01;
+
+    pub(super) fn tilted() -> Quaternion {
+        Quaternion::from_axis_angle([1.0, 0.0, 0.0], 0.5)
+    }
+
+    pub(super) fn tilted_and_turned() -> Quaternion {
+        Quaternion::from_axis_angle([0.0, 0.0, 1.0], 1.0) * tilted()
+    }
+
+    // what the sensors read lying still in orientation
+    pub(super) fn still<A: Ahrs>(filter: &mut A, orientation: Quaternion, with_mag: bool, seconds: f32) {
+        let to_sensor = orientation.conjugate();
+        let accel = to_sensor.rotate([0.0, 0.0, 9.8]);
+        let mag = if with_mag { Some(to_sensor.rotate(EARTH_FIELD)) } else { None };
+        for _ in 0..(seconds / DT) as usize {
+            filter.update([0.0; 3], accel, mag, DT);
+        }
+    }
+
+    // Angle between where the filter and orientation put up in the sensor
+    // frame. Without a magnetometer the heading is anyone's guess.
+    pub(super) fn tilt_error<A: Ahrs>(filter: &A, orientation: Quaternion) -> f32 {
+        let up = filter.orientation().conjugate().rotate([0.0, 0.0, 1.0]);
+        let expected = orientation.conjugate().rotate([0.0, 0.0, 1.0]);
+        let dot = up[0] * expected[0] + up[1] * expected[1] + up[2] * expected[2];
+        dot.min(1.0).acos()
+    }
+
+    // 0.6 rad/s about [2, -2, 1], for 2s is 1.2 rad
+    pub(super) const TURN_RATE: [f32; 3] = [0.4, -0.4, 0.2];
+
+    // cos 0.6 and sin 0.6 times the normalized axis
+    pub(super) fn turned() -> Quaternion {
+        Quaternion::new(0.825_336, 0.376_428, -0.376_428, 0.188_214)
+    }
+
+    // What the sensors read turning at TURN_RATE from identity for 2s,
+    // gravity and the field following the true orientation at every sample
+    pub(super) fn turning<A: Ahrs>(filter: &mut A, with_mag: bool) {
+        let rate = (TURN_RATE[0] * TURN_RATE[0] + TURN_RATE[1] * TURN_RATE[1] + TURN_RATE[2] * TURN_RATE[2]).sqrt();
+        for step in 1..=(2.0 / DT).round() as usize {
+            let to_sensor = Quaternion::from_axis_angle(TURN_RATE, rate * step as f32 * DT).conjugate();
+            let mag = if with_mag { Some(to_sensor.rotate(EARTH_FIELD)) } else { None };
+            filter.update(TURN_RATE, to_sensor.rotate([0.0, 0.0, 9.8]), mag, DT);
+        }
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        for axis in 0..3 {
+            assert!((a[axis] - b[axis]).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn rotate_about_each_axis() {
+        let x = Quaternion::from_axis_angle([1.0, 0.0, 0.0], FRAC_PI_2);
+        assert_close(x.rotate([0.0, 1.0, 0.0]), [0.0, 0.0, 1.0]);
+        let y = Quaternion::from_axis_angle([0.0, 2.0, 0.0], FRAC_PI_2);
+        assert_close(y.rotate([0.0, 0.0, 1.0]), [1.0, 0.0, 0.0]);
+        let z = Quaternion::from_axis_angle([0.0, 0.0, 1.0], FRAC_PI_2);
+        assert_close(z.rotate([1.0, 0.0, 0.0]), [0.0, 1.0, 0.0]);
+        // the conjugate rotates back
+        assert_close(z.conjugate().rotate(z.rotate([0.3, -0.2, 0.9])), [0.3, -0.2, 0.9]);
+        // x then z
+        assert_close((z * x).rotate([0.0, 1.0, 0.0]), [0.0, 0.0, 1.0]);
+        assert_close((z * x).rotate([1.0, 0.0, 0.0]), [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn angle_between_orientations() {
+        let a = Quaternion::from_axis_angle([0.0, 0.0, 1.0], 0.25);
+        let b = Quaternion::from_axis_angle([0.0, 0.0, 1.0], 1.0);
+        assert!((a.angle_to(b) - 0.75).abs() < 1e-5);
+        // q and -q are the same orientation
+        let minus_b = Quaternion::new(-b.w, -b.x, -b.y, -b.z);
+        assert!((a.angle_to(minus_b) - 0.75).abs() < 1e-5);
+        assert!(b.angle_to(b) < 1e-3);
+    }
+
+    #[test]
+    fn integrate_constant_rate() {
+        let mut q = Quaternion::IDENTITY;
+        for _ in 0..1000 {
+            q = q.integrate([0.0, 0.5, 0.0], 0.001);
+        }
+        assert!(q.angle_to(Quaternion::from_axis_angle([0.0, 1.0, 0.0], 0.5)) < 1e-3);
+        assert!((q.norm() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn integrate_about_a_tilted_axis() {
+        let mut q = Quaternion::IDENTITY;
+        for _ in 0..(2.0 / DT).round() as usize {
+            q = q.integrate(TURN_RATE, DT);
+        }
+        assert!(q.angle_to(turned()) < 1e-3);
+        assert!(Quaternion::from_axis_angle([2.0, -2.0, 1.0], 1.2).angle_to(turned()) < 1e-5);
+    }
+
+    #[test]
+    fn zero_vectors() {
+        assert_eq!(normalize([0.0; 3]), [0.0; 3]);
+        assert!(is_zero(normalize([0.0; 3])));
+        assert_eq!(Quaternion::new(0.0, 0.0, 0.0, 0.0).normalize(), Quaternion::IDENTITY);
+        assert_close(normalize([3.0, 0.0, 4.0]), [0.6, 0.0, 0.8]);
+        assert_close(cross([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]), [0.0, 0.0, 1.0]);
+    }
+}
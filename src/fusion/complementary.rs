@@ -0,0 +1,150 @@
+use super::{
+    cross,
+    is_zero,
+    normalize,
+    Ahrs,
+    Quaternion,
+};
+
+// Integrates the gyro and feeds back the angle between where the estimate
+// says gravity (and magnetic north) should be and where the sensors see it.
+// gain is in rad/s per unit of that error, larger trusts the accelerometer
+// more. Mahony's filter without the integral term.
+// https://hal.archives-ouvertes.fr/hal-00488376/document
+pub const COMPLEMENTARY_DEFAULT_GAIN: f32 = 1.0;
+
+#[derive(Debug, Clone)]
+pub struct Complementary {
+    gain: f32,
+    q: Quaternion,
+}
+
+impl Complementary {
+    pub fn new(gain: f32) -> Complementary {
+        Complementary {
+            gain,
+            q: Quaternion::IDENTITY,
+        }
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+}
+
+impl Default for Complementary {
+    fn default() -> Complementary {
+        Complementary::new(COMPLEMENTARY_DEFAULT_GAIN)
+    }
+}
+
+impl Ahrs for Complementary {
+    fn update(&mut self, gyro: [f32; 3], accel: [f32; 3], mag: Option<[f32; 3]>, dt: f32) {
+        let mut gyro = gyro;
+
+        if !is_zero(accel) {
+            let to_sensor = self.q.conjugate();
+            let mut error = cross(normalize(accel), to_sensor.rotate([0.0, 0.0, 1.0]));
+
+            if let Some(mag) = mag.filter(|&mag| !is_zero(mag)) {
+                let m = normalize(mag);
+                // only the heading is taken from the magnetometer, the field
+                // is flattened onto north and down as the estimate sees it
+                let h = self.q.rotate(m);
+                let north = [(h[0] * h[0] + h[1] * h[1]).sqrt(), 0.0, h[2]];
+                let mag_error = cross(m, to_sensor.rotate(north));
+                for axis in 0..3 {
+                    error[axis] += mag_error[axis];
+                }
+            }
+
+            for axis in 0..3 {
+                gyro[axis] += self.gain * error[axis];
+            }
+        }
+
+        self.q = self.q.integrate(gyro, dt);
+    }
+
+    fn orientation(&self) -> Quaternion {
+        self.q
+    }
+
+    fn reset(&mut self) {
+        self.q = Quaternion::IDENTITY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{
+        still,
+        tilt_error,
+        tilted,
+        tilted_and_turned,
+        turned,
+        turning,
+        DT,
+    };
+    use super::*;
+
+    #[test]
+    fn converges_with_mag() {
+        let mut filter = Complementary::default();
+        // the heading is pulled in much slower than the tilt
+        still(&mut filter, tilted_and_turned(), true, 60.0);
+        assert!(filter.orientation().angle_to(tilted_and_turned()) < 0.01);
+    }
+
+    #[test]
+    fn converges_to_tilt_without_mag() {
+        let mut filter = Complementary::default();
+        still(&mut filter, tilted_and_turned(), false, 30.0);
+        assert!(tilt_error(&filter, tilted_and_turned()) < 0.01);
+        // nothing pulls the heading, so the estimate only tilts
+        let heading = filter.orientation().rotate([0.0, 1.0, 0.0]);
+        assert!(heading[0].abs() < 0.01);
+    }
+
+    #[test]
+    fn zero_gain_only_integrates_the_gyro() {
+        let mut filter = Complementary::new(0.0);
+        let accel = tilted().conjugate().rotate([0.0, 0.0, 1.0]);
+        for _ in 0..100 {
+            filter.update([0.0, 0.0, 1.0], accel, None, DT);
+        }
+        assert!(filter.orientation().angle_to(Quaternion::from_axis_angle([0.0, 0.0, 1.0], 1.0)) < 1e-3);
+    }
+
+    #[test]
+    fn gyro_rotation_is_tracked_while_corrected() {
+        // turning about up doesn't fight gravity
+        let mut filter = Complementary::default();
+        for _ in 0..100 {
+            filter.update([0.0, 0.0, 0.5], [0.0, 0.0, 1.0], None, DT);
+        }
+        assert!(filter.orientation().angle_to(Quaternion::from_axis_angle([0.0, 0.0, 1.0], 0.5)) < 1e-3);
+    }
+
+    #[test]
+    fn follows_a_turn_about_a_tilted_axis() {
+        for with_mag in [true, false] {
+            let mut filter = Complementary::default();
+            turning(&mut filter, with_mag);
+            assert!(filter.orientation().angle_to(turned()) < 0.01, "{:?}", filter.orientation());
+        }
+    }
+
+    #[test]
+    fn reset_to_identity() {
+        let mut filter = Complementary::default();
+        still(&mut filter, tilted(), true, 1.0);
+        assert!(filter.orientation().angle_to(Quaternion::IDENTITY) > 0.01);
+        filter.reset();
+        assert_eq!(filter.orientation(), Quaternion::IDENTITY);
+    }
+}
@@ -0,0 +1,209 @@
+use super::{
+    is_zero,
+    normalize,
+    Ahrs,
+    Quaternion,
+};
+
+// Madgwick's gradient descent filter, beta is how hard it pulls the gyro
+// estimate towards the accelerometer (and magnetometer) per second. Larger
+// corrects drift faster but lets more accelerometer noise through.
+// https://x-io.co.uk/open-source-imu-and-ahrs-algorithms/
+pub const MADGWICK_DEFAULT_BETA: f32 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct Madgwick {
+    beta: f32,
+    q: Quaternion,
+}
+
+impl Madgwick {
+    pub fn new(beta: f32) -> Madgwick {
+        Madgwick {
+            beta,
+            q: Quaternion::IDENTITY,
+        }
+    }
+
+    pub fn beta(&self) -> f32 {
+        self.beta
+    }
+
+    pub fn set_beta(&mut self, beta: f32) {
+        self.beta = beta;
+    }
+
+    // objective function gradient with gravity only
+    fn imu_gradient(&self, a: [f32; 3]) -> [f32; 4] {
+        let Quaternion { w: q0, x: q1, y: q2, z: q3 } = self.q;
+        let [ax, ay, az] = a;
+        [
+            4.0 * q0 * q2 * q2 + 2.0 * q2 * ax + 4.0 * q0 * q1 * q1 - 2.0 * q1 * ay,
+            4.0 * q1 * q3 * q3 - 2.0 * q3 * ax + 4.0 * q0 * q0 * q1 - 2.0 * q0 * ay - 4.0 * q1
+                + 8.0 * q1 * q1 * q1 + 8.0 * q1 * q2 * q2 + 4.0 * q1 * az,
+            4.0 * q0 * q0 * q2 + 2.0 * q0 * ax + 4.0 * q2 * q3 * q3 - 2.0 * q3 * ay - 4.0 * q2
+                + 8.0 * q2 * q1 * q1 + 8.0 * q2 * q2 * q2 + 4.0 * q2 * az,
+            4.0 * q1 * q1 * q3 - 2.0 * q1 * ax + 4.0 * q2 * q2 * q3 - 2.0 * q2 * ay,
+        ]
+    }
+
+    // gravity and the earth's magnetic field, which is taken to point north
+    // and down in the plane the current estimate puts it in
+    fn marg_gradient(&self, a: [f32; 3], m: [f32; 3]) -> [f32; 4] {
+        let Quaternion { w: q0, x: q1, y: q2, z: q3 } = self.q;
+        let [ax, ay, az] = a;
+        let [mx, my, mz] = m;
+
+        let h = self.q.rotate(m);
+        let bx = (h[0] * h[0] + h[1] * h[1]).sqrt();
+        let bz = h[2];
+
+        // the difference between the estimated and measured directions
+        let f = [
+            2.0 * (q1 * q3 - q0 * q2) - ax,
+            2.0 * (q0 * q1 + q2 * q3) - ay,
+            2.0 * (0.5 - q1 * q1 - q2 * q2) - az,
+            2.0 * bx * (0.5 - q2 * q2 - q3 * q3) + 2.0 * bz * (q1 * q3 - q0 * q2) - mx,
+            2.0 * bx * (q1 * q2 - q0 * q3) + 2.0 * bz * (q0 * q1 + q2 * q3) - my,
+            2.0 * bx * (q0 * q2 + q1 * q3) + 2.0 * bz * (0.5 - q1 * q1 - q2 * q2) - mz,
+        ];
+        // and its Jacobian, one row per entry of f
+        let j = [
+            [-2.0 * q2, 2.0 * q3, -2.0 * q0, 2.0 * q1],
+            [2.0 * q1, 2.0 * q0, 2.0 * q3, 2.0 * q2],
+            [0.0, -4.0 * q1, -4.0 * q2, 0.0],
+            [-2.0 * bz * q2, 2.0 * bz * q3, -4.0 * bx * q2 - 2.0 * bz * q0, -4.0 * bx * q3 + 2.0 * bz * q1],
+            [-2.0 * bx * q3 + 2.0 * bz * q1, 2.0 * bx * q2 + 2.0 * bz * q0, 2.0 * bx * q1 + 2.0 * bz * q3, -2.0 * bx * q0 + 2.0 * bz * q2],
+            [2.0 * bx * q2, 2.0 * bx * q3 - 4.0 * bz * q1, 2.0 * bx * q0 - 4.0 * bz * q2, 2.0 * bx * q1],
+        ];
+
+        let mut gradient = [0f32; 4];
+        for (row, error) in j.iter().zip(f.iter()) {
+            for (component, derivative) in gradient.iter_mut().zip(row.iter()) {
+                *component += derivative * error;
+            }
+        }
+        gradient
+    }
+}
+
+impl Default for Madgwick {
+    fn default() -> Madgwick {
+        Madgwick::new(MADGWICK_DEFAULT_BETA)
+    }
+}
+
+impl Ahrs for Madgwick {
+    fn update(&mut self, gyro: [f32; 3], accel: [f32; 3], mag: Option<[f32; 3]>, dt: f32) {
+        let mut rate = self.q.derivative(gyro);
+
+        // without gravity there is nothing to correct against, e.g. free fall
+        if !is_zero(accel) {
+            let a = normalize(accel);
+            let gradient = match mag {
+                Some(mag) if !is_zero(mag) => self.marg_gradient(a, normalize(mag)),
+                _ => self.imu_gradient(a),
+            };
+            let step = Quaternion::new(gradient[0], gradient[1], gradient[2], gradient[3]);
+            if step.norm() > 0.0 {
+                let step = step.normalize();
+                rate.w -= self.beta * step.w;
+                rate.x -= self.beta * step.x;
+                rate.y -= self.beta * step.y;
+                rate.z -= self.beta * step.z;
+            }
+        }
+
+        self.q = Quaternion::new(
+            self.q.w + rate.w * dt,
+            self.q.x + rate.x * dt,
+            self.q.y + rate.y * dt,
+            self.q.z + rate.z * dt,
+        ).normalize();
+    }
+
+    fn orientation(&self) -> Quaternion {
+        self.q
+    }
+
+    fn reset(&mut self) {
+        self.q = Quaternion::IDENTITY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{
+        still,
+        tilt_error,
+        tilted,
+        tilted_and_turned,
+        turned,
+        turning,
+        DT,
+        EARTH_FIELD,
+    };
+    use super::*;
+
+    #[test]
+    fn converges_with_mag() {
+        let mut filter = Madgwick::default();
+        still(&mut filter, tilted_and_turned(), true, 60.0);
+        assert!(filter.orientation().angle_to(tilted_and_turned()) < 0.01);
+    }
+
+    #[test]
+    fn converges_to_tilt_without_mag() {
+        let mut filter = Madgwick::default();
+        still(&mut filter, tilted_and_turned(), false, 30.0);
+        assert!(tilt_error(&filter, tilted_and_turned()) < 0.01);
+    }
+
+    #[test]
+    fn larger_beta_converges_faster() {
+        let mut slow = Madgwick::new(0.05);
+        let mut fast = Madgwick::new(0.5);
+        still(&mut slow, tilted(), false, 1.0);
+        still(&mut fast, tilted(), false, 1.0);
+        assert!(tilt_error(&fast, tilted()) < tilt_error(&slow, tilted()));
+    }
+
+    #[test]
+    fn zero_mag_falls_back_to_gravity() {
+        let mut with_zero_mag = Madgwick::default();
+        let mut without_mag = Madgwick::default();
+        let accel = tilted().conjugate().rotate([0.0, 0.0, 1.0]);
+        for _ in 0..100 {
+            with_zero_mag.update([0.1, 0.0, 0.0], accel, Some([0.0; 3]), DT);
+            without_mag.update([0.1, 0.0, 0.0], accel, None, DT);
+        }
+        assert_eq!(with_zero_mag.orientation(), without_mag.orientation());
+    }
+
+    #[test]
+    fn follows_the_gyro_in_free_fall() {
+        let mut filter = Madgwick::default();
+        for _ in 0..100 {
+            filter.update([0.0, 0.0, 1.0], [0.0; 3], Some(EARTH_FIELD), DT);
+        }
+        assert!(filter.orientation().angle_to(Quaternion::from_axis_angle([0.0, 0.0, 1.0], 1.0)) < 1e-3);
+    }
+
+    #[test]
+    fn follows_a_turn_about_a_tilted_axis() {
+        for with_mag in [true, false] {
+            let mut filter = Madgwick::default();
+            turning(&mut filter, with_mag);
+            assert!(filter.orientation().angle_to(turned()) < 0.01, "{:?}", filter.orientation());
+        }
+    }
+
+    #[test]
+    fn reset_to_identity() {
+        let mut filter = Madgwick::default();
+        still(&mut filter, tilted(), true, 1.0);
+        assert!(filter.orientation().angle_to(Quaternion::IDENTITY) > 0.01);
+        filter.reset();
+        assert_eq!(filter.orientation(), Quaternion::IDENTITY);
+    }
+}
@@ -1,3 +1,3 @@
-mod bluetooth;
-mod controller;
-mod utils;
\ No newline at end of file
+pub mod bluetooth;
+pub mod controller;
+pub mod fusion;
@@ -1,47 +1,209 @@
-mod controller;
-mod bluetooth;
-mod utils;
-
 use hid_rs::usb::{
-    hid_enumerate_all,
     hid_open_path,
 };
-use crate::controller::ps_move::{
-    PS_MOVE_VID, PS_MOVE_PID,
+
+use std::env;
+use std::thread;
+use std::time::Instant;
+
+use rsvr::controller::ps_move::{
     get_controller_pair,
+    is_usb_connection,
+    pair_controller,
+    ps_move_input_query,
+    ps_move_query,
+    PSMoveModel,
+    PSMoveOutput,
+    PSMoveOutputScheduler,
+};
+#[cfg(target_os = "linux")]
+use rsvr::controller::ps_move::input::{
+    PSMoveInputReport,
+    PSMOVE_INPUT_REPORT_SIZE,
+};
+use rsvr::bluetooth::{
+    find_adapter,
+    get_host_address,
+    list_adapters,
+};
+#[cfg(target_os = "linux")]
+use rsvr::bluetooth::hid::{
+    BluetoothHidDevice,
+    BluetoothHidListener,
 };
-use crate::bluetooth::{get_host_address};
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        // rsvr pair [adapter id or address]
+        Some("pair") => pair(args.get(1).map(String::as_str)),
+        Some("adapters") => adapters(),
+        // rsvr light <red> <green> <blue>
+        Some("light") => light(&args[1..]),
+        // rsvr listen [adapter id or address]
+        Some("listen") => listen(args.get(1).map(String::as_str)),
+        Some(command) => println!("unknown command {:?}, try pair, adapters, light or listen", command),
+        None => show_pair(),
+    }
+}
+
+fn show_pair() {
     // get computer's bluetooth radio MAC
     let host_addr = get_host_address().unwrap();
-    println!("bluetooth host_addr: {:?}", host_addr);
+    println!("bluetooth host_addr: {}", host_addr);
     // find PS Move controller
-    let mut device = None;
-    // https://github.com/psmoveservice/PSMoveService/blob/edbb31417/src/psmoveservice/PSMoveController/PSMoveController.cpp#L1057
-    for result in hid_enumerate_all()
-        .filter(|d| d.is_ok())
-        .filter(|d| {
-            let d = d.as_ref().unwrap();
-            d.vendor_id == PS_MOVE_VID &&
-            d.product_id == PS_MOVE_PID &&
-            d.path.contains("&col02#")
-         }) {
+    let device_info = ps_move_query().find().into_iter().next().expect("no PS Move found");
+    let model = PSMoveModel::from_device_info(&device_info).unwrap();
+    let device = hid_open_path(&device_info.path).unwrap();
+    let (cur_host_addr, controller_addr) = get_controller_pair(&device, model).unwrap();
+    println!("cur_host_addr: {}, controller_addr: {}", cur_host_addr, controller_addr);
+
+    // connect to controllers BT addr
+    // start reading position data
+}
+
+fn adapters() {
+    for adapter in list_adapters().unwrap() {
+        println!(
+            "{} {} {:?}{}",
+            adapter.id, adapter.address, adapter.name,
+            if adapter.powered { "" } else { " (off)" }
+        );
+    }
+}
+
+// send radio MAC to every controller plugged in over USB
+fn pair(adapter: Option<&str>) {
+    let host_addr = match find_adapter(adapter) {
+        Ok(adapter) => adapter.address,
+        Err(error) => {
+            println!("{}", error);
+            return;
+        }
+    };
+    println!("bluetooth host_addr: {}", host_addr);
+    let controllers = ps_move_query().filter(is_usb_connection).find();
+    if controllers.is_empty() {
+        println!("no PS Move connected over USB");
+        return;
+    }
+    for device_info in controllers {
+        let result = PSMoveModel::from_device_info(&device_info)
+            .and_then(|model| {
+                let device = hid_open_path(&device_info.path)?;
+                pair_controller(&device, model, host_addr)?;
+                get_controller_pair(&device, model)
+            });
         match result {
-            Ok(device_info) => {
-                device = Some(hid_open_path(&device_info.path));
-                break
-            }
-            Err(error) => panic!(error),
+            Ok((_, controller_addr)) => println!("paired {} with {}", controller_addr, host_addr),
+            Err(error) => println!("could not pair {}: {}", device_info.path, error),
+        }
+    }
+}
+
+// Light up every PS Move until killed. The sphere goes dark on its own unless
+// the color is sent again every few seconds.
+fn light(color: &[String]) {
+    let color: Vec<u8> = match color.iter().map(|value| value.parse::<u8>()).collect::<Result<Vec<u8>, _>>() {
+        Ok(color) if color.len() == 3 => color,
+        _ => {
+            println!("usage: rsvr light <red> <green> <blue>, each 0 to 255");
+            return;
         }
     };
+    let mut controllers = Vec::new();
+    for device_info in ps_move_input_query().find() {
+        match hid_open_path(&device_info.path) {
+            Ok(device) => {
+                let mut scheduler = PSMoveOutputScheduler::new();
+                scheduler.set_output(PSMoveOutput::new(color[0], color[1], color[2], 0));
+                controllers.push((device_info.path, device, scheduler));
+            },
+            Err(error) => println!("could not open {}: {}", device_info.path, error),
+        }
+    }
+    if controllers.is_empty() {
+        println!("no PS Move found");
+        return;
+    }
+    loop {
+        for (path, device, scheduler) in controllers.iter_mut() {
+            if let Err(error) = scheduler.update(device) {
+                println!("could not light {}: {}", path, error);
+                return;
+            }
+        }
+        let next_update = controllers.iter()
+            .map(|(_, _, scheduler)| scheduler.next_update())
+            .min()
+            .unwrap();
+        thread::sleep(next_update.saturating_duration_since(Instant::now()));
+    }
+}
 
-    let device = device.unwrap().unwrap();
-    let (cur_host_addr, controller_addr) = get_controller_pair(&device).unwrap();
-    println!("cur_host_addr: {:?}, controller_addr: {:?}", cur_host_addr, controller_addr);
+// Wait for paired controllers to connect over Bluetooth and show their buttons
+#[cfg(target_os = "linux")]
+fn listen(adapter: Option<&str>) {
+    let adapter = match adapter.map(|choice| find_adapter(Some(choice))).transpose() {
+        Ok(adapter) => adapter.map(|adapter| adapter.address),
+        Err(error) => {
+            println!("{}", error);
+            return;
+        }
+    };
+    let mut listener = match BluetoothHidListener::bind(adapter) {
+        Ok(listener) => listener,
+        Err(error) => {
+            println!("could not listen for controllers: {}", error);
+            return;
+        }
+    };
+    println!("press the PS button on a paired controller");
+    loop {
+        match listener.accept() {
+            Ok(device) => {
+                thread::spawn(move || show_buttons(device));
+            },
+            Err(error) => {
+                println!("{}", error);
+                return;
+            }
+        }
+    }
+}
 
-    // send radio MAC to controller (pair)
+#[cfg(not(target_os = "linux"))]
+fn listen(_adapter: Option<&str>) {
+    println!("listen is only supported on Linux");
+}
 
-    // connect to controllers BT addr
-    // start reading position data
-}
\ No newline at end of file
+#[cfg(target_os = "linux")]
+fn show_buttons(device: BluetoothHidDevice) {
+    let address = device.address();
+    println!("{} connected", address);
+    let mut scheduler = PSMoveOutputScheduler::new();
+    scheduler.set_color(0, 0, 255);
+    let mut report = [0u8; PSMOVE_INPUT_REPORT_SIZE];
+    let mut buttons = 0;
+    loop {
+        if let Err(error) = scheduler.update(&device) {
+            println!("{} disconnected: {}", address, error);
+            return;
+        }
+        let timeout = scheduler.next_update().saturating_duration_since(Instant::now());
+        let len = match device.read_timeout(&mut report, timeout) {
+            Ok(len) => len,
+            Err(error) => {
+                println!("{} disconnected: {}", address, error);
+                return;
+            }
+        };
+        // the buttons are in the same place on both models
+        if let Ok(input) = PSMoveInputReport::parse(PSMoveModel::ZCM1, &report[..len]) {
+            if input.buttons != buttons {
+                println!("{} buttons {:#07x}", address, input.buttons);
+                buttons = input.buttons;
+            }
+        }
+    }
+}